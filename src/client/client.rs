@@ -3,17 +3,17 @@ extern crate capnp;
 extern crate capnp_rpc;
 extern crate futures;
 extern crate libcix;
-extern crate rand;
 extern crate tokio_core;
 extern crate uuid;
 
 use capnp::capability::Promise;
 use capnp_rpc as rpc;
 use futures::Future;
+use futures::sync::oneshot;
 use libcix::cix_capnp as cp;
 use libcix::order::trade_types::*;
 use self::cp::trading_session;
-use rand::Rng;
+use std::env::args;
 use std::io;
 use std::net::ToSocketAddrs;
 use tokio_core::reactor;
@@ -75,7 +75,9 @@ impl ClientContext {
             cp::ErrorCode::NotAuthenticated => {
                 println!("order rejected because user not signed in");
             },
-            _ => { unreachable!() }
+            code => {
+                println!("order rejected: {}", describe_code(code));
+            }
         }
     }
 
@@ -99,8 +101,8 @@ impl ClientContext {
             cp::ErrorCode::Ok => {
                 println!("canceled order {}", order_id);
             },
-            _ => {
-                println!("failed to cancel order {}", order_id);
+            code => {
+                println!("failed to cancel order {}: {}", order_id, describe_code(code));
             }
         }
     }
@@ -125,6 +127,426 @@ impl ClientContext {
         }
     }
 
+    fn process_positions_line(&mut self) {
+        let positions_req = self.client.get_positions_request();
+        let response = self.core.run(positions_req.send().promise).unwrap();
+        let contents = response.get().unwrap();
+
+        match contents.get_code().unwrap() {
+            cp::ErrorCode::Ok => {
+                println!("cash: {} ({} available)", contents.get_cash(), contents.get_available());
+
+                for position in contents.get_positions().unwrap().iter() {
+                    println!("{}: {} @ {}, realized {}", position.get_symbol().unwrap(),
+                             position.get_quantity(), position.get_average_cost(),
+                             position.get_realized_pnl());
+                }
+            },
+            code => {
+                println!("failed to get positions: {}", describe_code(code));
+            }
+        }
+    }
+
+    // None if this user isn't allowed to administer the server
+    fn admin(&mut self) -> Option<cp::admin::Client> {
+        let admin_req = self.client.admin_request();
+        let response = self.core.run(admin_req.send().promise).unwrap();
+        let response_data = response.get().unwrap();
+
+        match response_data.get_code().unwrap() {
+            cp::ErrorCode::Ok => Some(response_data.get_admin().unwrap()),
+            code => {
+                println!("admin commands unavailable: {}", describe_code(code));
+                None
+            }
+        }
+    }
+
+    fn process_promote_line(&mut self) {
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let response = self.core.run(admin.promote_request().send().promise).unwrap();
+
+        match response.get().unwrap().get_code().unwrap() {
+            cp::ErrorCode::Ok => {
+                println!("server promoted to primary");
+            },
+            _ => {
+                println!("server is not a standby");
+            }
+        }
+    }
+
+    fn process_replication_line(&mut self) {
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let response = self.core.run(admin.replication_status_request().send().promise)
+            .unwrap();
+        let status = response.get().unwrap().get_status().unwrap();
+
+        if !status.get_standby() {
+            println!("server is not a standby");
+            return;
+        }
+
+        println!("standby {} primary, latency {}us",
+                 if status.get_connected() { "connected to" } else { "disconnected from" },
+                 status.get_latency_micros());
+
+        for engine in status.get_engines().unwrap().iter() {
+            println!("engine {}: received {}:{}, primary at {}:{}", engine.get_engine(),
+                     engine.get_received_index(), engine.get_received_offset(),
+                     engine.get_primary_index(), engine.get_primary_offset());
+        }
+    }
+
+    fn process_list_line(&mut self, line: &String) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 2);
+
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let mut list_req = admin.list_symbol_request();
+        list_req.get().set_symbol(fields[1]);
+
+        let response = self.core.run(list_req.send().promise).unwrap();
+        let response_data = response.get().unwrap();
+
+        match response_data.get_code().unwrap() {
+            cp::ErrorCode::Ok => {
+                println!("listed {} with ID {}", fields[1], response_data.get_id());
+            },
+            _ => {
+                println!("failed to list {}", fields[1]);
+            }
+        }
+    }
+
+    // SUSPEND, RESUME or DELIST
+    fn process_symbol_change_line(&mut self, line: &String) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 2);
+
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let action = fields[0].to_uppercase();
+        let code = if action == "SUSPEND" {
+            let mut req = admin.suspend_symbol_request();
+            req.get().set_symbol(fields[1]);
+            self.core.run(req.send().promise).unwrap().get().unwrap().get_code().unwrap()
+        } else if action == "RESUME" {
+            let mut req = admin.resume_symbol_request();
+            req.get().set_symbol(fields[1]);
+            self.core.run(req.send().promise).unwrap().get().unwrap().get_code().unwrap()
+        } else {
+            let mut req = admin.delist_symbol_request();
+            req.get().set_symbol(fields[1]);
+            self.core.run(req.send().promise).unwrap().get().unwrap().get_code().unwrap()
+        };
+
+        match code {
+            cp::ErrorCode::Ok => {
+                println!("{} is now {}", fields[1], match action.as_str() {
+                    "SUSPEND" => "suspended",
+                    "RESUME" => "active",
+                    _ => "delisted"
+                });
+            },
+            _ => {
+                println!("failed to {} {}", action.to_lowercase(), fields[1]);
+            }
+        }
+    }
+
+    fn process_stats_line(&mut self) {
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let response = self.core.run(admin.engine_stats_request().send().promise).unwrap();
+
+        for engine in response.get().unwrap().get_engines().unwrap().iter() {
+            println!("engine {}: {} errors", engine.get_engine(), engine.get_errors());
+
+            let last_error = engine.get_last_error().unwrap();
+            if !last_error.is_empty() {
+                println!("    last error: {}", last_error);
+            }
+
+            for reject in engine.get_rejects().unwrap().iter() {
+                println!("    rejected {} for {}", reject.get_count(),
+                         reject.get_reason().unwrap());
+            }
+        }
+    }
+
+    fn process_latency_line(&mut self) {
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let response = self.core.run(admin.latency_stats_request().send().promise).unwrap();
+
+        println!("{:<9} {:<7} {:>6} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8}", "request", "symbol",
+                 "stage", "count", "p50 us", "p90 us", "p99 us", "p99.9 us", "max us");
+        for series in response.get().unwrap().get_series().unwrap().iter() {
+            let kind = series.get_kind().unwrap();
+            let symbol = series.get_symbol().unwrap();
+
+            for stage in series.get_stages().unwrap().iter() {
+                println!("{:<9} {:<7} {:>6} {:>10} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>8.1}", kind,
+                         symbol, stage.get_stage().unwrap(), stage.get_count(),
+                         stage.get_p50() as f64 / 1000.0, stage.get_p90() as f64 / 1000.0,
+                         stage.get_p99() as f64 / 1000.0, stage.get_p999() as f64 / 1000.0,
+                         stage.get_max() as f64 / 1000.0);
+            }
+        }
+    }
+
+    // SHUTDOWN [SNAPSHOT]
+    fn process_shutdown_line(&mut self, line: &String) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let snapshot = fields.get(1).map(|f| f.to_uppercase() == "SNAPSHOT").unwrap_or(false);
+
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let mut shutdown_req = admin.shutdown_request();
+        shutdown_req.get().set_snapshot(snapshot);
+
+        let response = self.core.run(shutdown_req.send().promise).unwrap();
+        match response.get().unwrap().get_code().unwrap() {
+            cp::ErrorCode::Ok => {
+                println!("server is shutting down{}",
+                         if snapshot { " after writing a snapshot" } else { "" });
+            },
+            _ => {
+                println!("server is already shutting down");
+            }
+        }
+    }
+
+    // RISK <user>
+    fn process_risk_line(&mut self, line: &String) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 2);
+        let user: u64 = fields[1].parse().unwrap();
+
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let mut status_req = admin.risk_status_request();
+        status_req.get().set_user(user);
+
+        let response = self.core.run(status_req.send().promise).unwrap();
+        let response_data = response.get().unwrap();
+
+        match response_data.get_code().unwrap() {
+            cp::ErrorCode::Ok => {},
+            code => {
+                println!("failed to get risk status for user {}: {}", user, describe_code(code));
+                return;
+            }
+        }
+
+        let limits = response_data.get_limits().unwrap();
+        println!("limits for user {} (0 is no limit):", user);
+        println!("    max_order_quantity = {}", limits.get_max_order_quantity());
+        println!("    max_order_notional = {}", limits.get_max_order_notional());
+        println!("    max_open_orders = {}", limits.get_max_open_orders());
+        println!("    max_symbol_exposure = {}", limits.get_max_symbol_exposure());
+        println!("    max_total_exposure = {}", limits.get_max_total_exposure());
+        println!("    max_price_deviation = {}", limits.get_max_price_deviation());
+
+        let exposure = response_data.get_exposure().unwrap();
+        println!("{} open orders, {} total exposure", exposure.get_open_orders(),
+                 exposure.get_total());
+        for symbol in exposure.get_symbols().unwrap().iter() {
+            println!("    {}: {}", symbol.get_symbol().unwrap(), symbol.get_notional());
+        }
+    }
+
+    // SET_RISK <user> <limit>=<value>...
+    // Limits are named as in RISK's output, and anything not given stays as it is
+    fn process_set_risk_line(&mut self, line: &String) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert!(fields.len() >= 3);
+        let user: u64 = fields[1].parse().unwrap();
+
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let mut status_req = admin.risk_status_request();
+        status_req.get().set_user(user);
+
+        let status = self.core.run(status_req.send().promise).unwrap();
+        let status_data = status.get().unwrap();
+
+        match status_data.get_code().unwrap() {
+            cp::ErrorCode::Ok => {},
+            code => {
+                println!("failed to get risk limits for user {}: {}", user, describe_code(code));
+                return;
+            }
+        }
+
+        let mut set_req = admin.set_risk_limits_request();
+        set_req.get().set_user(user);
+        set_req.get().set_limits(status_data.get_limits().unwrap()).unwrap();
+
+        {
+            let mut limits = set_req.get().get_limits().unwrap();
+            for field in fields[2..].iter() {
+                let parts: Vec<&str> = field.splitn(2, '=').collect();
+                assert_eq!(parts.len(), 2, "expected <limit>=<value>, not {}", field);
+                let (name, value) = (parts[0], parts[1]);
+
+                match name {
+                    "max_order_quantity" => limits.set_max_order_quantity(value.parse().unwrap()),
+                    "max_order_notional" => limits.set_max_order_notional(value.parse().unwrap()),
+                    "max_open_orders" => limits.set_max_open_orders(value.parse().unwrap()),
+                    "max_symbol_exposure" => {
+                        limits.set_max_symbol_exposure(value.parse().unwrap())
+                    },
+                    "max_total_exposure" => limits.set_max_total_exposure(value.parse().unwrap()),
+                    "max_price_deviation" => {
+                        limits.set_max_price_deviation(value.parse().unwrap())
+                    },
+                    _ => {
+                        println!("unknown risk limit {}", name);
+                        return;
+                    }
+                }
+            }
+        }
+
+        let response = self.core.run(set_req.send().promise).unwrap();
+        let response_data = response.get().unwrap();
+        match response_data.get_code().unwrap() {
+            cp::ErrorCode::Ok => {
+                println!("updated risk limits for user {}", user);
+                if response_data.has_warning() {
+                    println!("warning: {}", response_data.get_warning().unwrap());
+                }
+            },
+            code => {
+                println!("failed to set risk limits for user {}: {}", user, describe_code(code));
+            }
+        }
+    }
+
+    fn process_statement_line(&mut self) {
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let response = self.core.run(admin.export_statement_request().send().promise).unwrap();
+        let response_data = response.get().unwrap();
+
+        match response_data.get_code().unwrap() {
+            cp::ErrorCode::Ok => {
+                println!("statement written to {}", response_data.get_path().unwrap());
+            },
+            _ => {
+                println!("failed to export statement");
+            }
+        }
+    }
+
+    // DEPOSIT, WITHDRAW or SET_BALANCE <user> <amount>
+    fn process_funds_line(&mut self, line: &String) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields.len(), 3);
+        let user: u64 = fields[1].parse().unwrap();
+        let amount: f64 = fields[2].parse().unwrap();
+
+        let admin = match self.admin() {
+            Some(admin) => admin,
+            None => { return; }
+        };
+        let action = fields[0].to_uppercase();
+        let (code, balance) = if action == "DEPOSIT" {
+            let mut req = admin.deposit_request();
+            req.get().set_user(user);
+            req.get().set_amount(amount);
+            let response = self.core.run(req.send().promise).unwrap();
+            let response_data = response.get().unwrap();
+            (response_data.get_code().unwrap(), response_data.get_balance())
+        } else if action == "WITHDRAW" {
+            let mut req = admin.withdraw_request();
+            req.get().set_user(user);
+            req.get().set_amount(amount);
+            let response = self.core.run(req.send().promise).unwrap();
+            let response_data = response.get().unwrap();
+            (response_data.get_code().unwrap(), response_data.get_balance())
+        } else {
+            let mut req = admin.set_balance_request();
+            req.get().set_user(user);
+            req.get().set_balance(amount);
+            let response = self.core.run(req.send().promise).unwrap();
+            let response_data = response.get().unwrap();
+            (response_data.get_code().unwrap(), response_data.get_balance())
+        };
+
+        match code {
+            cp::ErrorCode::Ok => {
+                println!("user {} now has {}", user, balance);
+            },
+            code => {
+                println!("failed to {} for user {}: {}", action.to_lowercase(), user,
+                         describe_code(code));
+            }
+        }
+    }
+
+    // Whether the session is now signed in
+    fn authenticate(&mut self, user: &String, secret: &Secret) -> bool {
+        let mut auth_req = self.client.authenticate_request();
+        {
+            let mut credentials = auth_req.get().init_credentials();
+            credentials.set_username(user.as_str());
+            match *secret {
+                Secret::Password(ref p) => credentials.set_password(p.as_str()),
+                Secret::ApiKey(ref k) => credentials.set_api_key(k.as_str())
+            }
+        }
+
+        let response = self.core.run(auth_req.send().promise).unwrap();
+        let response_data = response.get().unwrap();
+
+        match response_data.get_response().unwrap() {
+            cp::AuthCode::Ok => {
+                println!("signed in as {} (user {})", user, response_data.get_user());
+                true
+            },
+            cp::AuthCode::Unavailable => {
+                println!("server is not accepting trading sessions right now");
+                false
+            },
+            cp::AuthCode::Invalid => {
+                panic!("wrong username, password or API key for {}", user);
+            },
+            cp::AuthCode::RateLimited => {
+                panic!("too many failed attempts to sign in as {}, try again later", user);
+            },
+            cp::AuthCode::AlreadyAuthenticated => {
+                panic!("session was already signed in");
+            }
+        }
+    }
+
     pub fn process_line(&mut self, line: &String) {
         let fields: Vec<&str> = line.split_whitespace().collect();
         let action = fields[0].to_uppercase();
@@ -132,13 +554,66 @@ impl ClientContext {
             self.process_cancel_line(line);
         } else if action == "OPEN_ORDERS" {
             self.process_open_orders_line();
+        } else if action == "POSITIONS" {
+            self.process_positions_line();
+        } else if action == "STATEMENT" {
+            self.process_statement_line();
+        } else if action == "PROMOTE" {
+            self.process_promote_line();
+        } else if action == "REPLICATION" {
+            self.process_replication_line();
+        } else if action == "STATS" {
+            self.process_stats_line();
+        } else if action == "LATENCY" {
+            self.process_latency_line();
+        } else if action == "RISK" {
+            self.process_risk_line(line);
+        } else if action == "SET_RISK" {
+            self.process_set_risk_line(line);
+        } else if action == "DEPOSIT" || action == "WITHDRAW" || action == "SET_BALANCE" {
+            self.process_funds_line(line);
+        } else if action == "SHUTDOWN" {
+            self.process_shutdown_line(line);
+        } else if action == "LIST" {
+            self.process_list_line(line);
+        } else if action == "SUSPEND" || action == "RESUME" || action == "DELIST" {
+            self.process_symbol_change_line(line);
         } else {
             self.process_new_order_line(line);
         }
     }
 }
 
-struct ExecutionFeedImpl;
+fn describe_code(code: cp::ErrorCode) -> &'static str {
+    match code {
+        cp::ErrorCode::Ok => "ok",
+        cp::ErrorCode::NotAuthenticated => "not signed in",
+        cp::ErrorCode::AlreadySubscribed => "already subscribed",
+        cp::ErrorCode::InvalidArgs => "invalid arguments",
+        cp::ErrorCode::Other => "internal error",
+        cp::ErrorCode::Unavailable => "server unavailable",
+        cp::ErrorCode::UnknownSymbol => "unknown symbol",
+        cp::ErrorCode::SymbolSuspended => "symbol is suspended",
+        cp::ErrorCode::InvalidOrder => "price or quantity not allowed for this symbol",
+        cp::ErrorCode::UnknownOrder => "unknown order",
+        cp::ErrorCode::NotOrderOwner => "order belongs to another user",
+        cp::ErrorCode::PermissionDenied => "not allowed for this user",
+        cp::ErrorCode::OrderQuantityLimit => "order quantity over risk limit",
+        cp::ErrorCode::OrderNotionalLimit => "order notional over risk limit",
+        cp::ErrorCode::OpenOrdersLimit => "too many open orders",
+        cp::ErrorCode::SymbolExposureLimit => "symbol exposure over risk limit",
+        cp::ErrorCode::TotalExposureLimit => "total exposure over risk limit",
+        cp::ErrorCode::PriceDeviationLimit => "price too far from last trade",
+        cp::ErrorCode::InsufficientFunds => "not enough buying power",
+        cp::ErrorCode::InsufficientPosition => "not enough shares to sell"
+    }
+}
+
+struct ExecutionFeedImpl {
+    // Completed when the server says it's shutting down
+    shutdown: Option<oneshot::Sender<()>>
+}
+
 impl cp::execution_feed::Server for ExecutionFeedImpl {
     fn execution(&mut self, params: cp::execution_feed::ExecutionParams,
                  results: cp::execution_feed::ExecutionResults)
@@ -155,12 +630,68 @@ impl cp::execution_feed::Server for ExecutionFeedImpl {
 
         Promise::ok(())
     }
+
+    fn shutdown(&mut self, _params: cp::execution_feed::ShutdownParams,
+                _results: cp::execution_feed::ShutdownResults)
+                -> Promise<(), capnp::Error> {
+        println!("server is shutting down");
+
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.complete(());
+        }
+
+        Promise::ok(())
+    }
+}
+
+enum Secret {
+    Password(String),
+    ApiKey(String)
+}
+
+struct ClientArgs {
+    server: String,
+    user: String,
+    secret: Secret
+}
+
+fn parse_args() -> Result<ClientArgs, String> {
+    let mut server = "localhost:2468".to_string();
+    let mut user = None;
+    let mut secret = None;
+
+    let mut cli_args = args().skip(1);
+
+    while let Some(flag) = cli_args.next() {
+        let mut value = || cli_args.next().ok_or(format!("{} requires a value", flag));
+
+        match flag.as_str() {
+            "--server" => { server = try!(value()); },
+            "--user" => { user = Some(try!(value())); },
+            "--password" => { secret = Some(Secret::Password(try!(value()))); },
+            "--api-key" => { secret = Some(Secret::ApiKey(try!(value()))); },
+            _ => { return Err(format!("unrecognized argument {}", flag)); }
+        }
+    }
+
+    // Even admin commands need a user allowed to use them
+    Ok(ClientArgs {
+        server: server,
+        user: try!(user.ok_or("--user is required".to_string())),
+        secret: try!(secret.ok_or("--user needs a --password or --api-key".to_string()))
+    })
 }
 
+// usage: cixcli [--server <address>] --user <name> (--password <password>|--api-key <key>)
+// Reads orders and commands from stdin, one per line
 fn main() {
+    let client_args = parse_args().unwrap_or_else(|e| {
+        panic!("invalid arguments: {}", e)
+    });
+
     let mut core = reactor::Core::new().unwrap();
     let handle = core.handle();
-    let addr = "localhost:2468".to_socket_addrs().unwrap().next()
+    let addr = client_args.server.to_socket_addrs().unwrap().next()
         .expect("failed to parse address");
     let stream = core.run(TcpStream::connect(&addr, &handle)).unwrap();
 
@@ -176,29 +707,22 @@ fn main() {
 
     let mut context = ClientContext::new(core, cli);
 
-    let mut auth_req = context.client.authenticate_request();
-    let userid = rand::weak_rng().next_u64();
-
-    println!("connecting with userid {}", userid);
-    auth_req.get().set_user(userid);
-
-    let response = context.core.run(auth_req.send().promise).unwrap();
+    let authenticated = context.authenticate(&client_args.user, &client_args.secret);
 
-    match response.get().unwrap().get_response().unwrap() {
-        cp::AuthCode::Ok => {
-            println!("authenticated as user {}", userid);
-        },
-        _ => {
-            panic!("failed to authenticate as user {}", userid);
-        }
-    }
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
-    let exec_feed = cp::execution_feed::ToClient::new(ExecutionFeedImpl)
-        .from_server::<::capnp_rpc::Server>();
-    let mut feed_req = context.client.execution_subscribe_request();
-    feed_req.get().set_feed(exec_feed);
+    // Hold on to the subscription for as long as we're running; dropping it unsubscribes
+    let feed = if authenticated {
+        let exec_feed = cp::execution_feed::ToClient::new(ExecutionFeedImpl {
+            shutdown: Some(shutdown_tx)
+        }).from_server::<::capnp_rpc::Server>();
+        let mut feed_req = context.client.execution_subscribe_request();
+        feed_req.get().set_feed(exec_feed);
 
-    let feed = context.core.run(feed_req.send().promise).unwrap();
+        Some(context.core.run(feed_req.send().promise).unwrap())
+    } else {
+        None
+    };
 
     let stdin = io::stdin();
     let mut line = String::new();
@@ -209,5 +733,9 @@ fn main() {
         line.clear();
     }
 
-    loop{}
+    // Keep printing executions until the server shuts down.  The feed is dropped along with the
+    // connection if the server goes away without saying so, which ends this too.
+    if feed.is_some() {
+        let _ = context.core.run(shutdown_rx);
+    }
 }
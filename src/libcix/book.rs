@@ -6,12 +6,11 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::iter::Chain;
 use std::rc::Rc;
-use time;
 
 trait OrderComparer: heap::Comparer<Order> {
     fn does_cross(new_order: &Order, book_order: &Order) -> bool;
-    fn create_execution(id: ExecutionId, new_order: &Order, book_order: &Order, quantity: Quantity)
-        -> Execution;
+    fn create_execution(id: ExecutionId, ts: OrderTime, new_order: &Order, book_order: &Order,
+                        quantity: Quantity) -> Execution;
 }
 
 #[derive(Debug)]
@@ -25,11 +24,11 @@ impl OrderComparer for BuyComparer {
         book_order.price >= new_order.price
     }
 
-    fn create_execution(id: ExecutionId, new_order: &Order, book_order: &Order, quantity: Quantity)
-            -> Execution {
+    fn create_execution(id: ExecutionId, ts: OrderTime, new_order: &Order, book_order: &Order,
+                        quantity: Quantity) -> Execution {
         Execution {
             symbol:     book_order.symbol,
-            ts:         time::now().to_timespec(),
+            ts:         ts,
             id:         id, 
             buy_user:   book_order.user,
             buy_order:  book_order.id,
@@ -50,7 +49,9 @@ impl heap::Comparer<Order> for BuyComparer {
                 match x.update.cmp(&y.update) {
                     Ordering::Greater => Ordering::Less,
                     Ordering::Less => Ordering::Greater,
-                    Ordering::Equal => Ordering::Equal
+                    // Timestamps can collide, so fall back on arrival order to keep priority
+                    // deterministic
+                    Ordering::Equal => y.id.sequence().cmp(&x.id.sequence())
                 }
             }
         }
@@ -62,11 +63,11 @@ impl OrderComparer for SellComparer {
         book_order.price <= new_order.price
     }
 
-    fn create_execution(id: ExecutionId, new_order: &Order, book_order: &Order, quantity: Quantity)
-            -> Execution {
+    fn create_execution(id: ExecutionId, ts: OrderTime, new_order: &Order, book_order: &Order,
+                        quantity: Quantity) -> Execution {
         Execution {
             symbol:     book_order.symbol,
-            ts:         time::now().to_timespec(),
+            ts:         ts,
             id:         id,
             buy_user:   new_order.user,
             buy_order:  new_order.id,
@@ -87,7 +88,9 @@ impl heap::Comparer<Order> for SellComparer {
                 match x.update.cmp(&y.update) {
                     Ordering::Greater => Ordering::Less,
                     Ordering::Less => Ordering::Greater,
-                    Ordering::Equal => Ordering::Equal
+                    // Timestamps can collide, so fall back on arrival order to keep priority
+                    // deterministic
+                    Ordering::Equal => y.id.sequence().cmp(&x.id.sequence())
                 }
             }
         }
@@ -106,11 +109,14 @@ struct BookSide<TCmp> where TCmp: OrderComparer {
     id_gen: Rc<ExecutionIdGenerator>
 }
 
-pub trait ExecutionHandler: Send {
-    fn ack_order(&self, order_id: OrderId, status: ErrorCode);
+pub trait ExecutionHandler {
     fn handle_match(&self, execution: &Execution);
     fn handle_market_data_l1(&self, md: L1Md);
     fn handle_market_data_l2(&self, md: L2Md);
+
+    // Called once the caller is done with a batch of orders.  Handlers that hold on to what
+    // they're given should send it on here.
+    fn flush(&self) {}
 }
 
 impl<TCmp> BookSide<TCmp> where TCmp: OrderComparer {
@@ -207,8 +213,11 @@ impl<TCmp> OrderProcessor<heap::HeapHandle> for BookSide<TCmp>
 
                 assert_ne!(cross_quantity, 0);
 
+                // Executions happen at the time the incoming order was stamped so that replaying
+                // it reproduces the same timestamps
                 let exec_id = self.id_gen.next_id();
-                TCmp::create_execution(exec_id, &new_order, book_order, cross_quantity)
+                TCmp::create_execution(exec_id, new_order.update, &new_order, book_order,
+                                       cross_quantity)
             };
             let quantity = ex.quantity;
 
@@ -259,6 +268,7 @@ impl ExecutionIdGenerator {
 
 pub struct OrderBook {
     pub symbol: Symbol,
+    pub symbol_id: u32,
     buys:       BookSide<BuyComparer>,
     sells:      BookSide<SellComparer>,
     last_exec:  Option<MdExecution>
@@ -272,6 +282,7 @@ impl OrderBook {
         let id_gen = Rc::new(ExecutionIdGenerator::new(symbol_id));
         OrderBook {
             symbol:     symbol,
+            symbol_id:  symbol_id,
             buys:       BookSide::<BuyComparer>::new(id_gen.clone()),
             sells:      BookSide::<SellComparer>::new(id_gen.clone()),
             last_exec:  None
@@ -296,7 +307,10 @@ impl OrderBook {
 }
 
 pub trait OrderMatcher: Send {
-    fn add_order<T: ExecutionHandler>(&mut self, book: &mut OrderBook, order: Order, handler: &T);
+    // The returned code is the acknowledgement status for the new order; it is up to the caller to
+    // relay it to whoever submitted the order
+    fn add_order<T: ExecutionHandler>(&mut self, book: &mut OrderBook, order: Order, handler: &T)
+        -> ErrorCode;
     fn cancel_order<T: ExecutionHandler>(&mut self, &mut OrderBook,
                                          order: OrderId, handler: &T);
     fn publish_md<T: ExecutionHandler>(&self, book: &OrderBook, handler: &T);
@@ -307,7 +321,7 @@ pub struct BasicMatcher;
 
 impl OrderMatcher for BasicMatcher {
     fn add_order<T: ExecutionHandler>(&mut self, book: &mut OrderBook,
-                                      order: Order, handler: &T) {
+                                      order: Order, handler: &T) -> ErrorCode {
         let mut o = order;
 
         {
@@ -318,8 +332,7 @@ impl OrderMatcher for BasicMatcher {
 
             if book.has_order(order.id) {
                 println!("rejecting duplicate order {}", order.id);
-                handler.ack_order(order.id, ErrorCode::DuplicateId);
-                return;
+                return ErrorCode::DuplicateId;
             }
         }
 
@@ -350,9 +363,9 @@ impl OrderMatcher for BasicMatcher {
             book.add_order(o);
         }
 
-        handler.ack_order(order.id, ErrorCode::Success);
-
         //self.publish_md(book, handler);
+
+        ErrorCode::Success
     }
 
     fn cancel_order<T: ExecutionHandler>(&mut self, book: &mut OrderBook,
@@ -0,0 +1,62 @@
+use order::trade_types::OrderTime;
+use std::cell::Cell;
+use time;
+
+// All timestamps assigned by the exchange come from one of these so that replaying a log can
+// reproduce them exactly.
+pub trait Clock {
+    fn now(&self) -> OrderTime;
+
+    // Called with each logged timestamp as it's replayed.  Live clocks ignore this.
+    fn observe(&self, ts: OrderTime) {
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> OrderTime {
+        time::now().to_timespec()
+    }
+}
+
+// Only moves when told to, either explicitly or by observing replayed timestamps
+#[derive(Clone, Debug)]
+pub struct ReplayClock {
+    current: Cell<OrderTime>
+}
+
+impl ReplayClock {
+    pub fn new(start: OrderTime) -> Self {
+        ReplayClock {
+            current: Cell::new(start)
+        }
+    }
+
+    pub fn set(&self, ts: OrderTime) {
+        self.current.set(ts);
+    }
+
+    pub fn advance(&self, d: time::Duration) {
+        self.current.set(self.current.get() + d);
+    }
+}
+
+impl Default for ReplayClock {
+    fn default() -> Self {
+        Self::new(time::Timespec::new(0, 0))
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> OrderTime {
+        self.current.get()
+    }
+
+    fn observe(&self, ts: OrderTime) {
+        if ts > self.current.get() {
+            self.current.set(ts);
+        }
+    }
+}
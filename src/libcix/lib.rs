@@ -10,6 +10,7 @@ pub mod cix_capnp {
 }
 
 pub mod book;
+pub mod clock;
 pub mod heap;
 pub mod order;
 
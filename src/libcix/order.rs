@@ -12,9 +12,10 @@ pub mod trade_types {
     use time;
     use uuid;
 
+    // Use as #[serde(with="TimeSpecDef")] on OrderTime fields
     #[derive(Serialize, Deserialize)]
     #[serde(remote = "time::Timespec")]
-    struct TimeSpecDef {
+    pub struct TimeSpecDef {
         pub sec: i64,
         pub nsec: i32
     }
@@ -288,10 +289,12 @@ pub mod trade_types {
         desc: String
     }
 
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
     pub enum ErrorCode {
         Success,
         DuplicateId,
+        UnknownOrder,
+        NotOrderOwner,
         Other
     }
 
@@ -335,7 +338,7 @@ pub mod trade_types {
         }
     }
 
-    #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
     pub enum OrderSide {
         Buy,
         Sell
@@ -533,9 +536,10 @@ pub mod trade_types {
         out.set_nanos(ts.nsec);
     }
 
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
     pub struct Execution {
         pub id:         ExecutionId,
+        #[serde(with="TimeSpecDef")]
         pub ts:         OrderTime,
         pub buy_order:  OrderId,
         pub buy_user:   UserId,
@@ -0,0 +1,516 @@
+use capnp;
+use capnp::capability::Promise;
+use futures::{future, Future};
+use ledger::{Ledger, WithdrawalHold};
+use libcix::cix_capnp as cp;
+use libcix::order::trade_types::*;
+use cp::admin::*;
+use messages::{EngineMessage, FundsChange, FundsMessage, SymbolMessage, SymbolStatus};
+use risk::RiskLimits;
+use session::{OrderRouter, ServerContext, ServerState};
+use std::rc::Rc;
+
+// Runtime limit changes aren't logged anywhere, so whoever makes one has to be told
+const LIMITS_NOT_SAVED: &'static str =
+    "only lasts until the server restarts; add the limits to the user database to keep them";
+
+pub struct AdminSession<R> where R: 'static + Clone + OrderRouter {
+    context: Rc<ServerContext<R>>
+}
+
+impl<R> AdminSession<R> where R: 'static + Clone + OrderRouter {
+    pub fn new(context: Rc<ServerContext<R>>) -> Self {
+        AdminSession {
+            context: context
+        }
+    }
+
+    // Symbols can only change once replay is done, and standbys only take changes from their
+    // primary
+    fn can_change_symbols(&self) -> bool {
+        if let ServerState::Running = self.context.state.get() {
+            true
+        } else {
+            false
+        }
+    }
+
+    // Sends a change for a listed symbol to its engine
+    fn change_symbol(&self, symbol: Symbol,
+                     change: fn(SymbolMessage) -> EngineMessage) -> Result<(), cp::ErrorCode> {
+        let symbol_id = match self.context.router.symbol_status(&symbol) {
+            Some((id, _)) => id,
+            None => { return Err(cp::ErrorCode::InvalidArgs); }
+        };
+
+        self.context.router.route_order(change(SymbolMessage {
+            symbol: symbol,
+            symbol_id: symbol_id
+        })).map_err(|e| {
+            println!("failed to change symbol {}: {}", symbol, e);
+            cp::ErrorCode::Other
+        })
+    }
+
+    // Has the change to the user's cash logged and returns their balance once it's been applied,
+    // or why it couldn't be made.  Changes are made one at a time, and `decide` works out how much
+    // this one changes the balance by once the ledger has caught up with everything before it.
+    // Whatever it holds back is let go once the change has been applied.
+    fn change_funds<F>(&self, user: UserId, change: FundsChange, decide: F)
+            -> Box<Future<Item=Result<f64, cp::ErrorCode>, Error=capnp::Error>>
+            where F: 'static + FnOnce(&Rc<Ledger>)
+                                      -> Result<(f64, Option<WithdrawalHold>), cp::ErrorCode> {
+        // Same as for symbol changes
+        if !self.can_change_symbols() {
+            return Box::new(future::ok(Err(cp::ErrorCode::Unavailable)));
+        }
+
+        // The ledger only hears about executions once the engines send them back
+        let context = self.context.clone();
+        let caught_up = Ledger::funds_turn(&self.context.ledger).and_then(move |turn| {
+            ServerContext::serialization_point(context.clone()).map(move |_| (context, turn))
+        }).map_err(|_| {
+            capnp::Error::failed("failed to wait for earlier funds changes".to_string())
+        });
+
+        Box::new(caught_up.and_then(move |(context, turn)|
+                -> Box<Future<Item=Result<f64, cp::ErrorCode>, Error=capnp::Error>> {
+            let (amount, hold) = match decide(&context.ledger) {
+                Ok(decided) => decided,
+                Err(code) => { return Box::new(future::ok(Err(code))); }
+            };
+
+            if let Err(e) = context.router.route_order(EngineMessage::Funds(FundsMessage {
+                user: user,
+                change: change,
+                amount: amount
+            })) {
+                println!("failed to change funds for user {}: {}", user, e);
+                return Box::new(future::ok(Err(cp::ErrorCode::Other)));
+            }
+
+            println!("{:?} of {} for user {}", change, amount, user);
+
+            Box::new(ServerContext::serialization_point(context.clone()).map(move |_| {
+                drop(hold);
+                drop(turn);
+                Ok(context.ledger.account(user).cash)
+            }).map_err(|_| {
+                capnp::Error::failed("failed to wait for funds change".to_string())
+            }))
+        }))
+    }
+
+    // Engines check symbol changes themselves, so wait until they've seen everything sent so far
+    // and then see whether the symbol ended up how we wanted
+    fn confirm_status(&self, symbol: Symbol, expected: Option<SymbolStatus>)
+            -> Box<Future<Item=cp::ErrorCode, Error=capnp::Error>> {
+        let context = self.context.clone();
+        Box::new(ServerContext::serialization_point(self.context.clone()).map(move |_| {
+            let status = context.router.symbol_status(&symbol).map(|(_, status)| status);
+            if status == expected {
+                cp::ErrorCode::Ok
+            } else {
+                println!("symbol {} is {:?} instead of {:?}", symbol, status, expected);
+                cp::ErrorCode::Other
+            }
+        }).map_err(|e| {
+            capnp::Error::failed("failed to wait for symbol change".to_string())
+        }))
+    }
+}
+
+fn valid_amount(amount: f64) -> bool {
+    amount >= 0.0 && !amount.is_infinite()
+}
+
+fn read_risk_limits(limits: cp::risk_limits::Reader) -> RiskLimits {
+    RiskLimits {
+        max_order_quantity: limits.get_max_order_quantity(),
+        max_order_notional: limits.get_max_order_notional(),
+        max_open_orders: limits.get_max_open_orders(),
+        max_symbol_exposure: limits.get_max_symbol_exposure(),
+        max_total_exposure: limits.get_max_total_exposure(),
+        max_price_deviation: limits.get_max_price_deviation()
+    }
+}
+
+fn write_risk_limits(limits: &RiskLimits, mut builder: cp::risk_limits::Builder) {
+    builder.set_max_order_quantity(limits.max_order_quantity);
+    builder.set_max_order_notional(limits.max_order_notional);
+    builder.set_max_open_orders(limits.max_open_orders);
+    builder.set_max_symbol_exposure(limits.max_symbol_exposure);
+    builder.set_max_total_exposure(limits.max_total_exposure);
+    builder.set_max_price_deviation(limits.max_price_deviation);
+}
+
+impl<R> Server for AdminSession<R> where R: 'static + Clone + OrderRouter {
+    fn promote(&mut self, _params: PromoteParams, mut results: PromoteResults)
+               -> Promise<(), capnp::Error> {
+        let standby = match self.context.standby.borrow_mut().take() {
+            Some(s) => s,
+            None => {
+                results.get().set_code(cp::ErrorCode::InvalidArgs);
+                return Promise::ok(());
+            }
+        };
+
+        println!("promoting standby to primary");
+        standby.handle.stop();
+
+        // Once the replication thread exits everything it received has been handed to the
+        // engines, and a serialization point after that tells us they've all been applied
+        let context = self.context.clone();
+        Promise::from_future(standby.drained.then(move |_| {
+            let c = context.clone();
+            ServerContext::serialization_point(context).map(move |_| c)
+        }).and_then(move |context| {
+            context.state.set(ServerState::Running);
+            println!("promotion complete, accepting sessions");
+            results.get().set_code(cp::ErrorCode::Ok);
+            Ok(())
+        }).map_err(|e| {
+            capnp::Error::failed("failed to promote standby".to_string())
+        }))
+    }
+
+    fn replication_status(&mut self, _params: ReplicationStatusParams,
+                          mut results: ReplicationStatusResults)
+                          -> Promise<(), capnp::Error> {
+        results.get().set_code(cp::ErrorCode::Ok);
+
+        let standby = self.context.standby.borrow();
+        let status = match *standby {
+            Some(ref s) => s.handle.status.lock().unwrap().clone(),
+            None => {
+                results.get().init_status().set_standby(false);
+                return Promise::ok(());
+            }
+        };
+
+        let mut builder = results.get().init_status();
+        builder.set_standby(true);
+        builder.set_connected(status.connected);
+        builder.set_latency_micros(status.latency.and_then(|l| l.num_microseconds()).unwrap_or(-1));
+
+        let mut engines = builder.init_engines(status.primary.len() as u32);
+        for (i, (engine, primary)) in status.primary.iter().enumerate() {
+            let mut e = engines.borrow().get(i as u32);
+            e.set_engine(*engine);
+            e.set_primary_index(primary.index);
+            e.set_primary_offset(primary.offset);
+
+            if let Some(received) = status.received.get(engine) {
+                e.set_received_index(received.index);
+                e.set_received_offset(received.offset);
+            }
+        }
+
+        Promise::ok(())
+    }
+    fn list_symbol(&mut self, params: ListSymbolParams, mut results: ListSymbolResults)
+                   -> Promise<(), capnp::Error> {
+        if !self.can_change_symbols() {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
+        let symbol = pry!(Symbol::from_capnp(pry!(pry!(params.get()).get_symbol())).map_err(|e| {
+            capnp::Error::failed("invalid symbol".to_string())
+        }));
+
+        let symbol_id = match self.context.router.list_symbol(symbol) {
+            Ok(id) => id,
+            Err(e) => {
+                println!("failed to list symbol {}: {}", symbol, e);
+                results.get().set_code(cp::ErrorCode::InvalidArgs);
+                return Promise::ok(());
+            }
+        };
+
+        Promise::from_future(self.confirm_status(symbol, Some(SymbolStatus::Active))
+                             .map(move |code| {
+            results.get().set_code(code);
+            results.get().set_id(symbol_id);
+        }))
+    }
+
+    fn suspend_symbol(&mut self, params: SuspendSymbolParams, mut results: SuspendSymbolResults)
+                      -> Promise<(), capnp::Error> {
+        if !self.can_change_symbols() {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
+        let symbol = pry!(Symbol::from_capnp(pry!(pry!(params.get()).get_symbol())).map_err(|e| {
+            capnp::Error::failed("invalid symbol".to_string())
+        }));
+
+        if let Err(code) = self.change_symbol(symbol, EngineMessage::SuspendSymbol) {
+            results.get().set_code(code);
+            return Promise::ok(());
+        }
+
+        Promise::from_future(self.confirm_status(symbol, Some(SymbolStatus::Suspended))
+                             .map(move |code| results.get().set_code(code)))
+    }
+
+    fn resume_symbol(&mut self, params: ResumeSymbolParams, mut results: ResumeSymbolResults)
+                     -> Promise<(), capnp::Error> {
+        if !self.can_change_symbols() {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
+        let symbol = pry!(Symbol::from_capnp(pry!(pry!(params.get()).get_symbol())).map_err(|e| {
+            capnp::Error::failed("invalid symbol".to_string())
+        }));
+
+        if let Err(code) = self.change_symbol(symbol, EngineMessage::ResumeSymbol) {
+            results.get().set_code(code);
+            return Promise::ok(());
+        }
+
+        Promise::from_future(self.confirm_status(symbol, Some(SymbolStatus::Active))
+                             .map(move |code| results.get().set_code(code)))
+    }
+
+    fn delist_symbol(&mut self, params: DelistSymbolParams, mut results: DelistSymbolResults)
+                     -> Promise<(), capnp::Error> {
+        if !self.can_change_symbols() {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
+        let symbol = pry!(Symbol::from_capnp(pry!(pry!(params.get()).get_symbol())).map_err(|e| {
+            capnp::Error::failed("invalid symbol".to_string())
+        }));
+
+        if let Err(code) = self.change_symbol(symbol, EngineMessage::DelistSymbol) {
+            results.get().set_code(code);
+            return Promise::ok(());
+        }
+
+        // Delisted symbols drop out of the lookup entirely
+        Promise::from_future(self.confirm_status(symbol, None)
+                             .map(move |code| results.get().set_code(code)))
+    }
+    fn engine_stats(&mut self, _params: EngineStatsParams, mut results: EngineStatsResults)
+                    -> Promise<(), capnp::Error> {
+        results.get().set_code(cp::ErrorCode::Ok);
+
+        let mut engines = results.get().init_engines(self.context.engine_stats.len() as u32);
+        for (i, &(engine_id, ref stats)) in self.context.engine_stats.iter().enumerate() {
+            let mut e = engines.borrow().get(i as u32);
+            e.set_engine(engine_id);
+            e.set_errors(stats.errors() as u64);
+            e.set_last_error(stats.last_error().unwrap_or_default().as_str());
+
+            let rejects = stats.rejects();
+            let mut counts = e.init_rejects(rejects.len() as u32);
+            for (j, (reason, count)) in rejects.iter().enumerate() {
+                let mut c = counts.borrow().get(j as u32);
+                c.set_reason(reason.to_string().as_str());
+                c.set_count(*count);
+            }
+        }
+
+        Promise::ok(())
+    }
+
+    fn latency_stats(&mut self, _params: LatencyStatsParams, mut results: LatencyStatsResults)
+                     -> Promise<(), capnp::Error> {
+        results.get().set_code(cp::ErrorCode::Ok);
+
+        let summaries = self.context.latency.summaries();
+        let mut series = results.get().init_series(summaries.len() as u32);
+        for (i, (kind, symbol, stages)) in summaries.into_iter().enumerate() {
+            let mut s = series.borrow().get(i as u32);
+            s.set_kind(kind.to_string().as_str());
+            s.set_symbol(symbol.to_string().as_str());
+
+            let mut stages_out = s.init_stages(stages.len() as u32);
+            for (j, (stage, summary)) in stages.into_iter().enumerate() {
+                let mut out = stages_out.borrow().get(j as u32);
+                out.set_stage(stage.to_string().as_str());
+                out.set_count(summary.count);
+                out.set_mean(summary.mean);
+                out.set_p50(summary.p50);
+                out.set_p90(summary.p90);
+                out.set_p99(summary.p99);
+                out.set_p999(summary.p999);
+                out.set_max(summary.max);
+            }
+        }
+
+        Promise::ok(())
+    }
+    fn shutdown(&mut self, params: ShutdownParams, mut results: ShutdownResults)
+                -> Promise<(), capnp::Error> {
+        let snapshot = pry!(params.get()).get_snapshot();
+
+        if let ServerState::ShuttingDown = self.context.state.get() {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
+        // The reply has to go out while the reactor is still running, so don't wait for it
+        self.context.handle.spawn(ServerContext::shutdown(self.context.clone(), snapshot));
+        results.get().set_code(cp::ErrorCode::Ok);
+        Promise::ok(())
+    }
+
+    fn risk_status(&mut self, params: RiskStatusParams, mut results: RiskStatusResults)
+                   -> Promise<(), capnp::Error> {
+        let user = pry!(params.get()).get_user();
+
+        if !self.context.auth.users().has_id(user) {
+            results.get().set_code(cp::ErrorCode::InvalidArgs);
+            return Promise::ok(());
+        }
+
+        results.get().set_code(cp::ErrorCode::Ok);
+        write_risk_limits(&self.context.risk.limits(user), results.get().init_limits());
+
+        let exposure = self.context.risk.exposure(user);
+        let mut builder = results.get().init_exposure();
+        builder.set_open_orders(exposure.open_orders);
+        builder.set_total(exposure.total);
+
+        let mut symbols = builder.init_symbols(exposure.by_symbol.len() as u32);
+        for (i, (symbol, notional)) in exposure.by_symbol.iter().enumerate() {
+            let mut s = symbols.borrow().get(i as u32);
+            s.set_symbol(symbol.as_str());
+            s.set_notional(*notional);
+        }
+
+        Promise::ok(())
+    }
+
+    fn set_risk_limits(&mut self, params: SetRiskLimitsParams, mut results: SetRiskLimitsResults)
+                       -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let user = params.get_user();
+        let limits = read_risk_limits(pry!(params.get_limits()));
+
+        if !self.context.auth.users().has_id(user) {
+            results.get().set_code(cp::ErrorCode::InvalidArgs);
+            return Promise::ok(());
+        }
+
+        if let Err(e) = limits.validate() {
+            println!("refused risk limits for user {}: {}", user, e);
+            results.get().set_code(cp::ErrorCode::InvalidArgs);
+            return Promise::ok(());
+        }
+
+        println!("risk limits for user {} are now {:?} until the server restarts", user, limits);
+        self.context.risk.set_limits(user, limits);
+        results.get().set_code(cp::ErrorCode::Ok);
+        results.get().set_warning(LIMITS_NOT_SAVED);
+        Promise::ok(())
+    }
+
+    fn export_statement(&mut self, _params: ExportStatementParams,
+                        mut results: ExportStatementResults)
+                        -> Promise<(), capnp::Error> {
+        match self.context.ledger.export_statement() {
+            Ok(path) => {
+                println!("wrote statement to {}", path.display());
+                results.get().set_code(cp::ErrorCode::Ok);
+                results.get().set_path(path.to_string_lossy().as_ref());
+            },
+            Err(e) => {
+                println!("failed to export statement: {}", e);
+                results.get().set_code(cp::ErrorCode::Other);
+            }
+        }
+
+        Promise::ok(())
+    }
+
+    fn deposit(&mut self, params: DepositParams, mut results: DepositResults)
+               -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let user = params.get_user();
+        let amount = params.get_amount();
+
+        if !self.context.auth.users().has_id(user) || !valid_amount(amount) {
+            results.get().set_code(cp::ErrorCode::InvalidArgs);
+            return Promise::ok(());
+        }
+
+        Promise::from_future(self.change_funds(user, FundsChange::Deposit, move |_| {
+            Ok((amount, None))
+        }).map(move |applied| {
+            match applied {
+                Ok(balance) => {
+                    results.get().set_code(cp::ErrorCode::Ok);
+                    results.get().set_balance(balance);
+                },
+                Err(code) => { results.get().set_code(code); }
+            }
+        }))
+    }
+
+    fn withdraw(&mut self, params: WithdrawParams, mut results: WithdrawResults)
+                -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let user = params.get_user();
+        let amount = params.get_amount();
+
+        if !self.context.auth.users().has_id(user) || !valid_amount(amount) {
+            results.get().set_code(cp::ErrorCode::InvalidArgs);
+            return Promise::ok(());
+        }
+
+        // Held back until it's been applied, so that neither another withdrawal nor an order can
+        // spend the same cash in the meantime
+        Promise::from_future(self.change_funds(user, FundsChange::Withdrawal, move |ledger| {
+            match Ledger::hold_withdrawal(ledger, user, amount) {
+                Ok(hold) => Ok((-amount, Some(hold))),
+                Err(_) => Err(cp::ErrorCode::InsufficientFunds)
+            }
+        }).map(move |applied| {
+            match applied {
+                Ok(balance) => {
+                    results.get().set_code(cp::ErrorCode::Ok);
+                    results.get().set_balance(balance);
+                },
+                Err(code) => { results.get().set_code(code); }
+            }
+        }))
+    }
+
+    // Logged as the difference from the current balance, which nothing else can change in the
+    // meantime apart from trading.  Anything traded from here on comes on top of the new balance.
+    fn set_balance(&mut self, params: SetBalanceParams, mut results: SetBalanceResults)
+                   -> Promise<(), capnp::Error> {
+        let params = pry!(params.get());
+        let user = params.get_user();
+        let balance = params.get_balance();
+
+        if !self.context.auth.users().has_id(user) || !valid_amount(balance) {
+            results.get().set_code(cp::ErrorCode::InvalidArgs);
+            return Promise::ok(());
+        }
+
+        Promise::from_future(self.change_funds(user, FundsChange::SetBalance, move |ledger| {
+            // Open buys have to stay covered
+            let account = ledger.account(user);
+            if balance < account.reserved_cash {
+                return Err(cp::ErrorCode::InsufficientFunds);
+            }
+
+            Ok((balance - account.cash, None))
+        }).map(move |applied| {
+            match applied {
+                Ok(balance) => {
+                    results.get().set_code(cp::ErrorCode::Ok);
+                    results.get().set_balance(balance);
+                },
+                Err(code) => { results.get().set_code(code); }
+            }
+        }))
+    }
+}
@@ -0,0 +1,489 @@
+use crypto::pbkdf2::{pbkdf2_check, pbkdf2_simple};
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use libcix::order::trade_types::{Symbol, UserId};
+use risk::RiskLimits;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use toml;
+
+// PBKDF2-SHA256 rounds for new hashes.  Hashes record their own count so this can go up without
+// invalidating old ones.
+pub const HASH_ITERATIONS: u32 = 10000;
+// Failed attempts in a row before a username is locked out
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT_SECS: u64 = 30;
+// Usernames whose lockout has expired are forgotten once this many are being tracked, so that
+// guessing random names can't use up memory
+const MAX_TRACKED: usize = 10000;
+
+// Salted hash of a password or API key, in the form written by hash_secret
+pub fn hash_secret(secret: &str) -> Result<String, String> {
+    pbkdf2_simple(secret, HASH_ITERATIONS).map_err(|e| format!("failed to hash secret: {}", e))
+}
+
+fn secret_matches(secret: &str, hash: &str) -> bool {
+    pbkdf2_check(secret, hash).unwrap_or(false)
+}
+
+// What a client proves it's a user with
+pub enum Credential {
+    Password(String),
+    ApiKey(String)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuthFailure {
+    // Unknown user or wrong secret.  These look the same from outside, and take as long.
+    Invalid,
+    // Too many recent failures for this username
+    RateLimited
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Trader,
+    MarketMaker,
+    // Can look at their own orders but not trade
+    ReadOnly,
+    Admin
+}
+
+// What a user is allowed to do.  Each role has defaults which the user's own entry can override.
+#[derive(Clone, Debug)]
+pub struct Permissions {
+    pub role: Role,
+    // None allows every symbol
+    pub symbols: Option<HashSet<Symbol>>,
+    // Whether they may send market orders.  Every order is a limit order for now, so this is
+    // accepted but has no effect until market orders exist.
+    pub market_orders: bool,
+    // Whether sells can go beyond what the user holds, when the server checks that at all
+    pub short_selling: bool,
+    pub admin: bool
+}
+
+impl Permissions {
+    // For sessions that haven't signed in
+    pub fn none() -> Self {
+        Permissions {
+            role: Role::ReadOnly,
+            symbols: Some(HashSet::new()),
+            market_orders: false,
+            short_selling: false,
+            admin: false
+        }
+    }
+
+    fn for_role(role: Role) -> Self {
+        Permissions {
+            role: role,
+            symbols: None,
+            // Market makers are there to quote, not to take
+            market_orders: match role {
+                Role::Trader | Role::Admin => true,
+                Role::MarketMaker | Role::ReadOnly => false
+            },
+            // Market makers have to be able to quote both sides without holding inventory
+            short_selling: role == Role::MarketMaker,
+            admin: role == Role::Admin
+        }
+    }
+
+    pub fn can_trade(&self, symbol: &Symbol) -> bool {
+        self.role != Role::ReadOnly &&
+            self.symbols.as_ref().map(|s| s.contains(symbol)).unwrap_or(true)
+    }
+
+    // Not limited by symbol, so that taking a symbol away from someone doesn't leave them stuck
+    // with orders they can't pull
+    pub fn can_cancel(&self) -> bool {
+        self.role != Role::ReadOnly
+    }
+
+    // Every role can see its own orders
+    pub fn can_view_orders(&self) -> bool {
+        true
+    }
+
+    pub fn can_administer(&self) -> bool {
+        self.admin
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserDatabaseFile {
+    #[serde(default)]
+    users: Vec<UserRecord>
+}
+
+// One user as written in the database.  Secrets are only ever stored hashed.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserRecord {
+    name: String,
+    id: UserId,
+    password: Option<String>,
+    #[serde(default)]
+    api_keys: Vec<String>,
+    role: Role,
+    // Anything given here overrides the role's default
+    symbols: Option<Vec<String>>,
+    market_orders: Option<bool>,
+    short_selling: Option<bool>,
+    admin: Option<bool>,
+    // Replaces the server's default risk limits rather than adding to them
+    risk: Option<RiskLimits>
+}
+
+pub struct User {
+    pub id: UserId,
+    password: Option<String>,
+    api_keys: Vec<String>,
+    pub permissions: Permissions,
+    pub risk: Option<RiskLimits>
+}
+
+impl User {
+    fn from_record(record: UserRecord) -> Result<Self, String> {
+        let mut permissions = Permissions::for_role(record.role);
+
+        if let Some(symbols) = record.symbols {
+            let mut allowed = HashSet::new();
+            for name in symbols.iter() {
+                allowed.insert(try!(Symbol::from_str(name.as_str()).map_err(|_| {
+                    format!("user {} has invalid symbol {:?}", record.name, name)
+                })));
+            }
+            permissions.symbols = Some(allowed);
+        }
+
+        if let Some(market_orders) = record.market_orders {
+            permissions.market_orders = market_orders;
+        }
+
+        if let Some(short_selling) = record.short_selling {
+            permissions.short_selling = short_selling;
+        }
+
+        if let Some(admin) = record.admin {
+            permissions.admin = admin;
+        }
+
+        if let Some(ref risk) = record.risk {
+            try!(risk.validate().map_err(|e| {
+                format!("user {} has invalid risk limits: {}", record.name, e)
+            }));
+        }
+
+        Ok(User {
+            id: record.id,
+            password: record.password,
+            api_keys: record.api_keys,
+            permissions: permissions,
+            risk: record.risk
+        })
+    }
+
+    // Hashes that the credential could match
+    fn hashes_for(&self, credential: &Credential) -> Vec<String> {
+        match *credential {
+            Credential::Password(_) => self.password.iter().cloned().collect(),
+            Credential::ApiKey(_) => self.api_keys.clone()
+        }
+    }
+}
+
+// Everyone who can open a trading session, by username
+pub struct UserDatabase {
+    users: HashMap<String, User>
+}
+
+impl UserDatabase {
+    // Nobody can sign in
+    pub fn empty() -> Self {
+        UserDatabase {
+            users: HashMap::new()
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| {
+            format!("failed to read {}: {}", path.display(), e)
+        }));
+
+        let file: UserDatabaseFile = try!(toml::from_str(contents.as_str()).map_err(|e| {
+            format!("failed to parse {}: {}", path.display(), e)
+        }));
+
+        let mut db = UserDatabase::empty();
+        let mut ids = HashMap::new();
+
+        for record in file.users.into_iter() {
+            // Sessions that haven't signed in are user 0
+            if record.id == 0 {
+                return Err(format!("user {} can't have id 0", record.name));
+            }
+
+            if let Some(other) = ids.insert(record.id, record.name.clone()) {
+                return Err(format!("users {} and {} both have id {}", other, record.name,
+                                   record.id));
+            }
+
+            if record.password.is_none() && record.api_keys.is_empty() {
+                println!("user {} has no password or API keys and can't sign in", record.name);
+            }
+
+            let name = record.name.clone();
+            if db.users.insert(name.clone(), try!(User::from_record(record))).is_some() {
+                return Err(format!("user {} is listed more than once", name));
+            }
+        }
+
+        Ok(db)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn has_id(&self, id: UserId) -> bool {
+        self.users.values().any(|u| u.id == id)
+    }
+
+    // For users that have their own
+    pub fn risk_limits(&self) -> HashMap<UserId, RiskLimits> {
+        self.users.values().filter_map(|u| u.risk.map(|r| (u.id, r))).collect()
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant
+}
+
+// Who a session signed in as
+pub type AuthFuture = Box<Future<Item = (UserId, Permissions), Error = AuthFailure>>;
+
+// Checks credentials against the user database, locking out usernames that fail too often.  Only
+// used from the reactor thread, although the hashing itself is done on a pool so that it doesn't
+// hold up everything else.
+pub struct Authenticator {
+    users: UserDatabase,
+    failures: Rc<RefCell<HashMap<String, Failures>>>,
+    pool: CpuPool,
+    // Checked instead when there's nothing to check against, so that unknown users take as long to
+    // turn down as wrong secrets
+    dummy_hash: String
+}
+
+impl Authenticator {
+    pub fn new(users: UserDatabase) -> Self {
+        Authenticator {
+            users: users,
+            failures: Rc::new(RefCell::new(HashMap::new())),
+            pool: CpuPool::new_num_cpus(),
+            dummy_hash: hash_secret("").unwrap_or_else(|e| panic!("{}", e))
+        }
+    }
+
+    pub fn users(&self) -> &UserDatabase {
+        &self.users
+    }
+
+    // Each attempt counts as a failure until it turns out to be right, so that attempts made
+    // before earlier ones have been checked can't get past the lockout.  Unknown usernames are
+    // counted too, so that a lockout doesn't give away which ones exist.
+    pub fn authenticate(&self, name: &str, credential: Credential) -> AuthFuture {
+        let lockout = Duration::from_secs(LOCKOUT_SECS);
+
+        {
+            let mut failures = self.failures.borrow_mut();
+            if let Some(f) = failures.get(name) {
+                if f.count >= MAX_FAILURES && f.last.elapsed() < lockout {
+                    return Box::new(future::err(AuthFailure::RateLimited));
+                }
+            }
+
+            if failures.len() >= MAX_TRACKED {
+                failures.retain(|_, f| f.last.elapsed() < lockout);
+            }
+
+            let f = failures.entry(name.to_string()).or_insert(Failures {
+                count: 0,
+                last: Instant::now()
+            });
+            f.count += 1;
+            f.last = Instant::now();
+        }
+
+        let user = self.users.get(name);
+        let found = user.map(|u| (u.id, u.permissions.clone()));
+        let hashes = user.map(|u| u.hashes_for(&credential)).unwrap_or(Vec::new());
+        // The dummy hash never counts as a match, even for a secret that happens to fit it
+        let real = !hashes.is_empty();
+        let hashes = if real { hashes } else { vec![self.dummy_hash.clone()] };
+        let secret = match credential {
+            Credential::Password(p) => p,
+            Credential::ApiKey(k) => k
+        };
+
+        let failures = self.failures.clone();
+        let name = name.to_string();
+
+        Box::new(self.pool.spawn_fn(move || {
+            let matched = hashes.iter().any(|h| secret_matches(secret.as_str(), h));
+            Ok::<bool, ()>(matched && real)
+        }).then(move |matched| {
+            match (matched, found) {
+                (Ok(true), Some(found)) => {
+                    failures.borrow_mut().remove(&name);
+                    Ok(found)
+                },
+                _ => Err(AuthFailure::Invalid)
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Cheap hashes, since what's being tested isn't the hashing
+    fn user(id: UserId, password: Option<&str>, api_keys: &[&str]) -> User {
+        User {
+            id: id,
+            password: password.map(|p| pbkdf2_simple(p, 1).unwrap()),
+            api_keys: api_keys.iter().map(|k| pbkdf2_simple(k, 1).unwrap()).collect(),
+            permissions: Permissions::for_role(Role::Trader),
+            risk: None
+        }
+    }
+
+    fn authenticator() -> Authenticator {
+        let mut users = UserDatabase::empty();
+        users.users.insert("alice".to_string(), user(1, Some("hunter2"), &["key1", "key2"]));
+        users.users.insert("bob".to_string(), user(2, None, &["key3"]));
+        Authenticator::new(users)
+    }
+
+    fn sign_in(auth: &Authenticator, name: &str, credential: Credential)
+            -> Result<UserId, AuthFailure> {
+        auth.authenticate(name, credential).wait().map(|(id, _)| id)
+    }
+
+    fn password(p: &str) -> Credential {
+        Credential::Password(p.to_string())
+    }
+
+    fn api_key(k: &str) -> Credential {
+        Credential::ApiKey(k.to_string())
+    }
+
+    #[test]
+    fn market_orders_can_be_set_per_user() {
+        let file: UserDatabaseFile = toml::from_str(r#"
+            [[users]]
+            name = "alice"
+            id = 1
+            role = "trader"
+            market_orders = false
+
+            [[users]]
+            name = "bob"
+            id = 2
+            role = "market_maker"
+            market_orders = true
+        "#).unwrap();
+        let users: Vec<User> = file.users.into_iter().map(|r| User::from_record(r).unwrap())
+            .collect();
+
+        assert!(!users[0].permissions.market_orders);
+        assert!(users[1].permissions.market_orders);
+        assert!(Permissions::for_role(Role::Trader).market_orders);
+        assert!(!Permissions::for_role(Role::MarketMaker).market_orders);
+    }
+
+    #[test]
+    fn signs_in_with_a_password_or_any_api_key() {
+        let auth = authenticator();
+
+        assert_eq!(sign_in(&auth, "alice", password("hunter2")), Ok(1));
+        assert_eq!(sign_in(&auth, "alice", api_key("key2")), Ok(1));
+        assert_eq!(sign_in(&auth, "bob", api_key("key3")), Ok(2));
+    }
+
+    #[test]
+    fn unknown_users_fail_like_wrong_secrets() {
+        let auth = authenticator();
+
+        assert_eq!(sign_in(&auth, "alice", password("hunter3")), Err(AuthFailure::Invalid));
+        assert_eq!(sign_in(&auth, "alice", api_key("key3")), Err(AuthFailure::Invalid));
+        assert_eq!(sign_in(&auth, "bob", password("")), Err(AuthFailure::Invalid));
+        assert_eq!(sign_in(&auth, "carol", password("hunter2")), Err(AuthFailure::Invalid));
+        // Would match the dummy hash
+        assert_eq!(sign_in(&auth, "carol", password("")), Err(AuthFailure::Invalid));
+        assert_eq!(sign_in(&auth, "carol", api_key("")), Err(AuthFailure::Invalid));
+    }
+
+    #[test]
+    fn repeated_failures_lock_out_any_username() {
+        let auth = authenticator();
+
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(sign_in(&auth, "alice", password("wrong")), Err(AuthFailure::Invalid));
+            assert_eq!(sign_in(&auth, "carol", password("wrong")), Err(AuthFailure::Invalid));
+        }
+
+        assert_eq!(sign_in(&auth, "alice", password("hunter2")), Err(AuthFailure::RateLimited));
+        assert_eq!(sign_in(&auth, "carol", password("wrong")), Err(AuthFailure::RateLimited));
+        // Only the names that failed are locked out
+        assert_eq!(sign_in(&auth, "bob", api_key("key3")), Ok(2));
+    }
+
+    #[test]
+    fn attempts_still_being_checked_count_towards_the_lockout() {
+        let auth = authenticator();
+
+        // All sent before any of them have been checked, as a pipelining client could
+        let attempts: Vec<AuthFuture> = (0..MAX_FAILURES + 1).map(|_| {
+            auth.authenticate("alice", password("wrong"))
+        }).collect();
+        let results: Vec<Result<UserId, AuthFailure>> = attempts.into_iter().map(|a| {
+            a.wait().map(|(id, _)| id)
+        }).collect();
+
+        let invalid = results.iter().filter(|r| **r == Err(AuthFailure::Invalid)).count();
+        assert_eq!(invalid, MAX_FAILURES as usize);
+        assert_eq!(results[MAX_FAILURES as usize], Err(AuthFailure::RateLimited));
+        assert_eq!(sign_in(&auth, "alice", password("hunter2")), Err(AuthFailure::RateLimited));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let auth = authenticator();
+
+        for _ in 0..MAX_FAILURES - 1 {
+            assert_eq!(sign_in(&auth, "alice", password("wrong")), Err(AuthFailure::Invalid));
+        }
+        assert_eq!(sign_in(&auth, "alice", password("hunter2")), Ok(1));
+
+        for _ in 0..MAX_FAILURES - 1 {
+            assert_eq!(sign_in(&auth, "alice", password("wrong")), Err(AuthFailure::Invalid));
+        }
+        assert_eq!(sign_in(&auth, "alice", password("hunter2")), Ok(1));
+    }
+}
@@ -0,0 +1,46 @@
+extern crate crypto;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate libcix;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate uuid;
+
+mod auth;
+mod risk;
+
+use std::env::args;
+use std::io;
+use uuid::Uuid;
+
+// usage: cixpasswd [--api-key]
+// Prints a hash to put in the user database.  Reads the password from the first line of stdin,
+// or with --api-key makes up a new key and prints that as well.
+fn main() {
+    let cli_args: Vec<String> = args().skip(1).collect();
+    let api_key = match cli_args.len() {
+        0 => false,
+        1 if cli_args[0] == "--api-key" => true,
+        _ => panic!("usage: cixpasswd [--api-key]")
+    };
+
+    let secret = if api_key {
+        let key = Uuid::new_v4().simple().to_string();
+        println!("key:  {}", key);
+        key
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).unwrap_or_else(|e| {
+            panic!("failed to read password: {}", e)
+        });
+        line.trim_right_matches(|c| c == '\r' || c == '\n').to_string()
+    };
+
+    if secret.is_empty() {
+        panic!("empty password");
+    }
+
+    println!("hash: {}", auth::hash_secret(secret.as_str()).unwrap_or_else(|e| panic!("{}", e)));
+}
@@ -0,0 +1,377 @@
+use engine::{EngineConfig, InstrumentParams, DEFAULT_BATCH_SIZE, DEFAULT_BUFFER_SIZE};
+use libcix::order::trade_types::*;
+use risk::RiskLimits;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml;
+use transport::TransportKind;
+use wal::{ArchiveFormat, ReplayLimit, WalConfig};
+
+pub const DEFAULT_WAL_SEGMENT_SIZE: usize = 10 * 1024 * 1024;
+// Smaller segments would rotate after only a handful of orders
+pub const MIN_WAL_SEGMENT_SIZE: usize = 4096;
+
+// Everything cixsrv can be told on startup besides what it should do with its log.  Every section
+// and field is optional, and anything left out gets the same value the server used before it had
+// a config file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: ListenConfig,
+    pub wal: WalSettings,
+    pub engines: EngineSettings,
+    pub market_data: MarketDataSettings,
+    pub latency: LatencySettings,
+    pub shutdown: ShutdownSettings,
+    pub auth: AuthSettings,
+    // For users that don't have their own in the user database
+    pub risk: RiskLimits,
+    pub ledger: LedgerSettings,
+    pub symbols: Vec<SymbolSettings>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    // Trading and admin sessions
+    pub sessions: String,
+    // Standbys following this server
+    pub replication: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalSettings {
+    pub dir: PathBuf,
+    // Standbys have to use the same segment size as their primary so that entries land at the
+    // same positions
+    pub segment_size: usize,
+    // none, gzip or zlib
+    pub archive: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineSettings {
+    pub count: u32,
+    // How many requests can be waiting for an engine (or for sessions to hear back from the
+    // engines) before senders block
+    pub buffer_size: usize,
+    // Most requests an engine handles before sending out what came of them
+    pub batch_size: usize,
+    // channel, ring-poll or ring-park
+    pub transport: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarketDataSettings {
+    // Least time between updates for a book.  0 publishes after every batch of requests.
+    pub interval_ms: u64
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LatencySettings {
+    // How often order latency stats are printed.  0 never prints them; they can still be fetched
+    // over the admin interface.
+    pub dump_interval_secs: u64
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSettings {
+    // Whether engines write a snapshot of their books when the server is stopped by a signal.
+    // Shutting down over the admin interface says for itself.
+    pub snapshot: bool
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    // Users who can open trading sessions.  Without one nobody can.
+    pub users: Option<PathBuf>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LedgerSettings {
+    // Where end-of-day statements are written when asked for over the admin interface.  Without
+    // one they can't be.
+    pub statement_dir: Option<PathBuf>,
+    // Turn down buys the user's cash can't cover and sells of shares they don't have, unless
+    // they're allowed to short
+    pub require_funds: bool
+}
+
+// Symbol IDs are positions in this list, so symbols can only ever be added to the end of it.
+// Anything listed at runtime is remembered by the engines' logs instead.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SymbolSettings {
+    pub name: String,
+    // Spread by a hash of the name if not given
+    pub engine: Option<u32>,
+    pub tick_size: Option<Price>,
+    pub lot_size: Option<Quantity>,
+    pub max_quantity: Option<Quantity>
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: ListenConfig::default(),
+            wal: WalSettings::default(),
+            engines: EngineSettings::default(),
+            market_data: MarketDataSettings::default(),
+            latency: LatencySettings::default(),
+            shutdown: ShutdownSettings::default(),
+            auth: AuthSettings::default(),
+            risk: RiskLimits::default(),
+            ledger: LedgerSettings::default(),
+            symbols: vec!["AAPL", "FB", "GOOG"].into_iter().map(|s| {
+                SymbolSettings::new(s)
+            }).collect()
+        }
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            sessions: "localhost:2468".to_string(),
+            replication: "localhost:2469".to_string()
+        }
+    }
+}
+
+impl Default for WalSettings {
+    fn default() -> Self {
+        WalSettings {
+            dir: PathBuf::from("/home/brendon/wal"),
+            segment_size: DEFAULT_WAL_SEGMENT_SIZE,
+            archive: "none".to_string()
+        }
+    }
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        EngineSettings {
+            count: 1,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            batch_size: DEFAULT_BATCH_SIZE,
+            transport: "channel".to_string()
+        }
+    }
+}
+
+impl Default for MarketDataSettings {
+    fn default() -> Self {
+        MarketDataSettings {
+            interval_ms: 1000
+        }
+    }
+}
+
+impl Default for LatencySettings {
+    fn default() -> Self {
+        LatencySettings {
+            dump_interval_secs: 60
+        }
+    }
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        ShutdownSettings {
+            snapshot: false
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            users: None
+        }
+    }
+}
+
+impl Default for LedgerSettings {
+    fn default() -> Self {
+        LedgerSettings {
+            statement_dir: None,
+            require_funds: false
+        }
+    }
+}
+
+impl SymbolSettings {
+    pub fn new(name: &str) -> Self {
+        SymbolSettings {
+            name: name.to_string(),
+            engine: None,
+            tick_size: None,
+            lot_size: None,
+            max_quantity: None
+        }
+    }
+
+    fn instrument(&self) -> InstrumentParams {
+        let defaults = InstrumentParams::default();
+
+        InstrumentParams {
+            tick_size: self.tick_size.unwrap_or(defaults.tick_size),
+            lot_size: self.lot_size.unwrap_or(defaults.lot_size),
+            max_quantity: self.max_quantity.unwrap_or(defaults.max_quantity)
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| {
+            format!("failed to read {}: {}", path.display(), e)
+        }));
+
+        toml::from_str(contents.as_str()).map_err(|e| {
+            format!("failed to parse {}: {}", path.display(), e)
+        })
+    }
+
+    // Checks everything at once so that a bad config only takes one round trip to fix
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+
+        for &(name, addr) in [("listen.sessions", &self.listen.sessions),
+                              ("listen.replication", &self.listen.replication)].iter() {
+            if let Err(e) = resolve(addr) {
+                problems.push(format!("{}: {}", name, e));
+            }
+        }
+
+        if self.wal.dir.as_os_str().is_empty() {
+            problems.push("wal.dir is empty".to_string());
+        }
+
+        if self.wal.segment_size < MIN_WAL_SEGMENT_SIZE {
+            problems.push(format!("wal.segment_size has to be at least {} bytes",
+                                  MIN_WAL_SEGMENT_SIZE));
+        }
+
+        if let Err(e) = ArchiveFormat::parse(self.wal.archive.as_str()) {
+            problems.push(format!("wal.archive: {}", e));
+        }
+
+        if self.engines.count == 0 {
+            problems.push("engines.count has to be at least 1".to_string());
+        }
+
+        if self.engines.buffer_size == 0 {
+            problems.push("engines.buffer_size has to be at least 1".to_string());
+        }
+
+        if self.engines.batch_size == 0 {
+            problems.push("engines.batch_size has to be at least 1".to_string());
+        }
+
+        if let Err(e) = TransportKind::parse(self.engines.transport.as_str()) {
+            problems.push(format!("engines.transport: {}", e));
+        }
+
+        if let Err(e) = self.risk.validate() {
+            problems.push(format!("risk: {}", e));
+        }
+
+        if self.symbols.is_empty() {
+            problems.push("no symbols given".to_string());
+        }
+
+        let mut seen = HashMap::new();
+        for (i, settings) in self.symbols.iter().enumerate() {
+            let symbol = match Symbol::from_str(settings.name.as_str()) {
+                Ok(s) => s,
+                Err(_) => {
+                    problems.push(format!("symbols[{}]: invalid symbol {:?}", i, settings.name));
+                    continue;
+                }
+            };
+
+            if let Some(other) = seen.insert(symbol, i) {
+                problems.push(format!("symbols[{}]: {} is already symbols[{}]", i, symbol, other));
+            }
+
+            if let Some(engine) = settings.engine {
+                if engine >= self.engines.count {
+                    problems.push(format!("symbols[{}]: {} is assigned to engine {} but there \
+                                           are only {}", i, symbol, engine, self.engines.count));
+                }
+            }
+
+            if settings.tick_size.map(|t| !(t >= 0.0) || t.is_infinite()).unwrap_or(false) {
+                problems.push(format!("symbols[{}]: tick_size for {} has to be a finite, \
+                                       non-negative number", i, symbol));
+            }
+
+            if settings.lot_size == Some(0) {
+                problems.push(format!("symbols[{}]: lot_size for {} has to be at least 1", i,
+                                      symbol));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+
+    // Everything below assumes the config is valid
+
+    pub fn session_addr(&self) -> SocketAddr {
+        resolve(&self.listen.sessions).unwrap()
+    }
+
+    pub fn symbols(&self) -> Vec<Symbol> {
+        self.symbols.iter().map(|s| Symbol::from_str(s.name.as_str()).unwrap()).collect()
+    }
+
+    pub fn engine_assignments(&self) -> HashMap<Symbol, u32> {
+        self.symbols.iter().filter_map(|s| {
+            s.engine.map(|engine| (Symbol::from_str(s.name.as_str()).unwrap(), engine))
+        }).collect()
+    }
+
+    pub fn wal_config(&self) -> WalConfig {
+        WalConfig {
+            segment_size: self.wal.segment_size,
+            archive: ArchiveFormat::parse(self.wal.archive.as_str()).unwrap()
+        }
+    }
+
+    pub fn engine_config(&self, replay_limit: ReplayLimit) -> EngineConfig {
+        EngineConfig {
+            wal: self.wal_config(),
+            replay_limit: replay_limit,
+            buffer_size: self.engines.buffer_size,
+            batch_size: self.engines.batch_size,
+            md_interval: Duration::from_millis(self.market_data.interval_ms),
+            transport: TransportKind::parse(self.engines.transport.as_str()).unwrap(),
+            instruments: self.symbols.iter().map(|s| {
+                (Symbol::from_str(s.name.as_str()).unwrap(), s.instrument())
+            }).collect()
+        }
+    }
+}
+
+fn resolve(addr: &String) -> Result<SocketAddr, String> {
+    try!(addr.to_socket_addrs().map_err(|e| {
+        format!("invalid address {}: {}", addr, e)
+    })).next().ok_or(format!("{} doesn't resolve to anything", addr))
+}
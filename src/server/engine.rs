@@ -1,129 +1,688 @@
+use bincode::{serialize, Infinite};
 use futures;
 use futures::future;
-use futures::{Future, Sink, Stream};
+use futures::stream;
+use futures::{Future, Stream};
 use futures::stream::MergedItem;
 use futures::sync::{mpsc, oneshot};
+use journal::{JournalingHandler, OutputJournal};
 use libcix::book;
 use libcix::cix_capnp as cp;
+use libcix::clock::{Clock, ReplayClock};
 use libcix::order::trade_types::*;
 use messages::*;
+use replication::ReplicationHub;
+use ring;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::fs::{create_dir_all, rename, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
-use std::time::Duration;
-use time;
+use std::time::{Duration, Instant};
 use tokio_core::reactor;
+use transport::{self, Batches, TransportKind};
+use wal::{ReplayLimit, Wal, WalConfig, WalDirectoryReader, WalPosition};
 
-const BUFFER_SIZE: usize = 1024;
+// Used where there's no configuration to go by
+pub const DEFAULT_BUFFER_SIZE: usize = 1024;
+pub const DEFAULT_BATCH_SIZE: usize = 256;
 
-struct OrderEngine<TMatcher, THandler>
+// Limits on orders for a single symbol.  These are only checked when orders are submitted, so
+// changing them never affects replay.
+#[derive(Clone, Copy, Debug)]
+pub struct InstrumentParams {
+    // Prices have to be a whole number of ticks, unless this is zero
+    pub tick_size: Price,
+    pub lot_size: Quantity,
+    // Zero for no limit
+    pub max_quantity: Quantity
+}
+
+impl Default for InstrumentParams {
+    fn default() -> Self {
+        InstrumentParams {
+            tick_size: 0.0,
+            lot_size: 1,
+            max_quantity: 0
+        }
+    }
+}
+
+impl InstrumentParams {
+    pub fn check(&self, price: Price, quantity: Quantity) -> Result<(), String> {
+        if quantity % self.lot_size != 0 {
+            return Err(format!("quantity {} is not a multiple of the lot size {}", quantity,
+                               self.lot_size));
+        }
+
+        if self.max_quantity > 0 && quantity > self.max_quantity {
+            return Err(format!("quantity {} is over the limit of {}", quantity,
+                               self.max_quantity));
+        }
+
+        if self.tick_size > 0.0 {
+            // Allow for prices that were a whole number of ticks before being converted to
+            // floating point
+            let ticks = price / self.tick_size;
+            if (ticks - ticks.round()).abs() > 1e-6 {
+                return Err(format!("price {} is not a multiple of the tick size {}", price,
+                                   self.tick_size));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Settings shared by every engine
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    pub wal: WalConfig,
+    pub replay_limit: ReplayLimit,
+    // How many requests can be waiting for an engine before senders block
+    pub buffer_size: usize,
+    // Most requests handled before responses and market data go out
+    pub batch_size: usize,
+    // Least time between market data updates for a book.  Zero publishes after every batch.
+    pub md_interval: Duration,
+    // How requests get to the engine and responses get back
+    pub transport: TransportKind,
+    // Symbols that aren't in here get the defaults
+    pub instruments: HashMap<Symbol, InstrumentParams>
+}
+
+// How a request failed
+#[derive(Debug)]
+pub enum EngineError {
+    // Something was wrong with the request itself.  The detail is only for logs; whoever sent it
+    // just gets the reason.
+    Rejected(RejectReason, String),
+    // Something went wrong in the engine
+    Failed(String)
+}
+
+impl EngineError {
+    fn reject(reason: RejectReason, detail: String) -> Self {
+        EngineError::Rejected(reason, detail)
+    }
+
+    pub fn reason(&self) -> RejectReason {
+        match *self {
+            EngineError::Rejected(reason, _) => reason,
+            EngineError::Failed(_) => RejectReason::Internal
+        }
+    }
+}
+
+impl From<String> for EngineError {
+    fn from(e: String) -> Self {
+        EngineError::Failed(e)
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EngineError::Rejected(_, ref detail) => write!(f, "{}", detail),
+            EngineError::Failed(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+// What an engine has turned down or failed at since it started, readable from other threads
+#[derive(Default)]
+pub struct EngineStats {
+    rejects: Mutex<HashMap<RejectReason, u64>>,
+    errors: AtomicUsize,
+    last_error: Mutex<Option<String>>
+}
+
+impl EngineStats {
+    fn record(&self, error: &EngineError) {
+        match *error {
+            EngineError::Rejected(reason, _) => {
+                *self.rejects.lock().unwrap().entry(reason).or_insert(0) += 1;
+            },
+            EngineError::Failed(ref e) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                *self.last_error.lock().unwrap() = Some(e.clone());
+            }
+        }
+    }
+
+    pub fn rejects(&self) -> HashMap<RejectReason, u64> {
+        self.rejects.lock().unwrap().clone()
+    }
+
+    // Failures that weren't the requester's fault
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+}
+
+struct OrderEngine<TMatcher, THandler, TClock>
         where TMatcher: book::OrderMatcher,
-              THandler: book::ExecutionHandler {
-    symbols:        Vec<Symbol>,
+              THandler: book::ExecutionHandler,
+              TClock: Clock {
+    engine_id:      u32,
+    // Symbols traded on this engine by ID.  IDs are assigned server-wide, so an engine's IDs
+    // needn't be contiguous or start at zero.
+    symbols:        BTreeMap<u32, Symbol>,
     dirty_symbols:  HashSet<Symbol>,
+    // Symbols whose books only accept cancels
+    suspended:      HashSet<Symbol>,
     books:          HashMap<Symbol, book::OrderBook>,
+    // Next order sequence number to assign for each symbol
+    order_seqs:     HashMap<Symbol, u64>,
     matcher:        TMatcher,
     handler:        THandler,
-    responder:      mpsc::Sender<SessionMessage>
+    clock:          TClock,
+    responder:      transport::Sender<SessionMessage>,
+    wal_dir:        PathBuf,
+    // None if the engine was only asked to replay part of its log, in which case it's read-only
+    wal:            Option<Wal<EngineMessage>>,
+    replay_limit:   ReplayLimit,
+    instruments:    HashMap<Symbol, InstrumentParams>,
+    journal:        RefCell<OutputJournal>,
+    replication:    Option<Arc<ReplicationHub>>,
+    // Logged since the last commit, so standbys haven't been sent them yet
+    unpublished:    Vec<(WalPosition, EngineMessage)>,
+    stats:          Arc<EngineStats>,
+    // Checkpoints for the request being handled
+    times:          EngineTimes,
+    // Set once the engine has been told to shut down and has closed its logs
+    stopped:        bool
 }
 
 pub struct EngineHandle {
     // XXX: wrap this in a function EngineHandle::send to avoid exposing
     // implementation details
-    pub tx: mpsc::Sender<EngineMessage>
+    pub tx: transport::Sender<EngineRequest>,
+    pub stats: Arc<EngineStats>,
+    // Finishes once the engine has been sent a shutdown request and has closed its logs
+    pub thread: thread::JoinHandle<Result<(), String>>
 }
 
 impl EngineHandle {
-    pub fn new<TMatcher, THandler> (symbols: &Vec<Symbol>, matcher: &TMatcher,
-                                    handler: &THandler,
-                                    responder: &mpsc::Sender<SessionMessage>) -> Result<Self, String>
+    pub fn new<TMatcher, THandler, TClock> (engine_id: u32, symbols: &Vec<(Symbol, u32)>,
+                                            matcher: &TMatcher, handler: &THandler,
+                                            clock: &TClock,
+                                            responder: transport::Sender<SessionMessage>,
+                                            wal_dir: &Path, config: &EngineConfig,
+                                            replication: Option<Arc<ReplicationHub>>)
+                                            -> Result<Self, String>
             where TMatcher: 'static + book::OrderMatcher + Clone,
-                  THandler: 'static + book::ExecutionHandler + Clone {
+                  THandler: 'static + book::ExecutionHandler + Clone + Send,
+                  TClock: 'static + Clock + Clone + Send {
         let (channel_tx, channel_rx) = oneshot::channel();
         let s_clone = symbols.clone();
         let m_clone = matcher.clone();
         let h_clone = handler.clone();
-        let r_clone = responder.clone();
+        let c_clone = clock.clone();
+        let w_clone = wal_dir.to_path_buf();
+        let config = config.clone();
+        let stats = Arc::new(EngineStats::default());
+        let engine_stats = stats.clone();
 
-        thread::spawn(move || -> Result<(), String> {
-            let mut engine = OrderEngine::new(s_clone, m_clone, h_clone, r_clone)
+        let thread = thread::spawn(move || -> Result<(), String> {
+            let buffer_size = config.buffer_size;
+            let batch_size = config.batch_size;
+            let md_interval = config.md_interval;
+            let kind = config.transport;
+            let mut engine = OrderEngine::new(engine_id, s_clone, m_clone, h_clone, c_clone,
+                                              responder, w_clone, config, replication,
+                                              engine_stats)
                 .unwrap_or_else(|e| {
                     panic!("failed to create order engine: {}", e)
                 });
-            let mut core = reactor::Core::new().unwrap();
-            let handle = core.handle();
-            let (tx, rx) = mpsc::channel(BUFFER_SIZE);
+            let (tx, rx) = transport::channel(kind, buffer_size);
 
             // hand sender back to the calling thread
             channel_tx.complete(tx);
 
-            // XXX: Make md frequency configurable
-            let md_frequency = Duration::new(1, 0);
-            let md_loop = reactor::Interval::new(md_frequency, &handle).unwrap().map_err(|e| {
-                panic!("market data timer error: {}", e.description());
+            // Anything sent to us in the meantime will queue up in the channel until replay is
+            // finished, so a serialization point sent after startup will only complete once this
+            // engine's books are caught up
+            let replay_count = engine.replay_wal().unwrap_or_else(|e| {
+                panic!("engine {} failed to replay messages: {}", engine_id, e)
             });
+            println!("engine {} replayed {} events", engine_id, replay_count);
 
-            let full_loop = rx.merge(md_loop).for_each(move |item| {
-                match item {
-                    MergedItem::First(msg) => {
-                        engine.process_message(msg);
-                    },
-                    MergedItem::Second(_) => {
-                        engine.publish_md();
-                    },
-                    MergedItem::Both(msg, _) => {
-                        engine.process_message(msg);
-                        engine.publish_md();
-                    }
-                }
+            // Publish everything replay touched
+            engine.run_batch(Vec::new(), true);
 
-                future::ok(())
-            });
+            match rx {
+                transport::Receiver::Channel(rx) => {
+                    engine.serve_channel(rx, batch_size, md_interval)
+                },
+                transport::Receiver::Ring(rx) => engine.serve_ring(rx, batch_size, md_interval)
+            }
 
-            core.run(full_loop);
             Ok(())
         });
 
         Ok(EngineHandle {
             tx: channel_rx.wait().unwrap_or_else(|e| {
                 panic!("failed to get channel handle: {}", e)
-            })
+            }),
+            stats: stats,
+            thread: thread
         })
     }
 }
 
-impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
+impl<TMatcher, THandler, TClock> OrderEngine<TMatcher, THandler, TClock>
         where TMatcher: book::OrderMatcher,
-              THandler: book::ExecutionHandler {
-    pub fn new(symbols: Vec<Symbol>, matcher: TMatcher, handler: THandler,
-               responder: mpsc::Sender<SessionMessage>) ->
-            Result<OrderEngine<TMatcher, THandler>, String> {
+              THandler: book::ExecutionHandler,
+              TClock: Clock {
+    pub fn new(engine_id: u32, symbols: Vec<(Symbol, u32)>, matcher: TMatcher, handler: THandler,
+               clock: TClock, responder: transport::Sender<SessionMessage>, wal_dir: PathBuf,
+               config: EngineConfig, replication: Option<Arc<ReplicationHub>>,
+               stats: Arc<EngineStats>) ->
+            Result<OrderEngine<TMatcher, THandler, TClock>, String> {
+        // Appending anything after a partial replay would leave the log inconsistent, so in that
+        // case don't even open it for writing
+        let (wal, journal) = if config.replay_limit.is_bounded() {
+            (None, OutputJournal::read_only())
+        } else {
+            try!(create_dir_all(wal_dir.as_path()).map_err(|e| {
+                format!("failed to create wal directory {}: {}", wal_dir.display(), e)
+            }));
+
+            let wal = try!(Wal::new(wal_dir.as_path(), config.wal));
+
+            let journal_dir = wal_dir.join("journal");
+            try!(create_dir_all(journal_dir.as_path()).map_err(|e| {
+                format!("failed to create journal directory {}: {}", journal_dir.display(), e)
+            }));
+
+            (Some(wal), try!(OutputJournal::new(journal_dir.as_path(), config.wal)))
+        };
+
+        Self::with_logs(engine_id, symbols, matcher, handler, clock, responder, wal_dir, wal,
+                        journal, config.replay_limit, config.instruments, replication, stats)
+    }
+
+    fn with_logs(engine_id: u32, symbols: Vec<(Symbol, u32)>, matcher: TMatcher,
+                 handler: THandler, clock: TClock, responder: transport::Sender<SessionMessage>,
+                 wal_dir: PathBuf, wal: Option<Wal<EngineMessage>>, journal: OutputJournal,
+                 replay_limit: ReplayLimit, instruments: HashMap<Symbol, InstrumentParams>,
+                 replication: Option<Arc<ReplicationHub>>, stats: Arc<EngineStats>) ->
+            Result<OrderEngine<TMatcher, THandler, TClock>, String> {
         let mut engine = OrderEngine {
-            symbols: symbols,
+            engine_id: engine_id,
+            symbols: BTreeMap::new(),
             dirty_symbols: HashSet::new(),
+            suspended: HashSet::new(),
             books: HashMap::new(),
+            order_seqs: HashMap::new(),
             matcher: matcher,
             handler: handler,
-            responder: responder
+            clock: clock,
+            responder: responder,
+            wal_dir: wal_dir,
+            wal: wal,
+            replay_limit: replay_limit,
+            instruments: instruments,
+            journal: RefCell::new(journal),
+            replication: replication,
+            unpublished: Vec::new(),
+            stats: stats,
+            times: EngineTimes::new(Instant::now()),
+            stopped: false
         };
 
-        // XXX: This is fine for now because we're only using one engine, but once we start
-        // sharding symbols across engines, we won't be able to rely on the assumption that symbol
-        // ids are sequential and zero-indexed.  The `symbols` argument here should then change to
-        // a vector of (symbol, id) tuples
-        for (i, symbol) in engine.symbols.iter().enumerate() {
+        for (symbol, id) in symbols.into_iter() {
+            if let Some(other) = engine.symbols.insert(id, symbol) {
+                return Err(format!("symbols {} and {} both have id {}", other, symbol, id));
+            }
+
             if let Some(_) = engine.books.insert(symbol.clone(),
-                                 book::OrderBook::new(symbol.clone(), i as u32)) {
+                                 book::OrderBook::new(symbol.clone(), id)) {
                 return Err(format!("duplicate symbol {}", symbol.as_str()));
             }
+
+            engine.order_seqs.insert(symbol.clone(), 0u64);
         }
 
         Ok(engine)
     }
 
-    fn new_order(&mut self, msg: NewOrderMessage) -> Result<(), String> {
+    // Rebuild the books from this engine's own log.  This has to happen before any new messages
+    // are processed so that newly assigned order IDs don't collide with logged ones.
+    pub fn replay_wal(&mut self) -> Result<usize, String> {
+        let mut reader = try!(WalDirectoryReader::new(self.wal_dir.as_path()));
+        let mut replay_count = 0usize;
+
+        // There's no point checking the journal if we aren't going to replay all of it
+        let verify = !self.replay_limit.is_bounded();
+        if verify {
+            try!(self.journal.borrow_mut().begin_verify(self.wal_dir.join("journal")));
+        }
+
+        while let Some(entry) = reader.next_entry() {
+            let (position, msg) = try!(entry);
+
+            if self.replay_limit.reached(position, replay_count, &msg) {
+                println!("engine {} stopping replay at {} ({:?})", self.engine_id, position,
+                         self.replay_limit);
+                break;
+            }
+
+            self.journal.borrow_mut().set_input(position);
+            if let Err(e) = self.replay_message(msg) {
+                println!("engine {} rejected replayed message {:?}: {}", self.engine_id, msg, e);
+            }
+            replay_count += 1;
+        }
+
+        if verify {
+            let stats = self.journal.borrow_mut().end_verify();
+            println!("engine {} verified {} journaled outputs ({} mismatches)", self.engine_id,
+                     stats.verified, stats.mismatches);
+        }
+
+        Ok(replay_count)
+    }
+
+    fn replay_message(&mut self, message: EngineMessage) -> Result<(), EngineError> {
+        match message {
+            EngineMessage::NewOrder(msg) => {
+                try!(self.observe_order_id(msg.symbol, msg.order_id));
+                self.clock.observe(msg.ts);
+                self.new_order(msg)
+            },
+            EngineMessage::CancelOrder(msg) => self.cancel_order(msg),
+            EngineMessage::ListSymbol(_) | EngineMessage::SuspendSymbol(_) |
+            EngineMessage::ResumeSymbol(_) | EngineMessage::DelistSymbol(_) => {
+                Ok(try!(self.apply_symbol_change(message)))
+            },
+            EngineMessage::Funds(msg) => Ok(try!(self.apply_funds(msg))),
+            // Control messages are never logged
+            _ => Err(EngineError::from("unexpected message type in wal".to_string()))
+        }
+    }
+
+    fn assign_order_id(&mut self, symbol: Symbol, side: OrderSide)
+            -> Result<OrderId, EngineError> {
+        let symbol_id = match self.books.get(&symbol) {
+            Some(book) => book.symbol_id,
+            None => {
+                return Err(EngineError::reject(RejectReason::UnknownSymbol,
+                                               format!("invalid symbol {}", symbol)));
+            }
+        };
+
+        // This is only accessed from the engine thread so there's no need for anything atomic
+        let seq = self.order_seqs.get_mut(&symbol).unwrap();
+        let order_id = try!(OrderId::new(symbol_id, side, *seq));
+        *seq += 1;
+
+        Ok(order_id)
+    }
+
+    fn observe_order_id(&mut self, symbol: Symbol, order_id: OrderId) -> Result<(), String> {
+        let seq = try!(self.order_seqs.get_mut(&symbol).ok_or_else(|| {
+            format!("invalid symbol {}", symbol)
+        }));
+
+        if order_id.sequence() >= *seq {
+            *seq = order_id.sequence() + 1;
+        }
+
+        Ok(())
+    }
+
+    fn record_output(&self, output: OutputMessage) {
+        if let Err(e) = self.journal.borrow_mut().record(output) {
+            println!("engine {} failed to journal {:?}: {}", self.engine_id, output, e);
+        }
+    }
+
+    // Everything the engine does for a request is done once it gets to the ack
+    fn matched(&self) -> EngineTimes {
+        EngineTimes {
+            matched: Instant::now(),
+            .. self.times
+        }
+    }
+
+    fn send_ack(&self, request: RequestId, order_id: OrderId, symbol: Symbol, status: ErrorCode,
+                resting: Option<Order>, times: EngineTimes) -> Result<(), String> {
+        self.responder.send(SessionMessage::NewOrderAck {
+            request: request,
+            order_id: order_id,
+            symbol: symbol,
+            status: status,
+            resting: resting,
+            times: times
+        }).map_err(|e| {
+            format!("failed to send ack for order {}", order_id)
+        })
+    }
+
+    fn ack_order(&self, request: RequestId, order_id: OrderId, symbol: Symbol, status: ErrorCode,
+                 resting: Option<Order>) -> Result<(), String> {
+        let times = self.matched();
+        self.record_output(OutputMessage::NewOrderAck {
+            request: request,
+            order_id: order_id,
+            status: status
+        });
+        self.send_ack(request, order_id, symbol, status, resting, times)
+    }
+
+    // Anything rejected here never makes it into the log so there's nothing to journal against
+    fn submit_order(&mut self, msg: NewOrderMessage) -> Result<(), EngineError> {
+        if self.wal.is_none() {
+            return Err(EngineError::reject(RejectReason::ReadOnly,
+                                           "engine is read-only".to_string()));
+        }
+
+        if self.suspended.contains(&msg.symbol) {
+            return Err(EngineError::reject(RejectReason::SymbolSuspended,
+                                           format!("symbol {} is suspended", msg.symbol)));
+        }
+
+        let params = self.instruments.get(&msg.symbol).cloned().unwrap_or_default();
+        try!(params.check(msg.price, msg.quantity).map_err(|e| {
+            EngineError::reject(RejectReason::InvalidOrder, e)
+        }));
+
+        let order_id = try!(self.assign_order_id(msg.symbol, msg.side));
+
+        let logged = NewOrderMessage {
+            order_id: order_id,
+            ts: self.clock.now(),
+            .. msg
+        };
+
+        try!(self.log_message(EngineMessage::NewOrder(logged)));
+        self.new_order(logged)
+    }
+
+    fn submit_cancel(&mut self, msg: CancelOrderMessage) -> Result<(), EngineError> {
+        try!(self.log_message(EngineMessage::CancelOrder(msg)));
+        self.cancel_order(msg)
+    }
+
+    // Symbol changes are checked before they're logged so that replay never sees one that fails
+    fn submit_symbol_change(&mut self, message: EngineMessage) -> Result<(), String> {
+        try!(self.check_symbol_change(message));
+        try!(self.log_message(message));
+        self.apply_symbol_change(message)
+    }
+
+    fn check_symbol_change(&self, message: EngineMessage) -> Result<(), String> {
+        match message {
+            EngineMessage::ListSymbol(msg) => {
+                if let Some(other) = self.symbols.get(&msg.symbol_id) {
+                    Err(format!("symbol id {} is already used by {}", msg.symbol_id, other))
+                } else if self.books.contains_key(&msg.symbol) {
+                    Err(format!("symbol {} is already listed", msg.symbol))
+                } else {
+                    Ok(())
+                }
+            },
+            EngineMessage::SuspendSymbol(msg) | EngineMessage::ResumeSymbol(msg) |
+            EngineMessage::DelistSymbol(msg) => {
+                match self.symbols.get(&msg.symbol_id) {
+                    Some(symbol) if *symbol == msg.symbol => Ok(()),
+                    _ => Err(format!("symbol {} is not listed on engine {} with id {}",
+                                     msg.symbol, self.engine_id, msg.symbol_id))
+                }
+            },
+            _ => Err(format!("{:?} is not a symbol change", message))
+        }
+    }
+
+    fn apply_symbol_change(&mut self, message: EngineMessage) -> Result<(), String> {
+        try!(self.check_symbol_change(message));
+
+        let (msg, status) = match message {
+            EngineMessage::ListSymbol(msg) => {
+                self.symbols.insert(msg.symbol_id, msg.symbol);
+                self.books.insert(msg.symbol, book::OrderBook::new(msg.symbol, msg.symbol_id));
+                self.order_seqs.insert(msg.symbol, 0u64);
+                (msg, SymbolStatus::Active)
+            },
+            EngineMessage::SuspendSymbol(msg) => {
+                self.suspended.insert(msg.symbol);
+                (msg, SymbolStatus::Suspended)
+            },
+            EngineMessage::ResumeSymbol(msg) => {
+                self.suspended.remove(&msg.symbol);
+                (msg, SymbolStatus::Active)
+            },
+            EngineMessage::DelistSymbol(msg) => {
+                self.delist_symbol(msg);
+                (msg, SymbolStatus::Delisted)
+            },
+            _ => unreachable!()
+        };
+
+        println!("engine {} symbol {} (id {}) is now {:?}", self.engine_id, msg.symbol,
+                 msg.symbol_id, status);
+
+        self.responder.send(SessionMessage::SymbolUpdate {
+            engine_id: self.engine_id,
+            symbol: msg.symbol,
+            symbol_id: msg.symbol_id,
+            status: status
+        }).map_err(|e| {
+            format!("failed to send update for symbol {}", msg.symbol)
+        })
+    }
+
+    // Cancels everything left on the book and then drops it.  The cancels aren't logged
+    // separately since replaying the delist does the same thing again.
+    fn delist_symbol(&mut self, msg: SymbolMessage) {
+        let resting: Vec<Order> = {
+            let mut book = self.books.get_mut(&msg.symbol).unwrap();
+            let resting: Vec<Order> = book.orders().collect();
+
+            for order in resting.iter() {
+                self.matcher.cancel_order(&mut book, order.id, &self.handler);
+            }
+
+            // Leave subscribers looking at an empty book rather than whatever was last on it
+            self.matcher.publish_md(book, &self.handler);
+            resting
+        };
+
+        for order in resting.iter() {
+            self.record_output(OutputMessage::CancelResult {
+                user: order.user,
+                order_id: order.id,
+                status: ErrorCode::Success
+            });
+        }
+
+        self.books.remove(&msg.symbol);
+        self.symbols.remove(&msg.symbol_id);
+        self.order_seqs.remove(&msg.symbol);
+        self.suspended.remove(&msg.symbol);
+        self.dirty_symbols.remove(&msg.symbol);
+    }
+
+    fn submit_funds(&mut self, msg: FundsMessage) -> Result<(), String> {
+        try!(self.log_message(EngineMessage::Funds(msg)));
+        self.apply_funds(msg)
+    }
+
+    // Balances are kept on the sessions' side, so there's nothing to do here but pass it on
+    fn apply_funds(&self, msg: FundsMessage) -> Result<(), String> {
+        self.responder.send(SessionMessage::FundsUpdate(msg)).map_err(|e| {
+            format!("failed to send funds change for user {}", msg.user)
+        })
+    }
+
+    // Write a message to the WAL.  Standbys hear about it once it's been committed.
+    fn log_message(&mut self, msg: EngineMessage) -> Result<WalPosition, String> {
+        let position = match self.wal {
+            Some(ref mut wal) => try!(wal.write_entry(&msg)),
+            None => { return Err("engine is read-only".to_string()); }
+        };
+        self.times.logged = Instant::now();
+        self.journal.borrow_mut().set_input(position);
+
+        if self.replication.is_some() {
+            self.unpublished.push((position, msg));
+        }
+
+        Ok(position)
+    }
+
+    // Commits everything logged and journaled since last time and lets any standbys know about
+    // it.  This is done once per batch, since each commit costs an msync.
+    fn commit_logs(&mut self) -> Result<(), String> {
+        if let Some(ref mut wal) = self.wal {
+            try!(wal.commit());
+        }
+        try!(self.journal.borrow_mut().commit());
+
+        if let Some(ref hub) = self.replication {
+            for (position, msg) in self.unpublished.drain(..) {
+                hub.publish(self.engine_id, position, msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_replicated(&mut self, position: WalPosition, msg: EngineMessage)
+            -> Result<(), EngineError> {
+        // We may be sent entries we already have after reconnecting to the primary
+        let next = match self.wal {
+            Some(ref wal) => wal.position(),
+            None => { return Err(EngineError::from("engine is read-only".to_string())); }
+        };
+
+        if position < next {
+            return Ok(());
+        }
+
+        let logged = try!(self.log_message(msg));
+        if logged != position {
+            return Err(EngineError::from(format!(
+                "replica diverged: primary logged entry at {} but we logged it at {}",
+                position, logged)));
+        }
+
+        self.replay_message(msg)
+    }
+
+    fn new_order(&mut self, msg: NewOrderMessage) -> Result<(), EngineError> {
         let symbol = msg.symbol;
 
         let order = Order {
@@ -133,16 +692,19 @@ impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
             side:       msg.side,
             price:      msg.price,
             quantity:   msg.quantity,
-            update:     time::now().to_timespec()
+            update:     msg.ts
         };
 
-        {
+        let status = {
             let mut book = self.books.get_mut(&symbol).unwrap();
-            self.matcher.add_order(&mut book, order, &self.handler);
-        }
+            let handler = JournalingHandler::new(&self.handler, &self.journal, &self.responder);
+            self.matcher.add_order(&mut book, order, &handler)
+        };
+
+        let resting = self.books[&symbol].get_order(msg.order_id).cloned();
 
         self.symbol_dirty(symbol);
-        Ok(())
+        Ok(try!(self.ack_order(msg.request, msg.order_id, symbol, status, resting)))
     }
 
     /*
@@ -151,13 +713,54 @@ impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
     }
     */
 
-    fn cancel_order(&mut self, msg: CancelOrderMessage) -> Result<(), String> {
-        let sym_id = msg.order_id.symbol_id();
-        if (sym_id as usize) >= self.symbols.len() {
-            return Err("invalid order id".to_string());
+    fn cancel_order(&mut self, msg: CancelOrderMessage) -> Result<(), EngineError> {
+        let status = self.remove_order(msg);
+
+        self.record_output(OutputMessage::CancelResult {
+            user: msg.user,
+            order_id: msg.order_id,
+            status: status
+        });
+
+        match status {
+            ErrorCode::NotOrderOwner => {
+                Err(EngineError::reject(RejectReason::NotOrderOwner,
+                                        format!("order {} does not belong to user {}",
+                                                msg.order_id, msg.user)))
+            },
+            ErrorCode::UnknownOrder => {
+                Err(EngineError::reject(RejectReason::UnknownOrder,
+                                        format!("unknown order {}", msg.order_id)))
+            },
+            _ => {
+                // Only found orders get this far, so the symbol is known
+                let symbol = self.symbols[&msg.order_id.symbol_id()];
+                Ok(try!(self.responder.send(SessionMessage::CancelAck {
+                    request: msg.request,
+                    order_id: msg.order_id,
+                    symbol: symbol,
+                    times: self.matched()
+                }).map_err(|e| {
+                    format!("failed to send ack for cancel of {}", msg.order_id)
+                })))
+            }
         }
+    }
 
-        let symbol = self.symbols[sym_id as usize];
+    fn reject(&self, request: RequestId, reason: RejectReason) -> Result<(), String> {
+        self.responder.send(SessionMessage::Reject {
+            request: request,
+            reason: reason
+        }).map_err(|e| {
+            format!("failed to send reject for request {}", request)
+        })
+    }
+
+    fn remove_order(&mut self, msg: CancelOrderMessage) -> ErrorCode {
+        let symbol = match self.symbols.get(&msg.order_id.symbol_id()) {
+            Some(s) => *s,
+            None => { return ErrorCode::UnknownOrder; }
+        };
 
         {
             // XXX: really the books should be stored directly in a vector and the lookup hashmap
@@ -169,33 +772,29 @@ impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
                         order.user
                     },
                     None => {
-                        println!("Received cancel for unknown order {}", msg.order_id);
-                        return Ok(());
+                        return ErrorCode::UnknownOrder;
                     }
                 }
             };
 
             if target_user != msg.user {
-                return Err(format!("order {} does not belong to user {}", msg.order_id, msg.user));
+                return ErrorCode::NotOrderOwner;
             }
 
             self.matcher.cancel_order(&mut book, msg.order_id, &self.handler);
         }
 
         self.symbol_dirty(symbol);
-        Ok(())
+        ErrorCode::Success
     }
 
     fn serialization_point(&mut self, seq: u32) -> Result<(), String> {
         // If we process messages asynchronously then this will have to track which have been
         // processed but right now because we handle them synchronously we can already be sure that
         // we're caught up.
-        self.responder.clone().send(SessionMessage::SerializationResponse(seq)).wait()
-            .map(|_| ())
-            .map_err(|e| {
-                "failed to send serialization response".to_string()
-            }
-        )
+        self.responder.send(SessionMessage::SerializationResponse(seq)).map_err(|e| {
+            "failed to send serialization response".to_string()
+        })
     }
 
     fn get_open_orders(&mut self, seq: OpenOrdersSequence) -> Result<(), String> {
@@ -228,13 +827,9 @@ impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
             // Instead we can combine them into a single future and make sure they all copmlete at
             // the end.  The channel should still guarantee delivery in the order that we attempt
             // to send them.
-            try!(self.responder.clone().send(SessionMessage::OpenOrdersResponse(response))
-                 .wait()
-                .map(|_| ())
-                .map_err(|e| {
-                    format!("failed to send open orders response to {}/{}",
-                            seq.user, seq.seq).to_string()
-                }));
+            try!(self.responder.send(SessionMessage::OpenOrdersResponse(response)).map_err(|e| {
+                format!("failed to send open orders response to {}/{}", seq.user, seq.seq)
+            }));
 
             if last_response {
                 break;
@@ -244,18 +839,150 @@ impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
         Ok(())
     }
 
+    // Failures are counted rather than returned since there's nobody to return them to, but
+    // whoever sent a live request still hears about it
+    pub fn process_request(&mut self, request: EngineRequest) {
+        let result = match request {
+            EngineRequest::Live(msg) => {
+                self.times = EngineTimes::new(Instant::now());
+                let result = self.process_message(msg);
+
+                if let (&Err(ref e), Some(request)) = (&result, msg.request()) {
+                    if let Err(send_error) = self.reject(request, e.reason()) {
+                        self.stats.record(&EngineError::Failed(send_error));
+                    }
+                }
+
+                result
+            },
+            EngineRequest::Replicated(position, msg) => self.apply_replicated(position, msg),
+            EngineRequest::DumpBooks => {
+                self.dump_books();
+                Ok(())
+            },
+            EngineRequest::Shutdown { snapshot } => {
+                // Nobody looks at the stats once the engine has stopped
+                if let Err(e) = self.shut_down(snapshot) {
+                    println!("engine {} didn't shut down cleanly: {}", self.engine_id, e);
+                }
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            self.stats.record(&e);
+        }
+    }
+
+    fn dump_books(&self) {
+        for symbol in self.symbols.values() {
+            let book = self.books.get(symbol).unwrap();
+            println!("engine {} book {}:", self.engine_id, symbol);
+
+            for order in book.orders() {
+                println!("    {} for user {} at {}.{:09}", order, order.user, order.update.sec,
+                         order.update.nsec);
+            }
+        }
+    }
+
+    // The logs are closed even if the snapshot can't be written, and the engine stops either way
+    fn shut_down(&mut self, snapshot: bool) -> Result<(), String> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+
+        println!("engine {} shutting down", self.engine_id);
+
+        let snapshot_result = if snapshot {
+            self.write_snapshot()
+        } else {
+            Ok(())
+        };
+
+        let journal_result = self.journal.borrow_mut().close();
+        if let Some(wal) = self.wal.take() {
+            try!(wal.close());
+        }
+
+        try!(journal_result);
+        snapshot_result
+    }
+
+    // Written next to the log as snapshot_<index>_<offset>, named for where in the log it was
+    // taken
+    fn write_snapshot(&self) -> Result<(), String> {
+        let position = match self.wal {
+            Some(ref wal) => wal.position(),
+            None => { return Err("read-only engines don't write snapshots".to_string()); }
+        };
+
+        let snapshot = BookSnapshot {
+            engine_id: self.engine_id,
+            position: position,
+            books: self.symbols.iter().map(|(&symbol_id, symbol)| {
+                SnapshotBook {
+                    symbol: *symbol,
+                    symbol_id: symbol_id,
+                    suspended: self.suspended.contains(symbol),
+                    next_sequence: self.order_seqs.get(symbol).cloned().unwrap_or(0),
+                    orders: self.books.get(symbol).map(|book| book.orders().collect())
+                        .unwrap_or_default()
+                }
+            }).collect()
+        };
+
+        let bytes = try!(serialize(&snapshot, Infinite).map_err(|e| {
+            format!("failed to serialize snapshot: {}", e.description())
+        }));
+
+        // Written to one side first so that a half-written snapshot is never mistaken for a real
+        // one
+        let name = format!("snapshot_{}_{}", position.index, position.offset);
+        let path = self.wal_dir.join(name.as_str());
+        let staging = self.wal_dir.join(format!("{}.tmp", name));
+
+        try!(File::create(staging.as_path()).and_then(|mut f| {
+            try!(f.write_all(bytes.as_slice()));
+            f.sync_all()
+        }).map_err(|e| {
+            format!("failed to write {}: {}", staging.display(), e)
+        }));
+
+        try!(rename(staging.as_path(), path.as_path()).map_err(|e| {
+            format!("failed to move {} into place: {}", path.display(), e)
+        }));
+
+        println!("engine {} wrote snapshot of {} books to {}", self.engine_id,
+                 snapshot.books.len(), path.display());
+
+        Ok(())
+    }
+
     pub fn process_message(&mut self, message: EngineMessage) ->
-            Result<(), String> {
+            Result<(), EngineError> {
         match message {
-            EngineMessage::NewOrder(msg) => self.new_order(msg),
+            EngineMessage::NewOrder(msg) => self.submit_order(msg),
             //EngineMessage::ChangeOrder(msg) => self.change_order(msg),
-            EngineMessage::CancelOrder(msg) => self.cancel_order(msg),
-            EngineMessage::SerializationMessage(seq) => self.serialization_point(seq),
-            EngineMessage::GetOpenOrdersMessaage(seq) => self.get_open_orders(seq),
+            EngineMessage::CancelOrder(msg) => self.submit_cancel(msg),
+            EngineMessage::SerializationMessage(seq) => Ok(try!(self.serialization_point(seq))),
+            EngineMessage::GetOpenOrdersMessaage(seq) => Ok(try!(self.get_open_orders(seq))),
+            EngineMessage::ListSymbol(_) | EngineMessage::SuspendSymbol(_) |
+            EngineMessage::ResumeSymbol(_) | EngineMessage::DelistSymbol(_) => {
+                Ok(try!(self.submit_symbol_change(message)))
+            },
+            EngineMessage::Funds(msg) => Ok(try!(self.submit_funds(msg))),
             EngineMessage::NullMessage => unreachable!()
         }
     }
 
+    fn has_order(&self, order_id: OrderId) -> bool {
+        self.symbols.get(&order_id.symbol_id()).and_then(|symbol| {
+            self.books.get(symbol)
+        }).map(|book| book.get_order(order_id).is_some()).unwrap_or(false)
+    }
+
     fn symbol_dirty(&mut self, symbol: Symbol) {
         self.dirty_symbols.insert(symbol);
     }
@@ -267,4 +994,323 @@ impl<TMatcher, THandler> OrderEngine<TMatcher, THandler>
 
         self.dirty_symbols.clear();
     }
+
+    // Handles everything in a batch before sending any of the responses or market data that came
+    // out of it, so that a burst of orders on a book only publishes its final state.  Returns
+    // false once the engine has shut down.
+    fn run_batch(&mut self, batch: Vec<EngineRequest>, publish_md: bool) -> bool {
+        self.responder.hold();
+
+        for request in batch.into_iter() {
+            self.process_request(request);
+        }
+
+        // Nothing that came of the batch can go out until the log says it happened, and there's
+        // no carrying on with a log that can't be committed
+        if let Err(e) = self.commit_logs() {
+            panic!("engine {} failed to commit its logs: {}", self.engine_id, e);
+        }
+
+        // Books left dirty when shutting down would never be published otherwise
+        if publish_md || self.stopped {
+            self.publish_md();
+        }
+
+        if let Err(e) = self.responder.flush() {
+            self.stats.record(&EngineError::Failed(format!("failed to send responses: {}", e)));
+        }
+        self.handler.flush();
+
+        !self.stopped
+    }
+
+    // Takes whatever the reactor has ready each time round.  The timer is only there to catch
+    // books left dirty because they'd published too recently.
+    fn serve_channel(mut self, rx: mpsc::Receiver<EngineRequest>, batch_size: usize,
+                     md_interval: Duration) {
+        let mut core = reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let mut throttle = MdThrottle::new(md_interval);
+
+        let ticks: Box<Stream<Item=(), Error=()>> = if throttle.is_immediate() {
+            Box::new(stream::empty())
+        } else {
+            Box::new(reactor::Interval::new(md_interval, &handle).unwrap().map_err(|e| {
+                panic!("market data timer error: {}", e.description());
+            }))
+        };
+
+        let full_loop = Batches::new(rx, batch_size).merge(ticks).for_each(move |item| {
+            let batch = match item {
+                MergedItem::First(batch) | MergedItem::Both(batch, _) => batch,
+                MergedItem::Second(_) => Vec::new()
+            };
+
+            let publish = throttle.due();
+            if self.run_batch(batch, publish) {
+                future::ok(())
+            } else {
+                future::err(())
+            }
+        });
+
+        // Ends with an error once the engine has shut down
+        let _ = core.run(full_loop);
+    }
+
+    // Same as serve_channel but without a reactor, since waiting on the ring is up to its own
+    // strategy
+    fn serve_ring(mut self, rx: ring::Consumer<EngineRequest>, batch_size: usize,
+                  md_interval: Duration) {
+        let mut throttle = MdThrottle::new(md_interval);
+
+        loop {
+            let batch = match rx.pop_until(throttle.next()) {
+                ring::Recv::Item(req) => {
+                    let mut batch = vec![req];
+                    while batch.len() < batch_size {
+                        match rx.try_pop() {
+                            Some(req) => batch.push(req),
+                            None => break
+                        }
+                    }
+                    batch
+                },
+                ring::Recv::TimedOut => Vec::new(),
+                ring::Recv::Closed => { break; }
+            };
+
+            let publish = throttle.due();
+            if !self.run_batch(batch, publish) {
+                break;
+            }
+        }
+    }
+}
+
+// Decides whether dirty books publish at the end of a batch.  With no interval they always do,
+// otherwise at most once per interval.
+struct MdThrottle {
+    interval: Duration,
+    last: Instant
+}
+
+impl MdThrottle {
+    fn new(interval: Duration) -> Self {
+        MdThrottle {
+            interval: interval,
+            last: Instant::now()
+        }
+    }
+
+    fn is_immediate(&self) -> bool {
+        self.interval == Duration::new(0, 0)
+    }
+
+    // When books left dirty have to be published by, if there's any limit
+    fn next(&self) -> Option<Instant> {
+        if self.is_immediate() {
+            None
+        } else {
+            Some(self.last + self.interval)
+        }
+    }
+
+    fn due(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last) >= self.interval {
+            self.last = now;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Executions and market data don't matter when checking a log
+struct NullExecutionHandler;
+
+impl book::ExecutionHandler for NullExecutionHandler {
+    fn handle_match(&self, execution: &Execution) {}
+    fn handle_market_data_l1(&self, md: L1Md) {}
+    fn handle_market_data_l2(&self, md: L2Md) {}
+}
+
+#[derive(Debug)]
+pub struct ReplayCheck {
+    pub replayed: usize,
+    // Entries that decoded fine but don't make sense given everything before them
+    pub problems: Vec<(WalPosition, String)>
+}
+
+// Replay a log into a scratch engine that never writes anything, to find entries that a real
+// engine would reject or silently ignore
+pub fn check_replay<TMatcher>(symbols: &Vec<(Symbol, u32)>, matcher: TMatcher, wal_dir: &Path)
+        -> Result<ReplayCheck, String>
+        where TMatcher: book::OrderMatcher {
+    // Replay still sends acks, so something has to drain them
+    let (tx, rx) = transport::channel(TransportKind::Channel, DEFAULT_BUFFER_SIZE);
+    thread::spawn(move || rx.for_each(|_| Ok(())).wait());
+
+    let mut engine = try!(OrderEngine::with_logs(0, symbols.clone(), matcher,
+                                                 NullExecutionHandler, ReplayClock::default(), tx,
+                                                 wal_dir.to_path_buf(), None,
+                                                 OutputJournal::read_only(),
+                                                 ReplayLimit::Everything, HashMap::new(), None,
+                                                 Arc::new(EngineStats::default())));
+    let mut reader = try!(WalDirectoryReader::new(wal_dir));
+    let mut check = ReplayCheck {
+        replayed: 0,
+        problems: Vec::new()
+    };
+
+    while let Some(entry) = reader.next_entry() {
+        // Unreadable entries are the caller's problem to find; all we can do is stop here
+        let (position, msg) = match entry {
+            Ok(e) => e,
+            Err(_) => break
+        };
+
+        match msg {
+            EngineMessage::NewOrder(ref order) if engine.has_order(order.order_id) => {
+                check.problems.push((position, format!("duplicate order {}", order.order_id)));
+            },
+            _ => ()
+        }
+
+        // This includes cancels for orders that aren't on the books
+        if let Err(e) = engine.replay_message(msg) {
+            check.problems.push((position, e.to_string()));
+        }
+
+        check.replayed += 1;
+    }
+
+    Ok(check)
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use journal::OutputJournal;
+    use libcix::book::{self, BasicMatcher};
+    use libcix::clock::{Clock, ReplayClock};
+    use libcix::order::trade_types::*;
+    use messages::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs::create_dir_all;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+    use time;
+    use transport::{self, TransportKind};
+    use wal::{ReplayLimit, Wal, WalConfig};
+    use wal::test::scratch_dir;
+
+    const CONFIG: WalConfig = WalConfig {
+        segment_size: 4096,
+        archive: None
+    };
+
+    #[derive(Default)]
+    struct RecordingHandler {
+        executions: RefCell<Vec<Execution>>
+    }
+
+    impl book::ExecutionHandler for RecordingHandler {
+        fn handle_match(&self, execution: &Execution) {
+            self.executions.borrow_mut().push(*execution);
+        }
+
+        fn handle_market_data_l1(&self, md: L1Md) {}
+        fn handle_market_data_l2(&self, md: L2Md) {}
+    }
+
+    type TestEngine = OrderEngine<BasicMatcher, RecordingHandler, ReplayClock>;
+
+    fn symbol() -> Symbol {
+        Symbol::from_str("AAPL").unwrap()
+    }
+
+    // Logs to `dir` unless it's only there to be replayed
+    fn engine(dir: &Path, writable: bool) -> TestEngine {
+        let (tx, rx) = transport::channel(TransportKind::Channel, DEFAULT_BUFFER_SIZE);
+        thread::spawn(move || rx.for_each(|_| Ok(())).wait());
+
+        let (wal, journal) = if writable {
+            let journal_dir = dir.join("journal");
+            create_dir_all(journal_dir.as_path()).unwrap();
+            (Some(Wal::new(dir, CONFIG).unwrap()),
+             OutputJournal::new(journal_dir.as_path(), CONFIG).unwrap())
+        } else {
+            (None, OutputJournal::read_only())
+        };
+
+        OrderEngine::with_logs(0, vec![(symbol(), 1)], BasicMatcher{},
+                               RecordingHandler::default(), ReplayClock::default(), tx,
+                               dir.to_path_buf(), wal, journal, ReplayLimit::Everything,
+                               HashMap::new(), None, Arc::new(EngineStats::default())).unwrap()
+    }
+
+    fn order(user: UserId, side: OrderSide, price: Price, quantity: Quantity) -> EngineMessage {
+        EngineMessage::NewOrder(NewOrderMessage {
+            request: 0,
+            user: user,
+            order_id: OrderId::default(),
+            symbol: symbol(),
+            side: side,
+            price: price,
+            quantity: quantity,
+            ts: OrderTime::new(0, 0)
+        })
+    }
+
+    // Everything about each resting order that replay has to get right, in ID order
+    fn book_state(engine: &TestEngine) -> Vec<(OrderId, UserId, Price, Quantity, OrderTime)> {
+        let mut orders: Vec<_> = engine.books[&symbol()].orders().map(|o| {
+            (o.id, o.user, o.price, o.quantity, o.update)
+        }).collect();
+        orders.sort_by_key(|o| o.0.raw());
+        orders
+    }
+
+    // Timestamps come from the log rather than from whichever clock the replaying engine has, so
+    // a clock that never moves on its own ends up wherever the log left it
+    #[test]
+    fn replay_rebuilds_books_and_timestamps() {
+        let dir = scratch_dir("engine_replay");
+        let messages = vec![
+            order(1, OrderSide::Buy, 10.0, 100),
+            order(2, OrderSide::Buy, 10.5, 50),
+            order(3, OrderSide::Sell, 10.0, 120),
+            order(1, OrderSide::Sell, 11.0, 30),
+            order(2, OrderSide::Buy, 9.0, 20),
+            // The fourth order, which is the only one still resting at 11
+            EngineMessage::CancelOrder(CancelOrderMessage {
+                request: 0,
+                user: 1,
+                order_id: OrderId::new(1, OrderSide::Sell, 3).unwrap()
+            })
+        ];
+
+        let mut live = engine(dir.as_path(), true);
+        live.clock.set(OrderTime::new(1000, 0));
+        for msg in messages.iter() {
+            live.clock.advance(time::Duration::milliseconds(1));
+            live.process_message(*msg).unwrap();
+        }
+        live.shut_down(false).unwrap();
+
+        let mut replayed = engine(dir.as_path(), false);
+        assert_eq!(replayed.replay_wal().unwrap(), messages.len());
+
+        assert_eq!(book_state(&replayed), book_state(&live));
+        assert_eq!(book_state(&replayed).len(), 2);
+        assert_eq!(*replayed.handler.executions.borrow(), *live.handler.executions.borrow());
+        assert_eq!(replayed.handler.executions.borrow().len(), 2);
+        // The cancel isn't timestamped, so the last order's time is the latest in the log
+        assert_eq!(replayed.clock.now(), OrderTime::new(1000, 5000000));
+    }
 }
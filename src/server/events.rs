@@ -1,33 +1,34 @@
 use libcix::order::trade_types::*;
 use messages::*;
-use session::{OpenOrderMap, OrderMap, OrderRouter, ServerContext};
+use session::{Accepted, OpenOrderMap, RequestMap, OrderRouter, ServerContext};
 use futures::{Async, Poll};
 use futures::future::Future;
 use futures::task::{park, Task};
 use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
+// Waits for an engine to accept or reject a new order or cancel
 #[derive(Clone)]
-pub struct NewOrderSend {
-    order_id: OrderId,
-    status_map: Rc<RefCell<OrderMap>>
+pub struct RequestSend {
+    request: RequestId,
+    status_map: Rc<RefCell<RequestMap>>
 }
 
-impl NewOrderSend {
-    pub fn new(order_id: OrderId, status_map: Rc<RefCell<OrderMap>>) -> Self {
-        NewOrderSend {
-            order_id: order_id,
+impl RequestSend {
+    pub fn new(request: RequestId, status_map: Rc<RefCell<RequestMap>>) -> Self {
+        RequestSend {
+            request: request,
             status_map: status_map
         }
     }
 }
 
-impl Future for NewOrderSend {
-    type Item = ErrorCode;
+impl Future for RequestSend {
+    type Item = Result<Accepted, RejectReason>;
     type Error = ();
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Some(ref entry) = self.status_map.borrow().get(&self.order_id) {
+        if let Some(ref entry) = self.status_map.borrow().get(&self.request) {
             match entry.status.get() {
                 Some(c) => Ok(Async::Ready(c.clone())),
                 None => Ok(Async::NotReady)
@@ -38,9 +39,9 @@ impl Future for NewOrderSend {
     }
 }
 
-impl Drop for NewOrderSend {
+impl Drop for RequestSend {
     fn drop(&mut self) {
-        self.status_map.borrow_mut().remove(&self.order_id);
+        self.status_map.borrow_mut().remove(&self.request);
     }
 }
 
@@ -0,0 +1,256 @@
+use libcix::book::ExecutionHandler;
+use libcix::order::trade_types::*;
+use messages::*;
+use std::cell::RefCell;
+use std::path::Path;
+use transport::Sender;
+use wal::{Wal, WalConfig, WalDirectoryReader, WalPosition};
+
+// Records everything an engine sends back out (acks, executions and cancel results), tagged with
+// the position of the input WAL entry that caused it.
+//
+// While an engine is replaying its input log the journal already contains the outputs from the
+// first time around, so instead of writing them again we check that replay reproduced them.
+pub struct OutputJournal {
+    // None when the engine is read-only, in which case nothing is recorded
+    wal: Option<Wal<JournalEntry>>,
+    input: WalPosition,
+    recorded: Option<WalDirectoryReader<JournalEntry>>,
+    verified: usize,
+    mismatches: usize
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyStats {
+    pub verified: usize,
+    pub mismatches: usize
+}
+
+impl OutputJournal {
+    pub fn new<P: AsRef<Path>>(dir: P, config: WalConfig) -> Result<Self, String> {
+        Ok(OutputJournal {
+            wal: Some(try!(Wal::new(dir, config))),
+            input: WalPosition::default(),
+            recorded: None,
+            verified: 0usize,
+            mismatches: 0usize
+        })
+    }
+
+    pub fn read_only() -> Self {
+        OutputJournal {
+            wal: None,
+            input: WalPosition::default(),
+            recorded: None,
+            verified: 0usize,
+            mismatches: 0usize
+        }
+    }
+
+    // Commits everything recorded so far
+    pub fn commit(&mut self) -> Result<(), String> {
+        match self.wal {
+            Some(ref mut wal) => wal.commit(),
+            None => Ok(())
+        }
+    }
+
+    // Nothing can be recorded afterwards
+    pub fn close(&mut self) -> Result<(), String> {
+        match self.wal.take() {
+            Some(wal) => wal.close().map(|_| ()),
+            None => Ok(())
+        }
+    }
+
+    pub fn set_input(&mut self, position: WalPosition) {
+        self.input = position;
+    }
+
+    pub fn begin_verify<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), String> {
+        self.recorded = Some(try!(WalDirectoryReader::new(dir)));
+        self.verified = 0usize;
+        self.mismatches = 0usize;
+        Ok(())
+    }
+
+    pub fn end_verify(&mut self) -> VerifyStats {
+        // Anything left over was recorded for inputs that replay never reached
+        if let Some(mut recorded) = self.recorded.take() {
+            while let Some(entry) = recorded.next() {
+                match entry {
+                    Ok(e) => {
+                        println!("journal has output {:?} for {} that replay did not produce",
+                                 e.output, e.input);
+                    },
+                    Err(e) => {
+                        println!("failed to read journal: {}", e);
+                    }
+                }
+                self.mismatches += 1;
+            }
+        }
+
+        VerifyStats {
+            verified: self.verified,
+            mismatches: self.mismatches
+        }
+    }
+
+    pub fn record(&mut self, output: OutputMessage) -> Result<(), String> {
+        let entry = JournalEntry {
+            input: self.input,
+            output: output
+        };
+
+        let expected = match self.recorded {
+            Some(ref mut recorded) => recorded.next(),
+            None => None
+        };
+
+        match expected {
+            Some(Ok(e)) => {
+                if e.input == entry.input && e.output == entry.output {
+                    self.verified += 1;
+                } else {
+                    println!("replay mismatch at {}: journal has {:?}, replay produced {:?}",
+                             entry.input, e.output, entry.output);
+                    self.mismatches += 1;
+                }
+                Ok(())
+            },
+            Some(Err(e)) => Err(format!("failed to read journal: {}", e)),
+            // Either we're live or the journal fell behind the input log (e.g. because we crashed
+            // between writing an input and its outputs), so record it now
+            None => {
+                match self.wal {
+                    Some(ref mut wal) => wal.write_entry(&entry).map(|_| ()),
+                    None => Ok(())
+                }
+            }
+        }
+    }
+}
+
+// Journals executions and tells the sessions about them on their way to the engine's real
+// handler.  Sessions hear about them from here rather than from the handler since the engine's
+// responder is the only thing allowed to send to them.
+pub struct JournalingHandler<'a, THandler> where THandler: 'a + ExecutionHandler {
+    inner: &'a THandler,
+    journal: &'a RefCell<OutputJournal>,
+    responder: &'a Sender<SessionMessage>
+}
+
+impl<'a, THandler> JournalingHandler<'a, THandler> where THandler: 'a + ExecutionHandler {
+    pub fn new(inner: &'a THandler, journal: &'a RefCell<OutputJournal>,
+               responder: &'a Sender<SessionMessage>) -> Self {
+        JournalingHandler {
+            inner: inner,
+            journal: journal,
+            responder: responder
+        }
+    }
+}
+
+impl<'a, THandler> ExecutionHandler for JournalingHandler<'a, THandler>
+        where THandler: 'a + ExecutionHandler {
+    fn handle_match(&self, execution: &Execution) {
+        if let Err(e) = self.journal.borrow_mut().record(OutputMessage::Execution(*execution)) {
+            println!("failed to journal execution {}: {}", execution.id, e);
+        }
+
+        if let Err(e) = self.responder.send(SessionMessage::Execution(*execution)) {
+            println!("failed to notify sessions of execution {}: {}", execution.id, e);
+        }
+
+        self.inner.handle_match(execution);
+    }
+
+    fn handle_market_data_l1(&self, md: L1Md) {
+        self.inner.handle_market_data_l1(md);
+    }
+
+    fn handle_market_data_l2(&self, md: L2Md) {
+        self.inner.handle_market_data_l2(md);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libcix::order::trade_types::*;
+    use messages::{JournalEntry, OutputMessage};
+    use super::OutputJournal;
+    use wal::{WalConfig, WalDirectoryReader, WalPosition};
+    use wal::test::scratch_dir;
+
+    const CONFIG: WalConfig = WalConfig {
+        segment_size: 4096,
+        archive: None
+    };
+
+    fn input(offset: u64) -> WalPosition {
+        WalPosition {
+            index: 0,
+            offset: offset
+        }
+    }
+
+    fn ack(seq: u64) -> OutputMessage {
+        OutputMessage::NewOrderAck {
+            request: seq,
+            order_id: OrderId::new(1, OrderSide::Buy, seq).unwrap(),
+            status: ErrorCode::Success
+        }
+    }
+
+    fn record_all(journal: &mut OutputJournal, outputs: &[(u64, OutputMessage)]) {
+        for &(offset, output) in outputs.iter() {
+            journal.set_input(input(offset));
+            journal.record(output).unwrap();
+        }
+    }
+
+    #[test]
+    fn replay_is_checked_against_recorded_outputs() {
+        let dir = scratch_dir("journal_verify");
+
+        let mut journal = OutputJournal::new(dir.as_path(), CONFIG).unwrap();
+        record_all(&mut journal, &[(0, ack(0)), (1, ack(1)), (2, ack(2))]);
+        journal.close().unwrap();
+
+        let mut journal = OutputJournal::new(dir.as_path(), CONFIG).unwrap();
+        journal.begin_verify(dir.as_path()).unwrap();
+        // The second output differs and replay never gets as far as the third
+        record_all(&mut journal, &[(0, ack(0)), (1, ack(5))]);
+
+        let stats = journal.end_verify();
+        assert_eq!(stats.verified, 1);
+        assert_eq!(stats.mismatches, 2);
+        journal.close().unwrap();
+    }
+
+    #[test]
+    fn outputs_past_the_end_of_the_journal_are_recorded() {
+        let dir = scratch_dir("journal_behind");
+
+        let mut journal = OutputJournal::new(dir.as_path(), CONFIG).unwrap();
+        record_all(&mut journal, &[(0, ack(0))]);
+        journal.close().unwrap();
+
+        let mut journal = OutputJournal::new(dir.as_path(), CONFIG).unwrap();
+        journal.begin_verify(dir.as_path()).unwrap();
+        record_all(&mut journal, &[(0, ack(0)), (1, ack(1))]);
+
+        let stats = journal.end_verify();
+        assert_eq!(stats.verified, 1);
+        assert_eq!(stats.mismatches, 0);
+        journal.close().unwrap();
+
+        let recorded: Vec<(WalPosition, OutputMessage)> =
+            WalDirectoryReader::<JournalEntry>::new(dir.as_path()).unwrap().map(|entry| {
+                let entry = entry.unwrap();
+                (entry.input, entry.output)
+            }).collect();
+        assert_eq!(recorded, vec![(input(0), ack(0)), (input(1), ack(1))]);
+    }
+}
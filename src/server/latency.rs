@@ -0,0 +1,229 @@
+use libcix::order::trade_types::Symbol;
+use messages::EngineTimes;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Everything below 2^(SUB_BITS + 1) ns gets a bucket of its own; above that each power of two is
+// split into 2^SUB_BITS buckets, which keeps every percentile within 1/8 of the real value
+const SUB_BITS: u32 = 3;
+const LINEAR_BUCKETS: usize = 1 << (SUB_BITS + 1);
+const N_BUCKETS: usize = LINEAR_BUCKETS + (64 - SUB_BITS as usize - 1) * (1 << SUB_BITS);
+
+// Where an order has got to on its way through the server
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Stage {
+    // Session receiving it to an engine taking it off its queue
+    Queue,
+    // Engine taking it off its queue to it being in the WAL
+    Log,
+    // In the WAL to the engine being done with the book
+    Match,
+    // Engine being done to the session answering the client
+    Reply,
+    // All of the above
+    Total
+}
+
+pub const STAGES: [Stage; 5] = [Stage::Queue, Stage::Log, Stage::Match, Stage::Reply,
+                                Stage::Total];
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            Stage::Queue => "queue",
+            Stage::Log => "log",
+            Stage::Match => "match",
+            Stage::Reply => "reply",
+            Stage::Total => "total"
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum RequestKind {
+    NewOrder,
+    Cancel
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            RequestKind::NewOrder => "new_order",
+            RequestKind::Cancel => "cancel"
+        })
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1000000000 + d.subsec_nanos() as u64
+}
+
+// Time from `from` to `to`, or zero if the clock went backwards
+fn elapsed(from: Instant, to: Instant) -> u64 {
+    if to > from {
+        nanos(to.duration_since(from))
+    } else {
+        0
+    }
+}
+
+fn bucket_for(value: u64) -> usize {
+    if value < LINEAR_BUCKETS as u64 {
+        return value as usize;
+    }
+
+    let exponent = 63 - value.leading_zeros();
+    let sub = (value >> (exponent - SUB_BITS)) as usize & ((1 << SUB_BITS) - 1);
+    LINEAR_BUCKETS + (exponent - SUB_BITS - 1) as usize * (1 << SUB_BITS) + sub
+}
+
+// Smallest value that lands in `bucket`
+fn bucket_floor(bucket: usize) -> u64 {
+    if bucket < LINEAR_BUCKETS {
+        return bucket as u64;
+    }
+
+    let exponent = ((bucket - LINEAR_BUCKETS) >> SUB_BITS) as u32 + SUB_BITS + 1;
+    let sub = ((bucket - LINEAR_BUCKETS) & ((1 << SUB_BITS) - 1)) as u64;
+    ((1 << SUB_BITS) + sub) << (exponent - SUB_BITS)
+}
+
+// Log-scaled counts of how long something took, in nanoseconds.  Recording is a couple of shifts
+// and an increment.
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    total: u64,
+    max: u64
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    pub count: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            buckets: vec![0u64; N_BUCKETS],
+            count: 0,
+            total: 0,
+            max: 0
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.buckets[bucket_for(value)] += 1;
+        self.count += 1;
+        self.total = self.total.saturating_add(value);
+        if value > self.max {
+            self.max = value;
+        }
+    }
+
+    // Reported as the bottom of the bucket the percentile falls in, but never more than the
+    // largest value actually seen
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let target = ((self.count as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0u64;
+
+        for (bucket, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return bucket_floor(bucket).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            count: self.count,
+            mean: if self.count == 0 { 0 } else { self.total / self.count },
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+            p999: self.percentile(0.999),
+            max: self.max
+        }
+    }
+}
+
+struct Series {
+    stages: Vec<Histogram>
+}
+
+// Per-stage latency for every request a session gets an answer to, by kind of request and
+// symbol.  Everything is recorded and read on the reactor thread, so none of it needs locking.
+pub struct LatencyStats {
+    series: RefCell<HashMap<(RequestKind, Symbol), Series>>
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        LatencyStats {
+            series: RefCell::new(HashMap::new())
+        }
+    }
+
+    // `received` is when the session got the request and `replied` is when it answered
+    pub fn record(&self, kind: RequestKind, symbol: Symbol, received: Instant,
+                  engine: EngineTimes, replied: Instant) {
+        let mut series = self.series.borrow_mut();
+        let s = series.entry((kind, symbol)).or_insert_with(|| Series {
+            stages: STAGES.iter().map(|_| Histogram::new()).collect()
+        });
+
+        let checkpoints = [received, engine.dequeued, engine.logged, engine.matched, replied];
+        for i in 0..(checkpoints.len() - 1) {
+            s.stages[i].record(elapsed(checkpoints[i], checkpoints[i + 1]));
+        }
+        s.stages[STAGES.len() - 1].record(elapsed(received, replied));
+    }
+
+    // Sorted by kind of request and then symbol
+    pub fn summaries(&self) -> Vec<(RequestKind, Symbol, Vec<(Stage, Summary)>)> {
+        let mut summaries: Vec<_> = self.series.borrow().iter().map(|(&(kind, symbol), s)| {
+            (kind, symbol, STAGES.iter().cloned().zip(s.stages.iter().map(|h| h.summary()))
+                .collect::<Vec<_>>())
+        }).collect();
+
+        summaries.sort_by_key(|&(kind, symbol, _)| (kind, symbol.to_string()));
+        summaries
+    }
+
+    pub fn dump(&self) {
+        let summaries = self.summaries();
+        if summaries.is_empty() {
+            return;
+        }
+
+        println!("latency (us)      {:>6} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}", "stage",
+                 "count", "mean", "p50", "p90", "p99", "p99.9", "max");
+        for (kind, symbol, stages) in summaries.into_iter() {
+            for (stage, s) in stages.into_iter() {
+                println!("{:<9} {:<7} {:>6} {:>10} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+                         kind.to_string(), symbol.to_string(), stage.to_string(), s.count,
+                         micros(s.mean), micros(s.p50), micros(s.p90), micros(s.p99),
+                         micros(s.p999), micros(s.max));
+            }
+        }
+    }
+}
+
+fn micros(nanos: u64) -> f64 {
+    nanos as f64 / 1000.0
+}
@@ -0,0 +1,645 @@
+use futures::{future, Future};
+use futures::sync::oneshot;
+use libcix::order::trade_types::*;
+use messages::FundsMessage;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs::{rename, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use time;
+
+// A user's holding in one symbol.  Short positions are negative.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Position {
+    pub quantity: i64,
+    // Of whatever is still held, long or short
+    pub average_cost: f64,
+    pub realized_pnl: f64
+}
+
+impl Position {
+    fn trade(&mut self, quantity: i64, price: Price) {
+        let held = self.quantity;
+
+        if held == 0 || held.signum() == quantity.signum() {
+            let total = held.abs() + quantity.abs();
+            self.average_cost = (self.average_cost * held.abs() as f64 +
+                                 price * quantity.abs() as f64) / total as f64;
+        } else {
+            let closed = quantity.abs().min(held.abs());
+            self.realized_pnl += (price - self.average_cost) * (closed * held.signum()) as f64;
+
+            // Anything beyond closing the old position opens a new one at this price
+            if quantity.abs() > held.abs() {
+                self.average_cost = price;
+            } else if quantity.abs() == held.abs() {
+                self.average_cost = 0.0;
+            }
+        }
+
+        self.quantity += quantity;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Account {
+    pub cash: f64,
+    pub positions: HashMap<Symbol, Position>,
+    // Held back for buys that are resting or on their way to an engine
+    pub reserved_cash: f64,
+    // Likewise for sells
+    pub reserved_shares: HashMap<Symbol, i64>
+}
+
+impl Account {
+    // What's left for new buys
+    pub fn available_cash(&self) -> f64 {
+        self.cash - self.reserved_cash
+    }
+
+    // What's left for new sells without going short
+    pub fn available_shares(&self, symbol: &Symbol) -> i64 {
+        let held = self.positions.get(symbol).map(|p| p.quantity).unwrap_or(0);
+        held - self.reserved_shares.get(symbol).cloned().unwrap_or(0)
+    }
+
+    fn hold(&mut self, symbol: Symbol, hold: Hold) {
+        match hold {
+            Hold::Cash(amount) => { self.reserved_cash += amount; },
+            Hold::Shares(quantity) => {
+                *self.reserved_shares.entry(symbol).or_insert(0) += quantity;
+            }
+        }
+    }
+
+    fn release(&mut self, symbol: Symbol, hold: Hold) {
+        match hold {
+            Hold::Cash(amount) => { self.reserved_cash -= amount; },
+            Hold::Shares(quantity) => {
+                let done = match self.reserved_shares.get_mut(&symbol) {
+                    Some(reserved) => {
+                        *reserved -= quantity;
+                        *reserved <= 0
+                    },
+                    None => false
+                };
+
+                if done {
+                    self.reserved_shares.remove(&symbol);
+                }
+            }
+        }
+    }
+}
+
+// What an order keeps back while it's open: cash for a buy, shares for a sell
+#[derive(Clone, Copy, Debug)]
+enum Hold {
+    Cash(f64),
+    Shares(i64)
+}
+
+impl Hold {
+    fn for_order(side: OrderSide, price: Price, quantity: Quantity) -> Self {
+        match side {
+            OrderSide::Buy => Hold::Cash(price * quantity as f64),
+            OrderSide::Sell => Hold::Shares(quantity as i64)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FundsReject {
+    InsufficientFunds,
+    InsufficientPosition
+}
+
+impl fmt::Display for FundsReject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            FundsReject::InsufficientFunds => "not enough buying power",
+            FundsReject::InsufficientPosition => "not enough shares to sell"
+        })
+    }
+}
+
+// Positions and cash for every user, kept up to date from executions and funds changes as the
+// engines send them.  Engines log funds changes, journal their executions and send both again
+// while replaying, so this is rebuilt on every restart without needing a log of its own.  Resting
+// orders are followed the same way so that what they hold back is too.  Only used from the
+// reactor thread.
+pub struct Ledger {
+    accounts: RefCell<HashMap<UserId, Account>>,
+    // Whatever's left of each resting order
+    orders: RefCell<HashMap<OrderId, Order>>,
+    // Where exported statements go
+    statement_dir: Option<PathBuf>,
+    // Whether orders have to be covered by cash or shares.  What they hold back is tracked either
+    // way.
+    require_funds: bool,
+    // Whether a funds change has its turn, and the ones waiting for theirs
+    funds_busy: Cell<bool>,
+    funds_waiting: RefCell<VecDeque<oneshot::Sender<FundsTurn>>>
+}
+
+impl Ledger {
+    pub fn new(statement_dir: Option<PathBuf>, require_funds: bool) -> Self {
+        Ledger {
+            accounts: RefCell::new(HashMap::new()),
+            orders: RefCell::new(HashMap::new()),
+            statement_dir: statement_dir,
+            require_funds: require_funds,
+            funds_busy: Cell::new(false),
+            funds_waiting: RefCell::new(VecDeque::new())
+        }
+    }
+
+    // Holds back what the order needs until the reservation is dropped, which should be once the
+    // engine has answered.  By then an accepted order is being tracked by its ack.
+    pub fn reserve(ledger: &Rc<Self>, user: UserId, symbol: Symbol, side: OrderSide,
+                   price: Price, quantity: Quantity, short_selling: bool)
+                   -> Result<FundsReservation, FundsReject> {
+        let mut accounts = ledger.accounts.borrow_mut();
+        let account = accounts.entry(user).or_insert_with(Account::default);
+        let hold = Hold::for_order(side, price, quantity);
+        debug_assert!(price > 0.0 && price.is_finite() && quantity > 0,
+                      "sessions should turn down orders like {} at {}", quantity, price);
+
+        if ledger.require_funds {
+            match hold {
+                Hold::Cash(amount) if amount > account.available_cash() => {
+                    return Err(FundsReject::InsufficientFunds);
+                },
+                Hold::Shares(shares) if !short_selling &&
+                        shares > account.available_shares(&symbol) => {
+                    return Err(FundsReject::InsufficientPosition);
+                },
+                _ => {}
+            }
+        }
+
+        account.hold(symbol, hold);
+
+        Ok(FundsReservation {
+            ledger: ledger.clone(),
+            user: user,
+            symbol: symbol,
+            hold: hold
+        })
+    }
+
+    // Funds changes are worked out from the balance they start from, so only one is made at a
+    // time.  Completes once it's this change's turn, which lasts until the turn is dropped.
+    pub fn funds_turn(ledger: &Rc<Self>) -> Box<Future<Item=FundsTurn, Error=()>> {
+        if !ledger.funds_busy.get() {
+            ledger.funds_busy.set(true);
+            return Box::new(future::ok(FundsTurn { ledger: ledger.clone() }));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        ledger.funds_waiting.borrow_mut().push_back(tx);
+        Box::new(rx.map_err(|_| ()))
+    }
+
+    fn next_funds_turn(ledger: &Rc<Self>) {
+        let waiting = ledger.funds_waiting.borrow_mut().pop_front();
+        match waiting {
+            // Anyone who has given up waiting hands the turn straight on by dropping it
+            Some(tx) => { let _ = tx.send(FundsTurn { ledger: ledger.clone() }); },
+            None => { ledger.funds_busy.set(false); }
+        }
+    }
+
+    // Takes a withdrawal out of the user's available cash until the hold is dropped, which should
+    // be once the withdrawal has come back from its engine and been applied
+    pub fn hold_withdrawal(ledger: &Rc<Self>, user: UserId, amount: f64)
+                           -> Result<WithdrawalHold, FundsReject> {
+        let mut accounts = ledger.accounts.borrow_mut();
+        let account = accounts.entry(user).or_insert_with(Account::default);
+
+        if amount > account.available_cash() {
+            return Err(FundsReject::InsufficientFunds);
+        }
+
+        account.reserved_cash += amount;
+
+        Ok(WithdrawalHold {
+            ledger: ledger.clone(),
+            user: user,
+            amount: amount
+        })
+    }
+
+    pub fn funds(&self, msg: &FundsMessage) {
+        self.accounts.borrow_mut().entry(msg.user).or_insert_with(Account::default).cash +=
+            msg.amount;
+    }
+
+    // An engine took an order and this is what was left of it after matching
+    pub fn order_resting(&self, order: &Order) {
+        self.accounts.borrow_mut().entry(order.user).or_insert_with(Account::default)
+            .hold(order.symbol, Hold::for_order(order.side, order.price, order.quantity));
+        self.orders.borrow_mut().insert(order.id, *order);
+    }
+
+    pub fn order_cancelled(&self, order_id: OrderId) {
+        if let Some(order) = self.orders.borrow_mut().remove(&order_id) {
+            self.release(order.user, order.symbol,
+                         Hold::for_order(order.side, order.price, order.quantity));
+        }
+    }
+
+    // Delisting cancels everything on the book without acking each order
+    pub fn symbol_delisted(&self, symbol: Symbol) {
+        let cancelled: Vec<OrderId> = self.orders.borrow().values().filter(|o| {
+            o.symbol == symbol
+        }).map(|o| o.id).collect();
+
+        for order_id in cancelled.into_iter() {
+            self.order_cancelled(order_id);
+        }
+    }
+
+    pub fn account(&self, user: UserId) -> Account {
+        self.accounts.borrow().get(&user).cloned().unwrap_or_default()
+    }
+
+    pub fn execution(&self, execution: &Execution) {
+        let notional = execution.price * execution.quantity as f64;
+        let quantity = execution.quantity as i64;
+        let mut accounts = self.accounts.borrow_mut();
+
+        {
+            let buyer = accounts.entry(execution.buy_user).or_insert_with(Account::default);
+            buyer.cash -= notional;
+            buyer.positions.entry(execution.symbol).or_insert_with(Position::default)
+                .trade(quantity, execution.price);
+        }
+
+        {
+            let seller = accounts.entry(execution.sell_user).or_insert_with(Account::default);
+            seller.cash += notional;
+            seller.positions.entry(execution.symbol).or_insert_with(Position::default)
+                .trade(-quantity, execution.price);
+        }
+
+        // An order taking liquidity is matched before it's acked, so only the resting side is
+        // known about here.  The ack says what's left of the other one.
+        for &order_id in [execution.buy_order, execution.sell_order].iter() {
+            self.fill(&mut accounts, order_id, execution.quantity);
+        }
+    }
+
+    fn fill(&self, accounts: &mut HashMap<UserId, Account>, order_id: OrderId,
+            quantity: Quantity) {
+        let mut orders = self.orders.borrow_mut();
+        let done = match orders.get_mut(&order_id) {
+            Some(order) => {
+                let filled = quantity.min(order.quantity);
+                order.quantity -= filled;
+
+                if let Some(account) = accounts.get_mut(&order.user) {
+                    account.release(order.symbol, Hold::for_order(order.side, order.price, filled));
+                }
+
+                order.quantity == 0
+            },
+            None => false
+        };
+
+        if done {
+            orders.remove(&order_id);
+        }
+    }
+
+    fn release(&self, user: UserId, symbol: Symbol, hold: Hold) {
+        if let Some(account) = self.accounts.borrow_mut().get_mut(&user) {
+            account.release(symbol, hold);
+        }
+    }
+
+    // Writes every account as CSV to statement_<UTC time>.csv in the statement directory and
+    // returns the path.  The time goes down to the millisecond, and a counter is added to the
+    // name in the rare case that's still taken, so an earlier statement is never replaced.  Each
+    // user gets a row per symbol they've traded and then one with only their cash.
+    pub fn export_statement(&self) -> Result<PathBuf, String> {
+        let dir = try!(self.statement_dir.as_ref().ok_or("no statement directory configured"));
+        let now = time::now_utc();
+        let stamp = try!(time::strftime("%Y%m%d-%H%M%S", &now).map_err(|e| {
+            format!("failed to format statement time: {}", e)
+        }));
+        let stamp = format!("{}.{:03}", stamp, now.tm_nsec / 1000000);
+
+        let mut contents = "user,symbol,quantity,average_cost,realized_pnl,cash\n".to_string();
+
+        // Sorted so that statements from different days are easy to compare
+        let accounts = self.accounts.borrow();
+        let sorted: BTreeMap<_, _> = accounts.iter().collect();
+        for (user, account) in sorted.into_iter() {
+            let positions: BTreeMap<_, _> = account.positions.iter().map(|(s, p)| {
+                (s.to_string(), p)
+            }).collect();
+
+            for (symbol, position) in positions.into_iter() {
+                contents.push_str(format!("{},{},{},{},{},\n", user, symbol, position.quantity,
+                                          position.average_cost, position.realized_pnl).as_str());
+            }
+
+            contents.push_str(format!("{},,,,,{}\n", user, account.cash).as_str());
+        }
+
+        // Written to one side first so that nothing picking statements up sees half of one
+        let mut attempt = 0;
+        let (path, staging, mut file) = loop {
+            let name = match attempt {
+                0 => format!("statement_{}", stamp),
+                n => format!("statement_{}_{}", stamp, n)
+            };
+            let path = dir.join(format!("{}.csv", name));
+            let staging = dir.join(format!("{}.csv.tmp", name));
+            attempt += 1;
+
+            if path.exists() {
+                continue;
+            }
+
+            match OpenOptions::new().write(true).create_new(true).open(staging.as_path()) {
+                Ok(f) => { break (path, staging, f); },
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {},
+                Err(e) => {
+                    return Err(format!("failed to create {}: {}", staging.display(), e));
+                }
+            }
+        };
+
+        try!(file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()).map_err(|e| {
+            format!("failed to write {}: {}", staging.display(), e)
+        }));
+
+        try!(rename(staging.as_path(), path.as_path()).map_err(|e| {
+            format!("failed to move {} into place: {}", path.display(), e)
+        }));
+
+        Ok(path)
+    }
+}
+
+// An order that has been covered but hasn't been answered by its engine yet
+pub struct FundsReservation {
+    ledger: Rc<Ledger>,
+    user: UserId,
+    symbol: Symbol,
+    hold: Hold
+}
+
+impl Drop for FundsReservation {
+    fn drop(&mut self) {
+        self.ledger.release(self.user, self.symbol, self.hold);
+    }
+}
+
+// Cash kept back for a withdrawal that hasn't been applied yet
+pub struct WithdrawalHold {
+    ledger: Rc<Ledger>,
+    user: UserId,
+    amount: f64
+}
+
+impl Drop for WithdrawalHold {
+    fn drop(&mut self) {
+        if let Some(account) = self.ledger.accounts.borrow_mut().get_mut(&self.user) {
+            account.reserved_cash -= self.amount;
+        }
+    }
+}
+
+// A funds change's turn to be worked out and applied
+pub struct FundsTurn {
+    ledger: Rc<Ledger>
+}
+
+impl Drop for FundsTurn {
+    fn drop(&mut self) {
+        Ledger::next_funds_turn(&self.ledger);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use messages::FundsChange;
+    use std::fs::remove_dir_all;
+    use super::*;
+    use wal::test::scratch_dir;
+
+    const BUYER: UserId = 1;
+    const SELLER: UserId = 2;
+
+    fn symbol() -> Symbol {
+        Symbol::from_str("AAPL").unwrap()
+    }
+
+    fn traded(trades: &[(i64, Price)]) -> Position {
+        let mut position = Position::default();
+        for &(quantity, price) in trades.iter() {
+            position.trade(quantity, price);
+        }
+        position
+    }
+
+    fn order(seq: u64, user: UserId, side: OrderSide, price: Price, quantity: Quantity) -> Order {
+        Order {
+            id: OrderId::new(0, side, seq).unwrap(),
+            user: user,
+            symbol: symbol(),
+            side: side,
+            price: price,
+            quantity: quantity,
+            update: OrderTime::new(0, 0)
+        }
+    }
+
+    fn execution(seq: u64, buy: &Order, sell: &Order, price: Price, quantity: Quantity)
+            -> Execution {
+        Execution {
+            id: ExecutionId::new(0, seq).unwrap(),
+            ts: OrderTime::new(0, 0),
+            buy_order: buy.id,
+            buy_user: buy.user,
+            sell_order: sell.id,
+            sell_user: sell.user,
+            symbol: symbol(),
+            price: price,
+            quantity: quantity
+        }
+    }
+
+    #[test]
+    fn adding_to_a_position_averages_its_cost() {
+        let position = traded(&[(100, 10.0), (100, 12.0)]);
+        assert_eq!(position.quantity, 200);
+        assert_eq!(position.average_cost, 11.0);
+        assert_eq!(position.realized_pnl, 0.0);
+    }
+
+    #[test]
+    fn partial_close_keeps_the_average_cost() {
+        let position = traded(&[(100, 10.0), (100, 12.0), (-50, 13.0)]);
+        assert_eq!(position.quantity, 150);
+        assert_eq!(position.average_cost, 11.0);
+        assert_eq!(position.realized_pnl, 100.0);
+
+        let closed = traded(&[(100, 10.0), (100, 12.0), (-50, 13.0), (-150, 9.0)]);
+        assert_eq!(closed.quantity, 0);
+        assert_eq!(closed.average_cost, 0.0);
+        assert_eq!(closed.realized_pnl, 100.0 - 300.0);
+    }
+
+    #[test]
+    fn flipping_long_to_short_opens_at_the_trade_price() {
+        let position = traded(&[(100, 10.0), (-150, 12.0)]);
+        assert_eq!(position.quantity, -50);
+        assert_eq!(position.average_cost, 12.0);
+        assert_eq!(position.realized_pnl, 200.0);
+
+        // And back again
+        let position = traded(&[(100, 10.0), (-150, 12.0), (80, 11.0)]);
+        assert_eq!(position.quantity, 30);
+        assert_eq!(position.average_cost, 11.0);
+        assert_eq!(position.realized_pnl, 250.0);
+    }
+
+    #[test]
+    fn shorts_gain_when_the_price_falls() {
+        let position = traded(&[(-100, 10.0), (40, 8.0)]);
+        assert_eq!(position.quantity, -60);
+        assert_eq!(position.average_cost, 10.0);
+        assert_eq!(position.realized_pnl, 80.0);
+
+        let position = traded(&[(-100, 10.0), (40, 8.0), (60, 12.0)]);
+        assert_eq!(position.quantity, 0);
+        assert_eq!(position.realized_pnl, 80.0 - 120.0);
+    }
+
+    #[test]
+    fn partial_fills_release_part_of_the_hold() {
+        let ledger = Ledger::new(None, true);
+        ledger.funds(&FundsMessage {
+            user: BUYER,
+            change: FundsChange::Deposit,
+            amount: 5000.0
+        });
+
+        let buy = order(1, BUYER, OrderSide::Buy, 10.0, 100);
+        let sell = order(2, SELLER, OrderSide::Sell, 10.0, 100);
+        ledger.order_resting(&buy);
+        ledger.order_resting(&sell);
+        assert_eq!(ledger.account(BUYER).available_cash(), 4000.0);
+        assert_eq!(ledger.account(SELLER).available_shares(&symbol()), -100);
+
+        ledger.execution(&execution(1, &buy, &sell, 10.0, 40));
+
+        let buyer = ledger.account(BUYER);
+        assert_eq!(buyer.cash, 4600.0);
+        assert_eq!(buyer.reserved_cash, 600.0);
+        assert_eq!(buyer.positions[&symbol()].quantity, 40);
+
+        let seller = ledger.account(SELLER);
+        assert_eq!(seller.cash, 400.0);
+        assert_eq!(seller.reserved_shares[&symbol()], 60);
+        assert_eq!(seller.positions[&symbol()].quantity, -40);
+
+        // Filling the rest lets go of everything, and cancelling afterwards does nothing
+        ledger.execution(&execution(2, &buy, &sell, 10.0, 60));
+        ledger.order_cancelled(buy.id);
+        ledger.order_cancelled(sell.id);
+
+        assert_eq!(ledger.account(BUYER).reserved_cash, 0.0);
+        assert_eq!(ledger.account(BUYER).cash, 4000.0);
+        assert!(ledger.account(SELLER).reserved_shares.is_empty());
+        assert_eq!(ledger.account(SELLER).available_shares(&symbol()), -100);
+    }
+
+    #[test]
+    fn cancelling_releases_what_is_left() {
+        let ledger = Ledger::new(None, true);
+        let buy = order(1, BUYER, OrderSide::Buy, 10.0, 100);
+        let sell = order(2, SELLER, OrderSide::Sell, 10.0, 100);
+        ledger.order_resting(&buy);
+
+        // Only the resting side is tracked here
+        ledger.execution(&execution(1, &buy, &sell, 10.0, 30));
+        assert_eq!(ledger.account(BUYER).reserved_cash, 700.0);
+
+        ledger.order_cancelled(buy.id);
+        assert_eq!(ledger.account(BUYER).reserved_cash, 0.0);
+    }
+
+    #[test]
+    fn concurrent_withdrawals_cant_both_take_the_balance() {
+        let ledger = Rc::new(Ledger::new(None, true));
+        ledger.funds(&FundsMessage {
+            user: BUYER,
+            change: FundsChange::Deposit,
+            amount: 1000.0
+        });
+
+        let first = Ledger::hold_withdrawal(&ledger, BUYER, 1000.0).unwrap();
+        assert!(Ledger::hold_withdrawal(&ledger, BUYER, 1000.0).is_err());
+        // Nor can an order spend it in the meantime
+        assert!(Ledger::reserve(&ledger, BUYER, symbol(), OrderSide::Buy, 10.0, 1, false)
+            .is_err());
+
+        // The withdrawal comes back from its engine before the hold is let go
+        ledger.funds(&FundsMessage {
+            user: BUYER,
+            change: FundsChange::Withdrawal,
+            amount: -1000.0
+        });
+        assert_eq!(ledger.account(BUYER).available_cash(), -1000.0);
+        drop(first);
+
+        let account = ledger.account(BUYER);
+        assert_eq!(account.cash, 0.0);
+        assert_eq!(account.reserved_cash, 0.0);
+        assert!(Ledger::hold_withdrawal(&ledger, BUYER, 1.0).is_err());
+    }
+
+    #[test]
+    fn funds_changes_take_turns() {
+        let ledger = Rc::new(Ledger::new(None, false));
+
+        let first = Ledger::funds_turn(&ledger).wait().unwrap();
+        let second = Ledger::funds_turn(&ledger);
+        let third = Ledger::funds_turn(&ledger);
+
+        // Giving up on a turn passes it on to whoever's next
+        drop(second);
+        drop(first);
+        let third = third.wait().unwrap();
+
+        let fourth = Ledger::funds_turn(&ledger);
+        drop(third);
+        drop(fourth.wait().unwrap());
+
+        // Nobody has a turn, so the next is taken straight away
+        assert!(!ledger.funds_busy.get());
+        drop(Ledger::funds_turn(&ledger).wait().unwrap());
+    }
+
+    #[test]
+    fn statements_exported_together_get_their_own_files() {
+        let dir = scratch_dir("statements");
+        let ledger = Ledger::new(Some(dir.clone()), false);
+
+        let paths: Vec<PathBuf> = (0..5).map(|_| ledger.export_statement().unwrap()).collect();
+        for (i, path) in paths.iter().enumerate() {
+            assert!(path.is_file());
+            assert!(!paths[..i].contains(path));
+        }
+
+        remove_dir_all(dir.as_path()).unwrap();
+    }
+}
@@ -1,7 +1,16 @@
 use libcix::order::trade_types::*;
+use std::fmt;
+use std::io::Read;
+use std::time::Instant;
+use wal::{decode_current, WalEntry, WalPosition};
 
 pub const OPEN_ORDER_MSG_MAX_LENGTH: usize = 10;
 
+// Identifies a client request across the session/engine boundary so that responses can be matched
+// up with the session waiting on them.  These are assigned by the server context and are unique
+// for the lifetime of the process.
+pub type RequestId = u64;
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct OpenOrdersSequence {
     pub user: UserId,
@@ -26,25 +35,112 @@ impl OpenOrders {
     }
 }
 
+// When an engine got to each of its checkpoints for a request, sent back along with the ack so
+// that sessions can keep latency stats
+#[derive(Clone, Copy, Debug)]
+pub struct EngineTimes {
+    pub dequeued: Instant,
+    pub logged: Instant,
+    pub matched: Instant
+}
+
+impl EngineTimes {
+    pub fn new(now: Instant) -> Self {
+        EngineTimes {
+            dequeued: now,
+            logged: now,
+            matched: now
+        }
+    }
+}
+
 // XXX: Rename now that this includes control metadata as well
 pub enum SessionMessage {
     NewOrderAck {
+        request: RequestId,
         order_id: OrderId,
-        status: ErrorCode
+        symbol: Symbol,
+        status: ErrorCode,
+        // What was left on the book once the order had been matched, if anything
+        resting: Option<Order>,
+        times: EngineTimes
     },
     Execution(Execution),
+    CancelAck {
+        request: RequestId,
+        order_id: OrderId,
+        symbol: Symbol,
+        times: EngineTimes
+    },
+    // The engine turned down a new order or cancel
+    Reject {
+        request: RequestId,
+        reason: RejectReason
+    },
     SerializationResponse(u32),
-    OpenOrdersResponse(OpenOrders)
+    OpenOrdersResponse(OpenOrders),
+    // An engine changed its symbol table, either live or during replay
+    SymbolUpdate {
+        engine_id: u32,
+        symbol: Symbol,
+        symbol_id: u32,
+        status: SymbolStatus
+    },
+    // A change to a user's cash has been logged, either live or during replay
+    FundsUpdate(FundsMessage)
+}
+
+// Why an engine turned down a request
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RejectReason {
+    UnknownSymbol,
+    SymbolSuspended,
+    // Doesn't fit the symbol's tick size, lot size or quantity limit
+    InvalidOrder,
+    UnknownOrder,
+    NotOrderOwner,
+    // The engine only replayed part of its log so it can't take anything new
+    ReadOnly,
+    // Something went wrong in the engine rather than with the request
+    Internal
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            RejectReason::UnknownSymbol => "unknown symbol",
+            RejectReason::SymbolSuspended => "symbol is suspended",
+            RejectReason::InvalidOrder => "invalid order",
+            RejectReason::UnknownOrder => "unknown order",
+            RejectReason::NotOrderOwner => "not the order's owner",
+            RejectReason::ReadOnly => "engine is read-only",
+            RejectReason::Internal => "internal error"
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SymbolStatus {
+    Active,
+    // Resting orders stay on the book and can be cancelled but new orders are rejected
+    Suspended,
+    // The book is gone.  The ID stays reserved since old orders and executions still refer to it.
+    Delisted
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct NewOrderMessage {
+    pub request:    RequestId,
     pub user:       UserId,
+    // Sessions leave this unset; the engine assigns the real ID before the message is logged
     pub order_id:   OrderId,
     pub symbol:     Symbol,
     pub side:       OrderSide,
     pub price:      Price,
-    pub quantity:   Quantity
+    pub quantity:   Quantity,
+    // Also assigned by the engine so that replay sees the same time the order was accepted at
+    #[serde(with="TimeSpecDef")]
+    pub ts:         OrderTime
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -57,10 +153,39 @@ pub struct ChangeOrderMessage {
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct CancelOrderMessage {
+    pub request:    RequestId,
     pub user:       UserId,
     pub order_id:   OrderId
 }
 
+// Symbol IDs are assigned by the router and are never reused, even once a symbol is delisted
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SymbolMessage {
+    pub symbol:     Symbol,
+    pub symbol_id:  u32
+}
+
+// Every funds change goes to this engine, whatever the user trades
+pub const FUNDS_ENGINE: u32 = 0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FundsChange {
+    Deposit,
+    Withdrawal,
+    SetBalance
+}
+
+// Cash an admin has moved into or out of a user's account.  Engines only log these and pass them
+// back, so that balances are rebuilt on replay and followed by standbys.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FundsMessage {
+    pub user:       UserId,
+    pub change:     FundsChange,
+    // Added to the balance.  Setting a balance is logged as the difference from what it was, so
+    // replay ends up in the same place however responses from different engines interleave.
+    pub amount:     f64
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum EngineMessage {
     // This is a temporary hack to avoid reading messages from empty log files
@@ -70,7 +195,186 @@ pub enum EngineMessage {
     CancelOrder(CancelOrderMessage),
     // Don't respond to this until all previous messages have been processed
     SerializationMessage(u32),
-    GetOpenOrdersMessaage(OpenOrdersSequence)
+    GetOpenOrdersMessaage(OpenOrdersSequence),
+    ListSymbol(SymbolMessage),
+    SuspendSymbol(SymbolMessage),
+    ResumeSymbol(SymbolMessage),
+    // Cancels everything resting on the book first
+    DelistSymbol(SymbolMessage),
+    Funds(FundsMessage)
+}
+
+impl EngineMessage {
+    // The session request waiting to hear how this turned out, if there is one
+    pub fn request(&self) -> Option<RequestId> {
+        match *self {
+            EngineMessage::NewOrder(ref msg) => Some(msg.request),
+            EngineMessage::CancelOrder(ref msg) => Some(msg.request),
+            _ => None
+        }
+    }
+}
+
+// Schema history:
+//   0: logs written before files had headers, when sessions assigned order IDs and orders weren't
+//      timestamped
+//   1: orders carry the request they came from and the time the engine accepted them
+//   2: symbols can be listed, suspended and delisted at runtime.  This only added variants so
+//      version 1 entries decode the same way.
+//   3: cancels carry the request they came from
+//   4: funds changes.  This only added a variant so version 3 entries decode the same way.
+impl WalEntry for EngineMessage {
+    const SCHEMA_VERSION: u32 = 4;
+
+    fn is_null(&self) -> bool {
+        if let EngineMessage::NullMessage = *self {
+            true
+        } else {
+            false
+        }
+    }
+
+    fn decode<R: Read>(version: u32, reader: &mut R) -> Result<Self, String> {
+        match version {
+            0 => decode_current::<v0::EngineMessage, R>(reader).map(EngineMessage::from),
+            1 | 2 => decode_current::<v1::EngineMessage, R>(reader).map(EngineMessage::from),
+            3 | 4 => decode_current(reader),
+            _ => Err(format!("unsupported schema version {}", version))
+        }
+    }
+}
+
+// Old encodings of EngineMessage, kept only to read existing logs.  These must never change.
+mod v0 {
+    use libcix::order::trade_types::*;
+    use super::OpenOrdersSequence;
+
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    pub struct NewOrderMessage {
+        pub user:       UserId,
+        pub order_id:   OrderId,
+        pub symbol:     Symbol,
+        pub side:       OrderSide,
+        pub price:      Price,
+        pub quantity:   Quantity
+    }
+
+    // Also used by versions 1 and 2
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    pub struct CancelOrderMessage {
+        pub user:       UserId,
+        pub order_id:   OrderId
+    }
+
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    pub enum EngineMessage {
+        NullMessage,
+        NewOrder(NewOrderMessage),
+        CancelOrder(CancelOrderMessage),
+        SerializationMessage(u32),
+        GetOpenOrdersMessaage(OpenOrdersSequence)
+    }
+}
+
+// Versions 1 and 2.  Version 1 logs never contain the symbol variants, which were only added on
+// the end.  New orders and symbol changes are still encoded the same way so those are shared with
+// the current version; they need copying in here if that ever changes.
+mod v1 {
+    use super::{NewOrderMessage, OpenOrdersSequence, SymbolMessage};
+    use super::v0::CancelOrderMessage;
+
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    pub enum EngineMessage {
+        NullMessage,
+        NewOrder(NewOrderMessage),
+        CancelOrder(CancelOrderMessage),
+        SerializationMessage(u32),
+        GetOpenOrdersMessaage(OpenOrdersSequence),
+        ListSymbol(SymbolMessage),
+        SuspendSymbol(SymbolMessage),
+        ResumeSymbol(SymbolMessage),
+        DelistSymbol(SymbolMessage)
+    }
+}
+
+impl From<v0::CancelOrderMessage> for CancelOrderMessage {
+    fn from(msg: v0::CancelOrderMessage) -> Self {
+        CancelOrderMessage {
+            // Nobody could still be waiting on these either
+            request:    0,
+            user:       msg.user,
+            order_id:   msg.order_id
+        }
+    }
+}
+
+impl From<v0::EngineMessage> for EngineMessage {
+    fn from(msg: v0::EngineMessage) -> Self {
+        match msg {
+            v0::EngineMessage::NullMessage => EngineMessage::NullMessage,
+            v0::EngineMessage::NewOrder(order) => EngineMessage::NewOrder(NewOrderMessage {
+                // Nobody could still be waiting on these
+                request:    0,
+                user:       order.user,
+                order_id:   order.order_id,
+                symbol:     order.symbol,
+                side:       order.side,
+                price:      order.price,
+                quantity:   order.quantity,
+                // The real time is lost.  Leaving it at zero means that ties are broken by order
+                // sequence number, which preserves the original arrival order.
+                ts:         OrderTime::new(0, 0)
+            }),
+            v0::EngineMessage::CancelOrder(cancel) => {
+                EngineMessage::CancelOrder(CancelOrderMessage::from(cancel))
+            },
+            v0::EngineMessage::SerializationMessage(seq) => {
+                EngineMessage::SerializationMessage(seq)
+            },
+            v0::EngineMessage::GetOpenOrdersMessaage(seq) => {
+                EngineMessage::GetOpenOrdersMessaage(seq)
+            }
+        }
+    }
+}
+
+impl From<v1::EngineMessage> for EngineMessage {
+    fn from(msg: v1::EngineMessage) -> Self {
+        match msg {
+            v1::EngineMessage::NullMessage => EngineMessage::NullMessage,
+            v1::EngineMessage::NewOrder(order) => EngineMessage::NewOrder(order),
+            v1::EngineMessage::CancelOrder(cancel) => {
+                EngineMessage::CancelOrder(CancelOrderMessage::from(cancel))
+            },
+            v1::EngineMessage::SerializationMessage(seq) => {
+                EngineMessage::SerializationMessage(seq)
+            },
+            v1::EngineMessage::GetOpenOrdersMessaage(seq) => {
+                EngineMessage::GetOpenOrdersMessaage(seq)
+            },
+            v1::EngineMessage::ListSymbol(msg) => EngineMessage::ListSymbol(msg),
+            v1::EngineMessage::SuspendSymbol(msg) => EngineMessage::SuspendSymbol(msg),
+            v1::EngineMessage::ResumeSymbol(msg) => EngineMessage::ResumeSymbol(msg),
+            v1::EngineMessage::DelistSymbol(msg) => EngineMessage::DelistSymbol(msg)
+        }
+    }
+}
+
+// What actually gets sent to engine threads
+#[derive(Clone, Copy, Debug)]
+pub enum EngineRequest {
+    // From sessions on this server; the engine assigns IDs and timestamps and logs it
+    Live(EngineMessage),
+    // Already accepted and logged by a replication primary at the given position, so it is
+    // logged and applied as-is
+    Replicated(WalPosition, EngineMessage),
+    // Print every book's resting orders to stdout
+    DumpBooks,
+    // Close the logs, optionally after writing a snapshot of the books, and stop.  Anything sent
+    // after this is never looked at.
+    Shutdown {
+        snapshot: bool
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -79,3 +383,192 @@ pub enum MdMessage {
     L2Message(L2Md),
     Execution(MdExecution)
 }
+
+// Everything the engine tells the outside world about an order, as recorded in the output journal
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OutputMessage {
+    // Zeroed journal memory decodes to this
+    NullMessage,
+    NewOrderAck {
+        request: RequestId,
+        order_id: OrderId,
+        status: ErrorCode
+    },
+    Execution(Execution),
+    CancelResult {
+        user: UserId,
+        order_id: OrderId,
+        status: ErrorCode
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    // Position in the input WAL of the message that produced this output
+    pub input: WalPosition,
+    pub output: OutputMessage
+}
+
+// Journals have only ever been written in one format, including before files had headers
+impl WalEntry for JournalEntry {
+    const SCHEMA_VERSION: u32 = 1;
+
+    fn decode<R: Read>(version: u32, reader: &mut R) -> Result<Self, String> {
+        match version {
+            0 | 1 => decode_current(reader),
+            _ => Err(format!("unsupported schema version {}", version))
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        if let OutputMessage::NullMessage = self.output {
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Everything on an engine's books when it shut down.  Replaying its log up to `position` ends up
+// in the same state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BookSnapshot {
+    pub engine_id: u32,
+    pub position: WalPosition,
+    pub books: Vec<SnapshotBook>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotBook {
+    pub symbol: Symbol,
+    pub symbol_id: u32,
+    pub suspended: bool,
+    // Sequence number the next order ID for this symbol gets
+    pub next_sequence: u64,
+    pub orders: Vec<Order>
+}
+
+#[cfg(test)]
+mod test {
+    use bincode::{serialize, Infinite};
+    use libcix::order::trade_types::*;
+    use serde::Serialize;
+    use super::*;
+    use wal::WalEntry;
+
+    fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+        serialize(value, Infinite).unwrap()
+    }
+
+    // Decodes the whole of `bytes`, which has to hold exactly one entry
+    fn decode(version: u32, bytes: &[u8]) -> EngineMessage {
+        let mut reader = bytes;
+        let msg = EngineMessage::decode(version, &mut reader).unwrap();
+        assert!(reader.is_empty(), "{} bytes left over", reader.len());
+        msg
+    }
+
+    fn order_id() -> OrderId {
+        OrderId::new(2, OrderSide::Sell, 7).unwrap()
+    }
+
+    fn symbol() -> Symbol {
+        Symbol::from_str("FB").unwrap()
+    }
+
+    fn check_cancel(msg: EngineMessage, request: RequestId) {
+        match msg {
+            EngineMessage::CancelOrder(cancel) => {
+                assert_eq!(cancel.request, request);
+                assert_eq!(cancel.user, 3);
+                assert_eq!(cancel.order_id, order_id());
+            },
+            _ => panic!("expected a cancel, got {:?}", msg)
+        }
+    }
+
+    // Variants are encoded as their index followed by their fields
+    #[test]
+    fn version_0_orders_get_no_request_or_time() {
+        let bytes = encode(&(1u32, 3u64, order_id(), symbol(), OrderSide::Sell, 12.5f64, 40u32));
+
+        match decode(0, bytes.as_slice()) {
+            EngineMessage::NewOrder(order) => {
+                assert_eq!(order.request, 0);
+                assert_eq!(order.user, 3);
+                assert_eq!(order.order_id, order_id());
+                assert_eq!(order.symbol, symbol());
+                assert_eq!(order.side, OrderSide::Sell);
+                assert_eq!(order.price, 12.5);
+                assert_eq!(order.quantity, 40);
+                assert_eq!(order.ts, OrderTime::new(0, 0));
+            },
+            msg => panic!("expected an order, got {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn cancels_before_version_3_get_no_request() {
+        let bytes = encode(&(2u32, 3u64, order_id()));
+
+        for &version in [0, 1, 2].iter() {
+            check_cancel(decode(version, bytes.as_slice()), 0);
+        }
+    }
+
+    #[test]
+    fn version_1_orders_and_symbol_changes_decode_unchanged() {
+        let order = NewOrderMessage {
+            request: 9,
+            user: 3,
+            order_id: order_id(),
+            symbol: symbol(),
+            side: OrderSide::Sell,
+            price: 12.5,
+            quantity: 40,
+            ts: OrderTime::new(1500000000, 250)
+        };
+
+        match decode(1, encode(&(1u32, order)).as_slice()) {
+            EngineMessage::NewOrder(decoded) => {
+                assert_eq!(decoded.request, 9);
+                assert_eq!(decoded.order_id, order_id());
+                assert_eq!(decoded.ts, order.ts);
+            },
+            msg => panic!("expected an order, got {:?}", msg)
+        }
+
+        let listed = SymbolMessage {
+            symbol: symbol(),
+            symbol_id: 4
+        };
+
+        match decode(2, encode(&(5u32, listed)).as_slice()) {
+            EngineMessage::ListSymbol(decoded) => {
+                assert_eq!(decoded.symbol, symbol());
+                assert_eq!(decoded.symbol_id, 4);
+            },
+            msg => panic!("expected a listing, got {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn current_versions_round_trip() {
+        let cancel = EngineMessage::CancelOrder(CancelOrderMessage {
+            request: 11,
+            user: 3,
+            order_id: order_id()
+        });
+
+        for &version in [3, EngineMessage::SCHEMA_VERSION].iter() {
+            check_cancel(decode(version, encode(&cancel).as_slice()), 11);
+        }
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let bytes = encode(&EngineMessage::SerializationMessage(1));
+        assert!(EngineMessage::decode(EngineMessage::SCHEMA_VERSION + 1,
+                                      &mut bytes.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use bincode::{serialize, deserialize, Infinite};
+use futures::{Future, Sink};
+use futures::sync::mpsc;
+use libcix::order::trade_types::*;
+use messages::EngineMessage;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+use time;
+use wal::{WalDirectoryReader, WalPosition, last_entry_position};
+
+// Standbys treat a primary that has been silent for this long as gone and reconnect
+const READ_TIMEOUT_SECS: u64 = 10;
+const HEARTBEAT_SECS: u64 = 1;
+// Only report lag this often unless we're actually behind
+const LAG_REPORT_INTERVAL: u32 = 60;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ReplicatedEntry {
+    pub engine_id: u32,
+    // Where the primary logged this entry.  Standbys log with the same segment size, so they're
+    // expected to log it at exactly the same position.
+    pub position: WalPosition,
+    // Primary's wall clock time when the entry was published, for measuring lag
+    #[serde(with="TimeSpecDef")]
+    pub sent: OrderTime,
+    pub msg: EngineMessage
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum ReplicationFrame {
+    // standby -> primary: the last entry each engine already has
+    Hello(Vec<(u32, Option<WalPosition>)>),
+    Entry(ReplicatedEntry),
+    // primary -> standby when there's nothing else to send
+    Heartbeat {
+        #[serde(with="TimeSpecDef")]
+        ts: OrderTime,
+        latest: Vec<(u32, WalPosition)>
+    }
+}
+
+fn write_frame<W: Write>(w: &mut W, frame: &ReplicationFrame) -> Result<(), String> {
+    let bytes = try!(serialize(frame, Infinite).map_err(|e| {
+        format!("failed to encode replication frame: {}", e)
+    }));
+    let len = bytes.len() as u32;
+    let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+
+    try!(w.write_all(&header).and_then(|_| w.write_all(bytes.as_slice())).map_err(|e| {
+        format!("failed to send replication frame: {}", e)
+    }));
+
+    Ok(())
+}
+
+fn read_frame<R: Read>(r: &mut R) -> Result<ReplicationFrame, String> {
+    let mut header = [0u8; 4];
+    try!(r.read_exact(&mut header).map_err(|e| {
+        format!("failed to read replication frame: {}", e)
+    }));
+
+    let len = ((header[0] as usize) << 24) | ((header[1] as usize) << 16) |
+              ((header[2] as usize) << 8) | (header[3] as usize);
+    let mut bytes = vec![0u8; len];
+    try!(r.read_exact(bytes.as_mut_slice()).map_err(|e| {
+        format!("failed to read replication frame: {}", e)
+    }));
+
+    deserialize(bytes.as_slice()).map_err(|e| {
+        format!("invalid replication frame: {}", e)
+    })
+}
+
+// Primary side.  Engines publish every entry here right after it's committed to their WAL and
+// each connected standby gets its own thread that catches up from disk and then streams live
+// entries.
+pub struct ReplicationHub {
+    subscribers: Mutex<Vec<std_mpsc::Sender<ReplicatedEntry>>>,
+    latest: Mutex<HashMap<u32, WalPosition>>,
+    engine_dirs: Vec<(u32, PathBuf)>
+}
+
+impl ReplicationHub {
+    // Starts from whatever the engines logged before a restart, so that standbys can catch up on
+    // it before anything new is published
+    pub fn new(engine_dirs: Vec<(u32, PathBuf)>) -> Result<Arc<Self>, String> {
+        let mut latest = HashMap::new();
+        for &(engine_id, ref dir) in engine_dirs.iter() {
+            // Engines that have never run haven't made their directories yet
+            if !dir.is_dir() {
+                continue;
+            }
+
+            if let Some(p) = try!(last_entry_position(dir.as_path())) {
+                latest.insert(engine_id, p);
+            }
+        }
+
+        Ok(Arc::new(ReplicationHub {
+            subscribers: Mutex::new(Vec::new()),
+            latest: Mutex::new(latest),
+            engine_dirs: engine_dirs
+        }))
+    }
+
+    pub fn publish(&self, engine_id: u32, position: WalPosition, msg: EngineMessage) {
+        self.latest.lock().unwrap().insert(engine_id, position);
+
+        let entry = ReplicatedEntry {
+            engine_id: engine_id,
+            position: position,
+            sent: time::get_time(),
+            msg: msg
+        };
+
+        // Senders only fail once the standby thread has gone away
+        self.subscribers.lock().unwrap().retain(|s| s.send(entry).is_ok());
+    }
+
+    pub fn listen<A: ToSocketAddrs>(hub: Arc<Self>, addr: A) -> Result<(), String> {
+        let listener = try!(TcpListener::bind(addr).map_err(|e| {
+            format!("failed to bind replication listener: {}", e)
+        }));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s) => {
+                        let h = hub.clone();
+                        thread::spawn(move || {
+                            let peer = s.peer_addr().map(|a| a.to_string())
+                                .unwrap_or("<unknown>".to_string());
+                            println!("standby {} connected", peer);
+                            if let Err(e) = h.serve_standby(s) {
+                                println!("standby {} disconnected: {}", peer, e);
+                            }
+                        });
+                    },
+                    Err(e) => {
+                        println!("failed to accept standby connection: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn serve_standby(&self, mut stream: TcpStream) -> Result<(), String> {
+        let mut sent: HashMap<u32, WalPosition> = match try!(read_frame(&mut stream)) {
+            ReplicationFrame::Hello(positions) => {
+                positions.into_iter().filter_map(|(engine, p)| p.map(|p| (engine, p))).collect()
+            },
+            _ => { return Err("expected hello from standby".to_string()); }
+        };
+
+        // Subscribe before catching up so that nothing committed in the meantime is missed.
+        // Anything we see twice is filtered out by position below.
+        let (tx, rx) = std_mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+
+        // Only read what has already been published; anything past that could be partially
+        // written and will show up on the live feed anyway
+        let latest = self.latest.lock().unwrap().clone();
+
+        for &(engine_id, ref dir) in self.engine_dirs.iter() {
+            let limit = match latest.get(&engine_id) {
+                Some(p) => *p,
+                None => { continue; }
+            };
+
+            let mut reader = try!(WalDirectoryReader::<EngineMessage>::new(dir.as_path()));
+            while let Some(entry) = reader.next_entry() {
+                let (position, msg) = try!(entry);
+                if position > limit {
+                    break;
+                }
+
+                if sent.get(&engine_id).map(|p| position <= *p).unwrap_or(false) {
+                    continue;
+                }
+
+                try!(write_frame(&mut stream, &ReplicationFrame::Entry(ReplicatedEntry {
+                    engine_id: engine_id,
+                    position: position,
+                    sent: time::get_time(),
+                    msg: msg
+                })));
+                sent.insert(engine_id, position);
+            }
+        }
+
+        loop {
+            match rx.recv_timeout(Duration::new(HEARTBEAT_SECS, 0)) {
+                Ok(entry) => {
+                    if sent.get(&entry.engine_id).map(|p| entry.position <= *p).unwrap_or(false) {
+                        continue;
+                    }
+
+                    try!(write_frame(&mut stream, &ReplicationFrame::Entry(entry)));
+                    sent.insert(entry.engine_id, entry.position);
+                },
+                Err(std_mpsc::RecvTimeoutError::Timeout) => {
+                    let latest = self.latest.lock().unwrap().iter().map(|(e, p)| (*e, *p))
+                        .collect();
+                    try!(write_frame(&mut stream, &ReplicationFrame::Heartbeat {
+                        ts: time::get_time(),
+                        latest: latest
+                    }));
+                },
+                Err(std_mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err("replication hub shut down".to_string());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StandbyStatus {
+    pub connected: bool,
+    // Last entry received for each engine
+    pub received: HashMap<u32, WalPosition>,
+    // Last entry the primary has told us about for each engine
+    pub primary: HashMap<u32, WalPosition>,
+    // Time between the primary publishing the most recent entry and us receiving it
+    pub latency: Option<time::Duration>
+}
+
+impl StandbyStatus {
+    pub fn behind(&self) -> bool {
+        self.primary.iter().any(|(engine, p)| {
+            self.received.get(engine).map(|r| r < p).unwrap_or(true)
+        })
+    }
+
+    pub fn report(&self) {
+        for (engine, p) in self.primary.iter() {
+            let received = self.received.get(engine).map(|r| r.to_string())
+                .unwrap_or("nothing".to_string());
+            println!("replication engine {}: received {}, primary at {}", engine, received, p);
+        }
+
+        if let Some(l) = self.latency {
+            println!("replication latency {}us", l.num_microseconds().unwrap_or(-1));
+        }
+    }
+}
+
+// Standby side.  Owns a thread that pulls entries from the primary and hands them to the main
+// reactor, which applies them through the router.
+pub struct StandbyHandle {
+    pub status: Arc<Mutex<StandbyStatus>>,
+    stop: Arc<AtomicBool>
+}
+
+impl StandbyHandle {
+    pub fn start(primary: String, engine_dirs: Vec<(u32, PathBuf)>,
+                 tx: mpsc::Sender<ReplicatedEntry>) -> Result<Self, String> {
+        let mut received = HashMap::new();
+        for &(engine_id, ref dir) in engine_dirs.iter() {
+            if let Some(p) = try!(last_entry_position(dir.as_path())) {
+                received.insert(engine_id, p);
+            }
+        }
+
+        let status = Arc::new(Mutex::new(StandbyStatus {
+            received: received,
+            .. StandbyStatus::default()
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_status = status.clone();
+        let thread_stop = stop.clone();
+        thread::spawn(move || {
+            // Dropping the sender when we're done is what tells the main reactor that there's
+            // nothing left to apply
+            let mut tx = tx;
+
+            while !thread_stop.load(Ordering::SeqCst) {
+                match Self::follow(&primary, &engine_dirs, &mut tx, &thread_status, &thread_stop) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        println!("lost connection to primary {}: {}", primary, e);
+                        thread_status.lock().unwrap().connected = false;
+                        thread::sleep(Duration::new(1, 0));
+                    }
+                }
+            }
+
+            println!("stopped replicating from {}", primary);
+        });
+
+        Ok(StandbyHandle {
+            status: status,
+            stop: stop
+        })
+    }
+
+    fn follow(primary: &String, engine_dirs: &Vec<(u32, PathBuf)>,
+              tx: &mut mpsc::Sender<ReplicatedEntry>, status: &Arc<Mutex<StandbyStatus>>,
+              stop: &Arc<AtomicBool>) -> Result<(), String> {
+        let mut stream = try!(TcpStream::connect(primary.as_str()).map_err(|e| {
+            format!("failed to connect: {}", e)
+        }));
+        try!(stream.set_read_timeout(Some(Duration::new(READ_TIMEOUT_SECS, 0))).map_err(|e| {
+            format!("failed to set timeout: {}", e)
+        }));
+
+        let hello = {
+            let s = status.lock().unwrap();
+            engine_dirs.iter().map(|&(engine_id, _)| {
+                (engine_id, s.received.get(&engine_id).map(|p| *p))
+            }).collect()
+        };
+        try!(write_frame(&mut stream, &ReplicationFrame::Hello(hello)));
+
+        status.lock().unwrap().connected = true;
+        println!("replicating from primary {}", primary);
+
+        let mut heartbeats = 0u32;
+
+        while !stop.load(Ordering::SeqCst) {
+            match try!(read_frame(&mut stream)) {
+                ReplicationFrame::Entry(entry) => {
+                    {
+                        let mut s = status.lock().unwrap();
+                        s.received.insert(entry.engine_id, entry.position);
+                        s.latency = Some(time::get_time() - entry.sent);
+
+                        let newer = s.primary.get(&entry.engine_id).map(|p| entry.position > *p)
+                            .unwrap_or(true);
+                        if newer {
+                            s.primary.insert(entry.engine_id, entry.position);
+                        }
+                    }
+
+                    try!(tx.send(entry).wait().map(|_| ()).map_err(|_| {
+                        "server is no longer accepting replicated entries".to_string()
+                    }));
+                },
+                ReplicationFrame::Heartbeat { ts, latest } => {
+                    let s = {
+                        let mut s = status.lock().unwrap();
+                        for (engine_id, p) in latest.into_iter() {
+                            s.primary.insert(engine_id, p);
+                        }
+                        s.clone()
+                    };
+
+                    heartbeats += 1;
+                    if s.behind() || heartbeats % LAG_REPORT_INTERVAL == 0 {
+                        s.report();
+                    }
+                },
+                ReplicationFrame::Hello(_) => {
+                    return Err("unexpected hello from primary".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::remove_dir_all;
+    use std::ops::Range;
+    use std::path::Path;
+    use super::*;
+    use wal::Wal;
+    use wal::test::{scratch_dir, CONFIG};
+
+    fn log(dir: &Path, seqs: Range<u32>) -> Vec<WalPosition> {
+        let mut wal = Wal::<EngineMessage>::new(dir, CONFIG).unwrap();
+        let positions = seqs.map(|seq| {
+            wal.write_entry(&EngineMessage::SerializationMessage(seq)).unwrap()
+        }).collect();
+        wal.close().unwrap();
+        positions
+    }
+
+    // Connects a standby that already has `received` to the hub and says hello
+    fn connect(hub: Arc<ReplicationHub>, received: Option<WalPosition>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = hub.serve_standby(stream);
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_frame(&mut stream, &ReplicationFrame::Hello(vec![(0, received)])).unwrap();
+        stream
+    }
+
+    fn expect_entry(stream: &mut TcpStream) -> (WalPosition, u32) {
+        match read_frame(stream).unwrap() {
+            ReplicationFrame::Entry(ReplicatedEntry {
+                engine_id: 0,
+                position,
+                msg: EngineMessage::SerializationMessage(seq),
+                ..
+            }) => (position, seq),
+            frame => panic!("expected an entry, not {:?}", frame)
+        }
+    }
+
+    #[test]
+    fn standbys_catch_up_on_what_was_logged_before_a_restart() {
+        let dir = scratch_dir("replication_restart");
+        let engine_dir = dir.join("engine_0");
+        let positions = log(engine_dir.as_path(), 0..5);
+
+        // A primary that has just restarted and hasn't published anything yet
+        let hub = ReplicationHub::new(vec![(0, engine_dir.clone()), (1, dir.join("engine_1"))])
+            .unwrap();
+        assert_eq!(hub.latest.lock().unwrap().get(&0), Some(&positions[4]));
+        assert!(hub.latest.lock().unwrap().get(&1).is_none());
+
+        // This standby already has the first two
+        let mut stream = connect(hub.clone(), Some(positions[1]));
+        for i in 2..5 {
+            assert_eq!(expect_entry(&mut stream), (positions[i], i as u32));
+        }
+
+        match read_frame(&mut stream).unwrap() {
+            ReplicationFrame::Heartbeat { latest, .. } => {
+                assert_eq!(latest, vec![(0, positions[4])]);
+            },
+            frame => panic!("expected a heartbeat, not {:?}", frame)
+        }
+
+        // Then it follows along with what's logged from here on
+        let mut wal = Wal::<EngineMessage>::new(engine_dir.as_path(), CONFIG).unwrap();
+        let next = wal.write_entry(&EngineMessage::SerializationMessage(5)).unwrap();
+        wal.close().unwrap();
+        hub.publish(0, next, EngineMessage::SerializationMessage(5));
+        assert_eq!(expect_entry(&mut stream), (next, 5));
+
+        drop(stream);
+        remove_dir_all(dir.as_path()).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use futures::{Async, Poll, Stream};
+use futures::task::{park, Task};
+use std::cell::{Cell, UnsafeCell};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{fence, spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+// What a producer does when the ring is full, or a consumer when it's empty
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitStrategy {
+    // Spin on the other side's index.  Lowest latency, but burns a core per waiting thread.
+    BusyPoll,
+    // Sleep until the other side wakes us up
+    Park
+}
+
+// Someone waiting for the other end of the ring to do something
+enum Waiter {
+    Thread(Thread),
+    // A consumer polled as a stream from inside a reactor
+    Task(Task)
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Waiter::Thread(t) => t.unpark(),
+            Waiter::Task(t) => t.unpark()
+        }
+    }
+}
+
+// Only the slow path goes through this: the flag is checked first and the lock is only taken
+// when the other side has actually gone to sleep
+struct WaitSlot {
+    sleeping: AtomicBool,
+    waiter: Mutex<Option<Waiter>>
+}
+
+impl WaitSlot {
+    fn new() -> Self {
+        WaitSlot {
+            sleeping: AtomicBool::new(false),
+            waiter: Mutex::new(None)
+        }
+    }
+
+    // Has to be followed by checking the condition again before sleeping, otherwise a wakeup
+    // that happens in between is lost.  The fences here and in wake make sure at least one side
+    // sees the other's write.
+    fn prepare(&self, waiter: Waiter) {
+        *self.waiter.lock().unwrap() = Some(waiter);
+        self.sleeping.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+    }
+
+    fn cancel(&self) {
+        self.sleeping.store(false, Ordering::SeqCst);
+    }
+
+    fn wake(&self) {
+        fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) && self.sleeping.swap(false, Ordering::SeqCst) {
+            if let Some(w) = self.waiter.lock().unwrap().take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+// Keeps each index on its own cache line so that the producer and consumer don't keep stealing
+// it from each other
+struct Padded {
+    value: AtomicUsize,
+    _pad: [u64; 7]
+}
+
+impl Padded {
+    fn new() -> Self {
+        Padded {
+            value: AtomicUsize::new(0),
+            _pad: [0u64; 7]
+        }
+    }
+}
+
+struct Ring<T> {
+    slots: Vec<UnsafeCell<Option<T>>>,
+    mask: usize,
+    // Next slot the consumer will read.  Only the consumer writes this.
+    head: Padded,
+    // Next slot the producer will write.  Only the producer writes this.
+    tail: Padded,
+    // Set when either end is dropped
+    closed: AtomicBool,
+    strategy: WaitStrategy,
+    // Consumer waiting for something to read
+    readable: WaitSlot,
+    // Producer waiting for room to write
+    writable: WaitSlot
+}
+
+// Each slot is only ever touched by one side at a time, which the indexes guarantee
+unsafe impl<T: Send> Sync for Ring<T> {}
+unsafe impl<T: Send> Send for Ring<T> {}
+
+// The sending end.  There's only ever one and it can't be cloned; anything that needs to share
+// it has to do so from a single thread.
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    // Last head we saw, so we only have to look at the consumer's index when we think it's full
+    head_cache: Cell<usize>
+}
+
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    // Last tail we saw, so we only have to look at the producer's index when we think it's empty
+    tail_cache: Cell<usize>
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+// Bounded single-producer, single-consumer queue holding at least `capacity` items
+pub fn channel<T: Send>(capacity: usize, strategy: WaitStrategy) -> (Producer<T>, Consumer<T>) {
+    let size = capacity.max(2).next_power_of_two();
+    let ring = Arc::new(Ring {
+        slots: (0..size).map(|_| UnsafeCell::new(None)).collect(),
+        mask: size - 1,
+        head: Padded::new(),
+        tail: Padded::new(),
+        closed: AtomicBool::new(false),
+        strategy: strategy,
+        readable: WaitSlot::new(),
+        writable: WaitSlot::new()
+    });
+
+    (Producer { ring: ring.clone(), head_cache: Cell::new(0) },
+     Consumer { ring: ring, tail_cache: Cell::new(0) })
+}
+
+impl<T: Send> Producer<T> {
+    // Hands the item back if the ring is full or the consumer is gone
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let ring = self.ring.as_ref();
+        if ring.closed.load(Ordering::Acquire) {
+            return Err(item);
+        }
+
+        let tail = ring.tail.value.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head_cache.get()) > ring.mask {
+            self.head_cache.set(ring.head.value.load(Ordering::Acquire));
+            if tail.wrapping_sub(self.head_cache.get()) > ring.mask {
+                return Err(item);
+            }
+        }
+
+        unsafe {
+            *ring.slots[tail & ring.mask].get() = Some(item);
+        }
+        ring.tail.value.store(tail.wrapping_add(1), Ordering::Release);
+        ring.readable.wake();
+
+        Ok(())
+    }
+
+    // Waits for room if the ring is full.  Only fails once the consumer is gone.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let mut item = item;
+
+        loop {
+            item = match self.try_push(item) {
+                Ok(()) => { return Ok(()); },
+                Err(i) => i
+            };
+
+            if self.ring.closed.load(Ordering::Acquire) {
+                return Err(item);
+            }
+
+            self.wait_writable();
+        }
+    }
+
+    // Same as pushing each item in turn, except that the consumer only hears about them once for
+    // each stretch of free space rather than once per item.  Anything not sent by the time the
+    // consumer goes away is dropped.
+    pub fn push_all(&self, items: Vec<T>) -> Result<(), ()> {
+        let ring = self.ring.as_ref();
+        let mut items = items.into_iter().peekable();
+
+        while items.peek().is_some() {
+            if ring.closed.load(Ordering::Acquire) {
+                return Err(());
+            }
+
+            let tail = ring.tail.value.load(Ordering::Relaxed);
+            self.head_cache.set(ring.head.value.load(Ordering::Acquire));
+            let free = ring.mask + 1 - tail.wrapping_sub(self.head_cache.get());
+
+            if free == 0 {
+                self.wait_writable();
+                continue;
+            }
+
+            let mut written = 0usize;
+            while written < free {
+                match items.next() {
+                    Some(item) => unsafe {
+                        *ring.slots[tail.wrapping_add(written) & ring.mask].get() = Some(item);
+                    },
+                    None => break
+                }
+                written += 1;
+            }
+
+            ring.tail.value.store(tail.wrapping_add(written), Ordering::Release);
+            ring.readable.wake();
+        }
+
+        Ok(())
+    }
+
+    fn wait_writable(&self) {
+        match self.ring.strategy {
+            WaitStrategy::BusyPoll => spin_loop_hint(),
+            WaitStrategy::Park => {
+                self.ring.writable.prepare(Waiter::Thread(thread::current()));
+                if self.is_full() && !self.ring.closed.load(Ordering::Acquire) {
+                    thread::park();
+                }
+                self.ring.writable.cancel();
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        let ring = self.ring.as_ref();
+        let head = ring.head.value.load(Ordering::Acquire);
+        ring.tail.value.load(Ordering::Relaxed).wrapping_sub(head) > ring.mask
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+        self.ring.readable.wake();
+    }
+}
+
+// What came out of the ring while waiting for a deadline
+pub enum Recv<T> {
+    Item(T),
+    TimedOut,
+    // The producer is gone and everything it sent has been read
+    Closed
+}
+
+impl<T: Send> Consumer<T> {
+    pub fn try_pop(&self) -> Option<T> {
+        let ring = self.ring.as_ref();
+        let head = ring.head.value.load(Ordering::Relaxed);
+
+        if head == self.tail_cache.get() {
+            self.tail_cache.set(ring.tail.value.load(Ordering::Acquire));
+            if head == self.tail_cache.get() {
+                return None;
+            }
+        }
+
+        let item = unsafe { (*ring.slots[head & ring.mask].get()).take() };
+        ring.head.value.store(head.wrapping_add(1), Ordering::Release);
+        ring.writable.wake();
+
+        item
+    }
+
+    // Waits for the next item, or until `deadline` if there is one
+    pub fn pop_until(&self, deadline: Option<Instant>) -> Recv<T> {
+        loop {
+            if let Some(item) = self.try_pop() {
+                return Recv::Item(item);
+            }
+
+            // Anything sent before the producer went away has to be read first
+            if self.ring.closed.load(Ordering::Acquire) {
+                return match self.try_pop() {
+                    Some(item) => Recv::Item(item),
+                    None => Recv::Closed
+                };
+            }
+
+            let now = Instant::now();
+            if deadline.map(|d| now >= d).unwrap_or(false) {
+                return Recv::TimedOut;
+            }
+
+            match self.ring.strategy {
+                WaitStrategy::BusyPoll => spin_loop_hint(),
+                WaitStrategy::Park => {
+                    self.ring.readable.prepare(Waiter::Thread(thread::current()));
+                    if self.is_empty() && !self.ring.closed.load(Ordering::Acquire) {
+                        match deadline {
+                            Some(d) => thread::park_timeout(d - now),
+                            None => thread::park()
+                        }
+                    }
+                    self.ring.readable.cancel();
+                }
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        match self.pop_until(None) {
+            Recv::Item(item) => Some(item),
+            _ => None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        let ring = self.ring.as_ref();
+        ring.head.value.load(Ordering::Relaxed) == ring.tail.value.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+        self.ring.writable.wake();
+    }
+}
+
+// Lets a consumer be read from inside a reactor.  Spinning there would starve everything else
+// on the reactor, so this always parks the task regardless of the ring's strategy.
+impl<T: Send> Stream for Consumer<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        if let Some(item) = self.try_pop() {
+            return Ok(Async::Ready(Some(item)));
+        }
+
+        self.ring.readable.prepare(Waiter::Task(park()));
+
+        if let Some(item) = self.try_pop() {
+            self.ring.readable.cancel();
+            Ok(Async::Ready(Some(item)))
+        } else if self.ring.closed.load(Ordering::Acquire) {
+            self.ring.readable.cancel();
+            Ok(Async::Ready(self.try_pop()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use super::*;
+
+    fn expect_item(consumer: &Consumer<usize>) -> usize {
+        match consumer.pop_until(None) {
+            Recv::Item(item) => item,
+            Recv::TimedOut => panic!("timed out without a deadline"),
+            Recv::Closed => panic!("closed before everything was read")
+        }
+    }
+
+    #[test]
+    fn indexes_wrap_past_the_mask() {
+        let (producer, consumer) = channel(4, WaitStrategy::BusyPoll);
+        let mut next = 0;
+
+        // Goes round the four slots several times, leaving a different number queued each time
+        for round in 0..10 {
+            for i in 0..(round % 4) + 1 {
+                producer.try_push(next + i).unwrap();
+            }
+            for _ in 0..(round % 4) + 1 {
+                assert_eq!(consumer.try_pop(), Some(next));
+                next += 1;
+            }
+            assert_eq!(consumer.try_pop(), None);
+        }
+
+        for i in 0..4 {
+            producer.try_push(i).unwrap();
+        }
+        assert_eq!(producer.try_push(4), Err(4));
+        assert_eq!(consumer.try_pop(), Some(0));
+        producer.try_push(4).unwrap();
+        for i in 1..5 {
+            assert_eq!(consumer.try_pop(), Some(i));
+        }
+    }
+
+    #[test]
+    fn push_all_waits_for_room_when_full() {
+        for &strategy in [WaitStrategy::BusyPoll, WaitStrategy::Park].iter() {
+            let (producer, consumer) = channel(2, strategy);
+            producer.try_push(0).unwrap();
+            producer.try_push(1).unwrap();
+
+            let sender = thread::spawn(move || {
+                producer.push_all((2..50).collect()).unwrap();
+            });
+
+            for i in 0..50 {
+                assert_eq!(expect_item(&consumer), i);
+            }
+            sender.join().unwrap();
+            assert!(match consumer.pop_until(None) { Recv::Closed => true, _ => false });
+        }
+    }
+
+    #[test]
+    fn push_all_fails_once_the_consumer_is_gone() {
+        let (producer, consumer) = channel(2, WaitStrategy::Park);
+        producer.try_push(0).unwrap();
+        producer.try_push(1).unwrap();
+
+        let sender = thread::spawn(move || producer.push_all(vec![2, 3, 4]));
+        drop(consumer);
+
+        assert_eq!(sender.join().unwrap(), Err(()));
+    }
+
+    #[test]
+    fn queued_items_are_read_before_closed() {
+        let (producer, consumer) = channel(4, WaitStrategy::Park);
+        for i in 0..3 {
+            producer.push(i).unwrap();
+        }
+        drop(producer);
+
+        for i in 0..3 {
+            assert_eq!(expect_item(&consumer), i);
+        }
+        assert!(match consumer.pop_until(None) { Recv::Closed => true, _ => false });
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn pushing_fails_once_the_consumer_is_gone() {
+        let (producer, consumer) = channel(4, WaitStrategy::Park);
+        drop(consumer);
+
+        assert_eq!(producer.try_push(1), Err(1));
+        assert_eq!(producer.push(2), Err(2));
+    }
+
+    #[test]
+    fn items_cross_threads_in_order() {
+        const COUNT: usize = 200000;
+
+        for &strategy in [WaitStrategy::BusyPoll, WaitStrategy::Park].iter() {
+            // Small enough that both sides keep finding it full or empty
+            let (producer, consumer) = channel(8, strategy);
+
+            let sender = thread::spawn(move || {
+                let mut i = 0;
+                while i < COUNT {
+                    // Mix single pushes with batches of varying length
+                    if i % 3 == 0 {
+                        producer.push(i).unwrap();
+                        i += 1;
+                    } else {
+                        let end = (i + 1 + i % 13).min(COUNT);
+                        producer.push_all((i..end).collect()).unwrap();
+                        i = end;
+                    }
+                }
+            });
+
+            for i in 0..COUNT {
+                assert_eq!(expect_item(&consumer), i);
+            }
+            sender.join().unwrap();
+            assert!(match consumer.pop_until(None) { Recv::Closed => true, _ => false });
+        }
+    }
+}
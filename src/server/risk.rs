@@ -0,0 +1,265 @@
+use libcix::order::trade_types::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+// Pre-trade limits on a single user.  Zero means no limit.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskLimits {
+    pub max_order_quantity: Quantity,
+    // Price times quantity
+    pub max_order_notional: f64,
+    pub max_open_orders: u32,
+    // Notional of everything resting or on its way to an engine, buys and sells added together
+    pub max_symbol_exposure: f64,
+    pub max_total_exposure: f64,
+    // How far an order's price can be from the symbol's last trade, as a fraction of it (0.1 for
+    // 10%).  Nothing is checked until the symbol has traded.
+    pub max_price_deviation: f64
+}
+
+impl RiskLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        for &(name, value) in [("max_order_notional", self.max_order_notional),
+                               ("max_symbol_exposure", self.max_symbol_exposure),
+                               ("max_total_exposure", self.max_total_exposure),
+                               ("max_price_deviation", self.max_price_deviation)].iter() {
+            if !(value >= 0.0) || value.is_infinite() {
+                return Err(format!("{} has to be a finite, non-negative number", name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Which limit an order would have gone over
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RiskReject {
+    OrderQuantity,
+    OrderNotional,
+    OpenOrders,
+    SymbolExposure,
+    TotalExposure,
+    PriceDeviation
+}
+
+impl fmt::Display for RiskReject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            RiskReject::OrderQuantity => "order quantity over limit",
+            RiskReject::OrderNotional => "order notional over limit",
+            RiskReject::OpenOrders => "too many open orders",
+            RiskReject::SymbolExposure => "symbol exposure over limit",
+            RiskReject::TotalExposure => "total exposure over limit",
+            RiskReject::PriceDeviation => "price too far from last trade"
+        })
+    }
+}
+
+// Orders a user has resting or in flight and what they add up to
+#[derive(Clone, Debug, Default)]
+pub struct Exposure {
+    pub open_orders: u32,
+    pub total: f64,
+    pub by_symbol: HashMap<Symbol, f64>
+}
+
+impl Exposure {
+    fn add(&mut self, symbol: Symbol, notional: f64) {
+        self.open_orders += 1;
+        self.total += notional;
+        *self.by_symbol.entry(symbol).or_insert(0.0) += notional;
+    }
+
+    fn reduce(&mut self, symbol: Symbol, notional: f64) {
+        self.total -= notional;
+        if let Some(n) = self.by_symbol.get_mut(&symbol) {
+            *n -= notional;
+        }
+    }
+
+    fn remove(&mut self, symbol: Symbol, notional: f64) {
+        self.reduce(symbol, notional);
+        self.open_orders -= 1;
+
+        // Don't let rounding errors build up
+        if self.open_orders == 0 {
+            self.total = 0.0;
+            self.by_symbol.clear();
+        }
+    }
+}
+
+// Checks orders against their user's limits before they're routed, and keeps track of what each
+// user has in the market from the acks, fills and cancels the engines send back.  Those are
+// followed during replay too, so orders left resting from before a restart still count.  Only
+// used from the reactor thread.
+pub struct RiskManager {
+    defaults: RiskLimits,
+    limits: RefCell<HashMap<UserId, RiskLimits>>,
+    exposure: RefCell<HashMap<UserId, Exposure>>,
+    // Whatever's left of each resting order
+    orders: RefCell<HashMap<OrderId, Order>>,
+    last_trade: RefCell<HashMap<Symbol, Price>>
+}
+
+impl RiskManager {
+    // Users without limits of their own get the defaults
+    pub fn new(defaults: RiskLimits, limits: HashMap<UserId, RiskLimits>) -> Self {
+        RiskManager {
+            defaults: defaults,
+            limits: RefCell::new(limits),
+            exposure: RefCell::new(HashMap::new()),
+            orders: RefCell::new(HashMap::new()),
+            last_trade: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub fn limits(&self, user: UserId) -> RiskLimits {
+        self.limits.borrow().get(&user).cloned().unwrap_or(self.defaults)
+    }
+
+    // Only lasts until the server restarts, which whoever asked for it is told
+    pub fn set_limits(&self, user: UserId, limits: RiskLimits) {
+        self.limits.borrow_mut().insert(user, limits);
+    }
+
+    pub fn exposure(&self, user: UserId) -> Exposure {
+        self.exposure.borrow().get(&user).cloned().unwrap_or_default()
+    }
+
+    // Counts the order against the user's limits until the reservation is dropped, which should
+    // be once the engine has answered.  By then an accepted order is being tracked by its ack.
+    pub fn reserve(manager: &Rc<Self>, user: UserId, symbol: Symbol, price: Price,
+                   quantity: Quantity) -> Result<Reservation, RiskReject> {
+        let limits = manager.limits(user);
+        let notional = price * quantity as f64;
+        debug_assert!(notional.is_finite(), "sessions should turn down prices like {}", price);
+
+        if limits.max_order_quantity > 0 && quantity > limits.max_order_quantity {
+            return Err(RiskReject::OrderQuantity);
+        }
+
+        if limits.max_order_notional > 0.0 && notional > limits.max_order_notional {
+            return Err(RiskReject::OrderNotional);
+        }
+
+        if limits.max_price_deviation > 0.0 {
+            if let Some(&last) = manager.last_trade.borrow().get(&symbol) {
+                if last > 0.0 && (price - last).abs() / last > limits.max_price_deviation {
+                    return Err(RiskReject::PriceDeviation);
+                }
+            }
+        }
+
+        let mut exposures = manager.exposure.borrow_mut();
+        let exposure = exposures.entry(user).or_insert_with(Exposure::default);
+
+        if limits.max_open_orders > 0 && exposure.open_orders >= limits.max_open_orders {
+            return Err(RiskReject::OpenOrders);
+        }
+
+        let symbol_exposure = exposure.by_symbol.get(&symbol).cloned().unwrap_or(0.0);
+        if limits.max_symbol_exposure > 0.0 &&
+                symbol_exposure + notional > limits.max_symbol_exposure {
+            return Err(RiskReject::SymbolExposure);
+        }
+
+        if limits.max_total_exposure > 0.0 &&
+                exposure.total + notional > limits.max_total_exposure {
+            return Err(RiskReject::TotalExposure);
+        }
+
+        exposure.add(symbol, notional);
+
+        Ok(Reservation {
+            manager: manager.clone(),
+            user: user,
+            symbol: symbol,
+            notional: notional
+        })
+    }
+
+    // An engine took an order and this is what was left of it after matching
+    pub fn order_resting(&self, order: &Order) {
+        self.exposure.borrow_mut().entry(order.user).or_insert_with(Exposure::default)
+            .add(order.symbol, order.price * order.quantity as f64);
+        self.orders.borrow_mut().insert(order.id, *order);
+    }
+
+    pub fn order_cancelled(&self, order_id: OrderId) {
+        if let Some(order) = self.orders.borrow_mut().remove(&order_id) {
+            self.release(order.user, order.symbol, order.price * order.quantity as f64);
+        }
+    }
+
+    // An order taking liquidity is matched before it's acked, so only the resting side is known
+    // about here.  The ack says what's left of the other one.
+    pub fn execution(&self, execution: &Execution) {
+        self.last_trade.borrow_mut().insert(execution.symbol, execution.price);
+        self.fill(execution.buy_order, execution.quantity);
+        self.fill(execution.sell_order, execution.quantity);
+    }
+
+    // Delisting cancels everything on the book without acking each order
+    pub fn symbol_delisted(&self, symbol: Symbol) {
+        let cancelled: Vec<OrderId> = self.orders.borrow().values().filter(|o| {
+            o.symbol == symbol
+        }).map(|o| o.id).collect();
+
+        for order_id in cancelled.into_iter() {
+            self.order_cancelled(order_id);
+        }
+
+        self.last_trade.borrow_mut().remove(&symbol);
+    }
+
+    fn fill(&self, order_id: OrderId, quantity: Quantity) {
+        let mut orders = self.orders.borrow_mut();
+        let done = match orders.get_mut(&order_id) {
+            Some(order) => {
+                let filled = quantity.min(order.quantity);
+                order.quantity -= filled;
+
+                let mut exposures = self.exposure.borrow_mut();
+                if let Some(exposure) = exposures.get_mut(&order.user) {
+                    if order.quantity == 0 {
+                        exposure.remove(order.symbol, order.price * filled as f64);
+                    } else {
+                        exposure.reduce(order.symbol, order.price * filled as f64);
+                    }
+                }
+
+                order.quantity == 0
+            },
+            None => false
+        };
+
+        if done {
+            orders.remove(&order_id);
+        }
+    }
+
+    fn release(&self, user: UserId, symbol: Symbol, notional: f64) {
+        if let Some(exposure) = self.exposure.borrow_mut().get_mut(&user) {
+            exposure.remove(symbol, notional);
+        }
+    }
+}
+
+// An order that has passed the risk checks but hasn't been answered by its engine yet
+pub struct Reservation {
+    manager: Rc<RiskManager>,
+    user: UserId,
+    symbol: Symbol,
+    notional: f64
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.manager.release(self.user, self.symbol, self.notional);
+    }
+}
@@ -2,6 +2,8 @@ extern crate bincode;
 extern crate capnp;
 #[macro_use]
 extern crate capnp_rpc;
+extern crate crypto;
+extern crate flate2;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate libcix;
@@ -12,86 +14,127 @@ extern crate serde;
 extern crate serde_derive;
 extern crate time;
 extern crate tokio_core;
+extern crate tokio_signal;
+extern crate toml;
 extern crate uuid;
 
+mod admin;
+mod auth;
+mod config;
 mod engine;
 mod events;
+mod journal;
+mod latency;
+mod ledger;
 mod md;
 mod messages;
+mod replication;
+mod ring;
+mod risk;
 mod session;
+mod transport;
 mod wal;
 
+use auth::{Authenticator, UserDatabase};
+use config::{ServerConfig, SymbolSettings};
 use engine::EngineHandle;
-use futures::{future, Future, Stream};
+use futures::{future, stream, Future, Stream};
 use futures::sink::Sink;
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use libcix::book::{BasicMatcher, ExecutionHandler};
 use libcix::cix_capnp as cp;
+use libcix::clock::WallClock;
+use ledger::Ledger;
 use libcix::order::trade_types;
 use md::MdPublisherHandle;
-use messages::{EngineMessage, MdMessage, SessionMessage};
-use session::{OrderRouter, ServerContext, ServerState};
-use std::cell::Cell;
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::error::Error;
-use std::iter::repeat;
-use std::net::ToSocketAddrs;
-use std::path::Path;
+use messages::{EngineMessage, EngineRequest, MdMessage, RejectReason, RequestId, SessionMessage,
+               SymbolMessage, SymbolStatus, FUNDS_ENGINE};
+use replication::{ReplicationHub, StandbyHandle};
+use risk::RiskManager;
+use session::{Accepted, OrderRouter, ServerContext, ServerState, StandbyState};
+use std::cell::{Cell, RefCell};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::env::{args, current_dir};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
 use tokio_core::reactor;
 use tokio_core::io::Io;
 use tokio_core::net::TcpListener;
-use wal::{Wal, WalDirectoryReader};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use wal::{get_all_files, ReplayLimit, WalPosition};
 
+// Sessions hear about executions from the engines themselves, so this only has to publish them.
+// Everything is held until the engine finishes a batch and then sent to the publisher together.
 #[derive(Clone)]
 struct FeedExecutionHandler {
-    session_tx: mpsc::Sender<SessionMessage>,
-    md_tx:      mpsc::Sender<MdMessage>
+    md_tx:      mpsc::Sender<MdMessage>,
+    pending:    RefCell<Vec<MdMessage>>
 }
 
-impl ExecutionHandler for FeedExecutionHandler {
-    fn ack_order(&self, order_id: trade_types::OrderId,
-                 status: trade_types::ErrorCode) {
-        self.session_tx.clone().send(SessionMessage::NewOrderAck {
-            order_id: order_id,
-            status: status
-        }).wait();
+impl FeedExecutionHandler {
+    fn new(md_tx: mpsc::Sender<MdMessage>) -> Self {
+        FeedExecutionHandler {
+            md_tx: md_tx,
+            pending: RefCell::new(Vec::new())
+        }
     }
+}
 
+impl ExecutionHandler for FeedExecutionHandler {
     fn handle_match(&self, execution: &trade_types::Execution) {
         let md_execution = trade_types::MdExecution::from(execution.clone());
-        let exec_id = execution.id;
-
-        self.session_tx.clone().send(SessionMessage::Execution(*execution)).map_err(|e| {
-                format!("failed to notify client of execution {}", exec_id).to_string()
-            })
-            .join(self.md_tx.clone().send(MdMessage::Execution(md_execution)).map_err(|e| {
-                format!("failed to publish market datafor execution {}", exec_id).to_string()
-            }))
-            .wait();
+        self.pending.borrow_mut().push(MdMessage::Execution(md_execution));
     }
 
     fn handle_market_data_l1(&self, md: trade_types::L1Md) {
-        self.md_tx.clone().send(MdMessage::L1Message(md)).wait();
+        self.pending.borrow_mut().push(MdMessage::L1Message(md));
     }
 
     fn handle_market_data_l2(&self, md: trade_types::L2Md) {
-        self.md_tx.clone().send(MdMessage::L2Message(md)).wait();
+        self.pending.borrow_mut().push(MdMessage::L2Message(md));
+    }
+
+    fn flush(&self) {
+        let pending = mem::replace(&mut *self.pending.borrow_mut(), Vec::new());
+        let count = pending.len();
+        if count == 0 {
+            return;
+        }
+
+        let messages = stream::iter(pending.into_iter().map(Ok::<_, mpsc::SendError<_>>));
+        if let Err(_) = self.md_tx.clone().send_all(messages).wait() {
+            println!("failed to publish {} market data messages", count);
+        }
     }
 }
 
 type SymbolId = usize;
 
+struct SymbolInfo {
+    symbol: trade_types::Symbol,
+    engine: u32,
+    status: SymbolStatus
+}
+
 struct SymbolLookup {
-    symbols: Vec<trade_types::Symbol>,
+    // Every symbol ever listed, including delisted ones since their IDs are never reused
+    symbols: BTreeMap<SymbolId, SymbolInfo>,
+    // Only symbols that are currently listed
     lookup: HashMap<trade_types::Symbol, SymbolId>
 }
 
 impl SymbolLookup {
-    pub fn new(symbols: &Vec<trade_types::Symbol>) -> Result<Self, String> {
+    // Symbols given on startup are numbered by position.  Explicit engine assignments come first
+    // and everything else is spread by a hash of the symbol name.  This has to come out the same
+    // every time the server starts since each engine only replays its own log.
+    pub fn new(symbols: &Vec<trade_types::Symbol>, n_engine: u32,
+               explicit: &HashMap<trade_types::Symbol, u32>) -> Result<Self, String> {
         let mut res = SymbolLookup {
-            symbols: symbols.to_vec(),
+            symbols: BTreeMap::new(),
             lookup: HashMap::new()
         };
 
@@ -99,17 +142,32 @@ impl SymbolLookup {
             if let Some(_) = res.lookup.insert(symbol.clone(), i) {
                 return Err(format!("duplicate symbol {}", symbol));
             }
+
+            res.symbols.insert(i, SymbolInfo {
+                symbol: *symbol,
+                engine: explicit.get(symbol).cloned().unwrap_or_else(|| {
+                    default_engine(symbol, n_engine)
+                }),
+                status: SymbolStatus::Active
+            });
+        }
+
+        for (symbol, engine) in explicit.iter() {
+            try!(res.get_symbol_id(symbol).map_err(|_| {
+                format!("engine assignment for unknown symbol {}", symbol)
+            }));
+
+            if *engine >= n_engine {
+                return Err(format!("symbol {} assigned to engine {} but there are only {}",
+                                   symbol, engine, n_engine));
+            }
         }
 
         Ok(res)
     }
 
     pub fn get_symbol(&self, id: SymbolId) -> Result<trade_types::Symbol, ()> {
-        if id >= self.symbols.len() {
-            Err(())
-        } else {
-            Ok(self.symbols[id])
-        }
+        self.symbols.get(&id).map(|info| info.symbol).ok_or(())
     }
 
     pub fn get_symbol_id(&self, symbol: &trade_types::Symbol) -> Result<SymbolId, ()> {
@@ -119,86 +177,193 @@ impl SymbolLookup {
         }
     }
 
+    pub fn get_engine(&self, id: SymbolId) -> Result<u32, ()> {
+        self.symbols.get(&id).map(|info| info.engine).ok_or(())
+    }
+
+    pub fn get_status(&self, id: SymbolId) -> Result<SymbolStatus, ()> {
+        self.symbols.get(&id).map(|info| info.status).ok_or(())
+    }
+
+    // Lowest ID that has never been used
+    pub fn next_id(&self) -> SymbolId {
+        self.symbols.keys().next_back().map(|id| id + 1).unwrap_or(0)
+    }
+
     pub fn len(&self) -> usize {
-        self.symbols.len()
+        self.lookup.len()
+    }
+
+    // (symbol, id) pairs for the startup symbols assigned to one engine
+    pub fn engine_symbols(&self, engine_id: u32) -> Vec<(trade_types::Symbol, u32)> {
+        self.symbols.iter().filter(|&(_, info)| info.engine == engine_id).map(|(id, info)| {
+            (info.symbol, *id as u32)
+        }).collect()
+    }
+
+    pub fn update(&mut self, engine_id: u32, symbol: trade_types::Symbol, id: SymbolId,
+                  status: SymbolStatus) {
+        if let SymbolStatus::Delisted = status {
+            if self.lookup.get(&symbol) == Some(&id) {
+                self.lookup.remove(&symbol);
+            }
+        } else {
+            self.lookup.insert(symbol, id);
+        }
+
+        self.symbols.insert(id, SymbolInfo {
+            symbol: symbol,
+            engine: engine_id,
+            status: status
+        });
     }
 }
 
-// XXX: For now just use a single engine for all symbols
-// Later on we can either shard by symbol or use a lookup or whatever
+fn default_engine(symbol: &trade_types::Symbol, n_engine: u32) -> u32 {
+    (symbol_hash(symbol) % n_engine as u64) as u32
+}
+
+// FNV-1a, which unlike the std hashers is guaranteed not to change between releases
+fn symbol_hash(symbol: &trade_types::Symbol) -> u64 {
+    symbol.as_str().trim_right_matches('\0').bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Sends each order to the engine that trades its symbol
 #[derive(Clone)]
-struct SingleRouter {
-    symbols: Rc<SymbolLookup>,
-    tx: mpsc::Sender<EngineMessage>,
-    seq_list: Vec<Cell<u64>>
+struct ShardedRouter {
+    // Kept up to date by the engines as they list and delist symbols
+    symbols: Rc<RefCell<SymbolLookup>>,
+    // IDs handed out to symbols that their engines haven't confirmed yet
+    reserved_id: Rc<Cell<SymbolId>>,
+    // Shared rather than cloned since ring senders can't be.  Being stuck on the reactor thread
+    // also keeps each ring down to a single producer.
+    txs: Rc<Vec<transport::Sender<EngineRequest>>>
 }
 
-impl SingleRouter {
-    pub fn new(symbols: Rc<SymbolLookup>, tx: mpsc::Sender<EngineMessage>) -> Self {
-        let len = symbols.len();
-        SingleRouter {
-            symbols: symbols,
-            tx: tx,
-            seq_list: repeat(Cell::new(0u64)).take(len).collect()
+impl ShardedRouter {
+    pub fn new(symbols: SymbolLookup, txs: Vec<transport::Sender<EngineRequest>>) -> Self {
+        ShardedRouter {
+            reserved_id: Rc::new(Cell::new(symbols.next_id())),
+            symbols: Rc::new(RefCell::new(symbols)),
+            txs: Rc::new(txs)
+        }
+    }
+
+    fn send(tx: &transport::Sender<EngineRequest>, request: EngineRequest) -> Result<(), String> {
+        tx.send(request)
+    }
+
+    fn engine_for(&self, symbol_id: SymbolId)
+            -> Result<&transport::Sender<EngineRequest>, String> {
+        self.symbols.borrow().get_engine(symbol_id).ok().and_then(|engine| {
+            self.txs.get(engine as usize)
+        }).ok_or(format!("no engine for symbol id {}", symbol_id))
+    }
+
+    fn broadcast(&self, request: EngineRequest) -> Result<(), String> {
+        for tx in self.txs.iter() {
+            try!(Self::send(tx, request));
         }
+
+        Ok(())
     }
 }
 
-impl OrderRouter for SingleRouter {
+impl OrderRouter for ShardedRouter {
     fn route_order(&self, msg: EngineMessage) -> Result<(), String> {
-        self.broadcast_message(msg)
+        let symbol_id = match msg {
+            // Order IDs are assigned by the engine, so go by the symbol for new orders
+            EngineMessage::NewOrder(ref new_order) => {
+                try!(self.symbols.borrow().get_symbol_id(&new_order.symbol).map_err(|_| {
+                    format!("invalid symbol {}", new_order.symbol)
+                }))
+            },
+            EngineMessage::CancelOrder(ref cancel) => cancel.order_id.symbol_id() as SymbolId,
+            EngineMessage::ListSymbol(ref list) => {
+                let engine = default_engine(&list.symbol, self.n_engine());
+                return Self::send(&self.txs[engine as usize], EngineRequest::Live(msg));
+            },
+            EngineMessage::SuspendSymbol(ref change) | EngineMessage::ResumeSymbol(ref change) |
+            EngineMessage::DelistSymbol(ref change) => change.symbol_id as SymbolId,
+            EngineMessage::Funds(_) => {
+                return Self::send(&self.txs[FUNDS_ENGINE as usize], EngineRequest::Live(msg));
+            },
+            _ => {
+                return self.broadcast_message(msg);
+            }
+        };
+
+        Self::send(try!(self.engine_for(symbol_id)), EngineRequest::Live(msg))
     }
 
     fn broadcast_message(&self, msg: EngineMessage) -> Result<(), String> {
-        self.tx.clone().send(msg).wait().map(|_| ()).map_err(|e| e.description().to_string())
+        self.broadcast(EngineRequest::Live(msg))
     }
 
-    fn create_order_id(&self, symbol: &trade_types::Symbol, side: &trade_types::OrderSide)
-            -> Result<trade_types::OrderId, String> {
-        let sym_id = try!(self.symbols.get_symbol_id(symbol).map_err(|_| {
-            format!("invalid symbol {}", symbol)
-        }));
-        let ref seq = self.seq_list[sym_id];
-        let order_id = try!(trade_types::OrderId::new(sym_id as u32, *side, seq.get()));
-
-        // This is only accessed from the main thread so non-atomic updates like this are fine
-        seq.set(seq.get() + 1);
-        Ok(order_id)
+    fn replay_message(&self, engine_id: u32, position: WalPosition, msg: EngineMessage)
+            -> Result<(), String> {
+        let tx = try!(self.txs.get(engine_id as usize).ok_or(format!("no engine {}", engine_id)));
+        Self::send(tx, EngineRequest::Replicated(position, msg))
     }
 
-    fn replay_message(&self, msg: EngineMessage) -> Result<(), String> {
-        if let EngineMessage::NewOrder(new_order) = msg {
-            //println!("replaying order {}", new_order.order_id);
+    fn dump_books(&self) -> Result<(), String> {
+        self.broadcast(EngineRequest::DumpBooks)
+    }
 
-            let order_id = new_order.order_id.clone();
-            let sym_id = try!(self.symbols.get_symbol_id(&new_order.symbol).map_err(|_| {
-                format!("invalid symbol {}", new_order.symbol)
-            }));
+    fn shutdown_engines(&self, snapshot: bool) -> Result<(), String> {
+        self.broadcast(EngineRequest::Shutdown { snapshot: snapshot })
+    }
 
-            let order_seq = order_id.sequence();
-            let ref sym_seq = self.seq_list[sym_id];
+    fn n_engine(&self) -> u32 {
+        self.txs.len() as u32
+    }
 
-            if order_seq >= sym_seq.get() {
-                sym_seq.set(order_seq + 1);
+    fn list_symbol(&self, symbol: trade_types::Symbol) -> Result<u32, String> {
+        let symbol_id = {
+            let symbols = self.symbols.borrow();
+            if symbols.get_symbol_id(&symbol).is_ok() {
+                return Err(format!("symbol {} is already listed", symbol));
             }
-        }
-        self.route_order(msg)
+
+            max(symbols.next_id(), self.reserved_id.get())
+        };
+        self.reserved_id.set(symbol_id + 1);
+
+        try!(self.route_order(EngineMessage::ListSymbol(SymbolMessage {
+            symbol: symbol,
+            symbol_id: symbol_id as u32
+        })));
+
+        Ok(symbol_id as u32)
     }
 
-    fn n_engine(&self) -> u32 {
-        1u32
+    fn symbol_status(&self, symbol: &trade_types::Symbol) -> Option<(u32, SymbolStatus)> {
+        let symbols = self.symbols.borrow();
+        symbols.get_symbol_id(symbol).ok().and_then(|id| {
+            symbols.get_status(id).ok().map(|status| (id as u32, status))
+        })
+    }
+
+    fn update_symbol(&self, engine_id: u32, symbol: trade_types::Symbol, symbol_id: u32,
+                     status: SymbolStatus) {
+        self.symbols.borrow_mut().update(engine_id, symbol, symbol_id as SymbolId, status);
     }
 }
 
 struct ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
-    rx: mpsc::Receiver<SessionMessage>,
+    // Each engine has its own, so messages from one engine stay in order but messages from
+    // different engines can interleave
+    rxs: Vec<transport::Receiver<SessionMessage>>,
     context: Rc<ServerContext<R>>
 }
 
 impl<R> ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
-    fn new(rx: mpsc::Receiver<SessionMessage>, context: Rc<ServerContext<R>>) -> Self {
+    fn new(rxs: Vec<transport::Receiver<SessionMessage>>, context: Rc<ServerContext<R>>)
+            -> Self {
         ExecutionPublisher {
-            rx: rx,
+            rxs: rxs,
             context: context
         }
     }
@@ -229,16 +394,26 @@ impl<R> ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
 
     fn handle_executions(self) {
         let context = self.context.clone();
-        let exec_feed = self.rx.for_each(move |message| {
-            let running = if let ServerState::Running = context.state.get() {
-                true
-            } else {
-                false
+        let empty: Box<Stream<Item=SessionMessage, Error=()>> = Box::new(stream::empty());
+        let rx = self.rxs.into_iter().fold(empty, |all, rx| {
+            Box::new(all.select(rx)) as Box<Stream<Item=SessionMessage, Error=()>>
+        });
+        let exec_feed = rx.for_each(move |message| {
+            // Requests still in flight when a shutdown starts are answered as it drains them.  Only
+            // replay has nobody waiting on what comes back.
+            let live = match context.state.get() {
+                ServerState::Running | ServerState::ShuttingDown => true,
+                ServerState::Loading | ServerState::Standby | ServerState::ReadOnly => false
             };
 
             match message {
                 SessionMessage::Execution(execution) => {
-                    if running {
+                    // Risk tracking and the ledger follow replay as well, so that they've caught
+                    // up with everything before sessions are let in
+                    context.risk.execution(&execution);
+                    context.ledger.execution(&execution);
+
+                    if live {
                         //println!("EXECUTION {}", execution);
                         Self::handle_execution_side(context.as_ref(), &execution,
                                                     trade_types::OrderSide::Buy);
@@ -246,15 +421,40 @@ impl<R> ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
                                                     trade_types::OrderSide::Sell);
                     }
                 },
-                SessionMessage::NewOrderAck{order_id, status} => {
-                    if running {
+                SessionMessage::NewOrderAck{request, order_id, symbol, status, resting, times} => {
+                    if let Some(order) = resting {
+                        context.risk.order_resting(&order);
+                        context.ledger.order_resting(&order);
+                    }
+
+                    if live {
                         //println!("ACK {}: {:?}", order_id, status);
-                        let order_map = context.pending_orders.borrow_mut();
-                        if let Some(waiter) = order_map.get(&order_id) {
-                            waiter.ack(status);
-                        } else {
-                            println!("received ack for unknown order {}", order_id);
-                        }
+                        Self::complete_request(context.as_ref(), request, match status {
+                            trade_types::ErrorCode::Success => Ok(Accepted {
+                                order_id: order_id,
+                                symbol: symbol,
+                                times: times
+                            }),
+                            // The engine assigns IDs so a duplicate means something is broken
+                            _ => Err(RejectReason::Internal)
+                        });
+                    }
+                },
+                SessionMessage::CancelAck{request, order_id, symbol, times} => {
+                    context.risk.order_cancelled(order_id);
+                    context.ledger.order_cancelled(order_id);
+
+                    if live {
+                        Self::complete_request(context.as_ref(), request, Ok(Accepted {
+                            order_id: order_id,
+                            symbol: symbol,
+                            times: times
+                        }));
+                    }
+                },
+                SessionMessage::Reject{request, reason} => {
+                    if live {
+                        Self::complete_request(context.as_ref(), request, Err(reason));
                     }
                 },
                 SessionMessage::SerializationResponse(gen) => {
@@ -268,6 +468,17 @@ impl<R> ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
                         println!("received response for unknown open order request {}/{}",
                                  orders.seq.user, orders.seq.seq);
                     }
+                },
+                SessionMessage::SymbolUpdate{engine_id, symbol, symbol_id, status} => {
+                    if status == SymbolStatus::Delisted {
+                        context.risk.symbol_delisted(symbol);
+                        context.ledger.symbol_delisted(symbol);
+                    }
+
+                    context.router.update_symbol(engine_id, symbol, symbol_id, status);
+                },
+                SessionMessage::FundsUpdate(msg) => {
+                    context.ledger.funds(&msg);
                 }
             };
 
@@ -277,6 +488,16 @@ impl<R> ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
         self.context.handle.spawn(exec_feed);
     }
 
+    fn complete_request(context: &ServerContext<R>, request: RequestId,
+                        result: Result<Accepted, RejectReason>) {
+        let request_map = context.pending_requests.borrow();
+        if let Some(waiter) = request_map.get(&request) {
+            waiter.ack(result);
+        } else {
+            println!("received response for unknown request {}", request);
+        }
+    }
+
     fn handle_execution_side(context: &ServerContext<R>,
                              execution: &trade_types::Execution,
                              side: trade_types::OrderSide) -> Result<(), ()> {
@@ -323,64 +544,288 @@ impl<R> ExecutionPublisher<R> where R: 'static + Clone + OrderRouter {
     }
 }
 
-fn init_wal<P: AsRef<Path>, R: OrderRouter>(dir: P, router: &R) -> Wal {
-    let reader = WalDirectoryReader::new(dir.as_ref()).unwrap();
-    let mut replay_count = 0usize;
+// Follow the primary whose replication listener is at `primary` until promoted
+fn start_standby<R>(context: Rc<ServerContext<R>>, primary: String,
+                    engine_dirs: Vec<(u32, PathBuf)>, buffer_size: usize)
+        where R: 'static + Clone + OrderRouter {
+    let (repl_tx, repl_rx) = mpsc::channel(buffer_size);
+    let (drained_tx, drained_rx) = oneshot::channel();
+    let standby = StandbyHandle::start(primary, engine_dirs, repl_tx).unwrap();
+
+    let apply_context = context.clone();
+    context.handle.spawn(repl_rx.for_each(move |entry| {
+        if let Err(e) = apply_context.router.replay_message(entry.engine_id, entry.position,
+                                                            entry.msg) {
+            println!("failed to apply replicated entry {}: {}", entry.position, e);
+        }
+
+        future::ok(())
+    }).then(move |_| {
+        drained_tx.complete(());
+        Ok::<(), ()>(())
+    }));
+
+    *context.standby.borrow_mut() = Some(StandbyState {
+        handle: standby,
+        drained: drained_rx
+    });
+}
+
+// Logs written before each engine had a directory of their own went straight into the top-level
+// directory.  Starting without them would silently come up with empty books, so refuse to.
+fn check_legacy_wal(wal_dir: &Path) -> Result<(), String> {
+    if !wal_dir.is_dir() {
+        return Ok(());
+    }
+
+    let legacy = try!(get_all_files(wal_dir));
+    if legacy.is_empty() {
+        return Ok(());
+    }
+
+    Err(format!("{} has {} wal files from before engines had their own directories; move them \
+                 into {} and start with a single engine to replay them", wal_dir.display(),
+                legacy.len(), wal_dir.join("engine_0").display()))
+}
+
+struct ServerArgs {
+    // Replication address of the primary to follow, if we're a standby
+    standby: Option<String>,
+    config: ServerConfig,
+    replay_limit: ReplayLimit,
+    // Print the books once replay finishes and exit instead of serving them
+    dump: bool
+}
+
+// usage: cixsrv [--config <file>] [--standby <primary replication address>]
+//               [--listen <address>] [--replication-listen <address>]
+//               [--wal-dir <dir>] [--wal-segment-size <bytes>] [--wal-archive <none|gzip|zlib>]
+//               [--symbols <symbol>,...] [--engines <count>] [--assign <symbol>=<engine>]...
+//               [--buffer-size <count>] [--transport <channel|ring-poll|ring-park>]
+//               [--batch-size <count>] [--md-interval-ms <millis>]
+//               [--latency-dump-secs <seconds>] [--shutdown-snapshot] [--users <file>]
+//               [--replay-until <pos:INDEX:OFFSET|count:N|time:SECONDS>] [--dump]
+// Flags override whatever the config file says, wherever they appear.  Replaying only part of the
+// log makes the server read-only.
+fn parse_args() -> Result<ServerArgs, String> {
+    let cli_args: Vec<String> = args().skip(1).collect();
+
+    let config = match cli_args.iter().position(|a| a == "--config") {
+        Some(i) => {
+            let path = try!(cli_args.get(i + 1).ok_or("--config requires a value".to_string()));
+            try!(ServerConfig::load(Path::new(path)))
+        },
+        None => ServerConfig::default()
+    };
 
-    // Replay all messages from existing log files to catch books up
-    for entry in reader {
-        match entry {
-            Ok(msg) => {
-                router.replay_message(msg).unwrap();
-                replay_count += 1;
+    let mut parsed = ServerArgs {
+        standby: None,
+        config: config,
+        replay_limit: ReplayLimit::Everything,
+        dump: false
+    };
+    // Applied once we know the final symbol list
+    let mut assignments = Vec::new();
+
+    let mut cli_args = cli_args.into_iter();
+
+    while let Some(flag) = cli_args.next() {
+        let mut value = || cli_args.next().ok_or(format!("{} requires a value", flag));
+
+        match flag.as_str() {
+            "--config" => { try!(value()); },
+            "--standby" => { parsed.standby = Some(try!(value())); },
+            "--listen" => { parsed.config.listen.sessions = try!(value()); },
+            "--replication-listen" => { parsed.config.listen.replication = try!(value()); },
+            "--wal-dir" => { parsed.config.wal.dir = PathBuf::from(try!(value())); },
+            "--wal-segment-size" => {
+                parsed.config.wal.segment_size = try!(usize::from_str(try!(value()).as_str())
+                    .map_err(|_| "invalid segment size".to_string()));
             },
-            Err(e) => {
-                panic!("failed to replay messages: {}", e);
-            }
+            "--wal-archive" => { parsed.config.wal.archive = try!(value()); },
+            "--symbols" => {
+                parsed.config.symbols = try!(value()).split(',').map(SymbolSettings::new)
+                    .collect();
+            },
+            "--engines" => {
+                parsed.config.engines.count = try!(u32::from_str(try!(value()).as_str())
+                    .map_err(|_| "invalid engine count".to_string()));
+            },
+            "--assign" => {
+                let spec = try!(value());
+                let mut parts = spec.splitn(2, '=');
+                let symbol = parts.next().unwrap_or("").to_string();
+                let engine = try!(parts.next().and_then(|e| u32::from_str(e).ok()).ok_or(
+                    format!("invalid engine in assignment {}", spec)));
+
+                assignments.push((symbol, engine));
+            },
+            "--transport" => { parsed.config.engines.transport = try!(value()); },
+            "--batch-size" => {
+                parsed.config.engines.batch_size = try!(usize::from_str(try!(value()).as_str())
+                    .map_err(|_| "invalid batch size".to_string()));
+            },
+            "--buffer-size" => {
+                parsed.config.engines.buffer_size = try!(usize::from_str(try!(value()).as_str())
+                    .map_err(|_| "invalid buffer size".to_string()));
+            },
+            "--latency-dump-secs" => {
+                let secs = try!(value());
+                parsed.config.latency.dump_interval_secs = try!(u64::from_str(secs.as_str())
+                    .map_err(|_| "invalid latency dump interval".to_string()));
+            },
+            "--users" => { parsed.config.auth.users = Some(PathBuf::from(try!(value()))); },
+            "--shutdown-snapshot" => { parsed.config.shutdown.snapshot = true; },
+            "--md-interval-ms" => {
+                parsed.config.market_data.interval_ms = try!(u64::from_str(try!(value()).as_str())
+                    .map_err(|_| "invalid market data interval".to_string()));
+            },
+            "--replay-until" => {
+                parsed.replay_limit = try!(ReplayLimit::parse(try!(value()).as_str()));
+            },
+            "--dump" => { parsed.dump = true; },
+            _ => { return Err(format!("unrecognized argument {}", flag)); }
         }
     }
 
-    println!("replayed {} events", replay_count);
+    for (symbol, engine) in assignments.into_iter() {
+        let settings = try!(parsed.config.symbols.iter_mut().find(|s| s.name == symbol).ok_or(
+            format!("engine assignment for unknown symbol {}", symbol)));
+        settings.engine = Some(engine);
+    }
+
+    try!(parsed.config.validate().map_err(|e| format!("invalid configuration:\n{}", e)));
 
-    Wal::new(dir, (10 * 1024 * 1024) as usize).unwrap()
+    if parsed.standby.is_some() && parsed.replay_limit.is_bounded() {
+        return Err("a standby has to replay its whole log".to_string());
+    }
+
+    Ok(parsed)
 }
 
 fn main() {
     let mut core = reactor::Core::new().unwrap();
     let handle = core.handle();
 
-    let symbols = vec!["AAPL", "FB", "GOOG"].into_iter().map(|x| {
-        trade_types::Symbol::from_str(x).unwrap()
-    }).collect();
+    let ServerArgs { standby: primary, config, replay_limit, dump } = parse_args()
+        .unwrap_or_else(|e| {
+            panic!("{}", e)
+        });
+    let read_only = replay_limit.is_bounded();
+
+    let buffer_size = config.engines.buffer_size;
+    let engine_config = config.engine_config(replay_limit);
     let matcher = BasicMatcher{};
     let md_publisher = MdPublisherHandle::new();
-    let (exec_tx, exec_rx) = mpsc::channel(1024 as usize);
-    let handler = FeedExecutionHandler{
-        session_tx: exec_tx.clone(),
-        md_tx: md_publisher.tx
+    let handler = FeedExecutionHandler::new(md_publisher.tx);
+    let symbol_lookup = SymbolLookup::new(&config.symbols(), config.engines.count,
+                                          &config.engine_assignments())
+        .unwrap_or_else(|e| {
+            panic!("invalid symbols: {}", e)
+        });
+
+    // Each engine replays and appends to its own subdirectory
+    let wal_dir = config.wal.dir.as_path();
+    check_legacy_wal(wal_dir).unwrap_or_else(|e| {
+        panic!("{}", e)
+    });
+    let engine_dirs: Vec<(u32, PathBuf)> = (0..config.engines.count).map(|i| {
+        (i, wal_dir.join(format!("engine_{}", i)))
+    }).collect();
+
+    // Every server, standby or not, serves its log to anyone who wants to follow it.  Read-only
+    // servers don't write anything so there's nothing to follow.
+    let hub = if read_only {
+        None
+    } else {
+        let hub = ReplicationHub::new(engine_dirs.clone()).unwrap_or_else(|e| {
+            panic!("failed to read what the engines have logged: {}", e)
+        });
+        if let Err(e) = ReplicationHub::listen(hub.clone(), config.listen.replication.as_str()) {
+            println!("not serving replication: {}", e);
+        }
+        Some(hub)
     };
-    let engine = EngineHandle::new(&symbols, &matcher, &handler, &exec_tx).unwrap();
-    let sym_context = Rc::new(SymbolLookup::new(&symbols).unwrap());
-    let router = SingleRouter::new(sym_context, engine.tx.clone());
 
-    let wal_dir = Path::new("/home/brendon/wal");
-    let wal = init_wal(wal_dir, &router);
+    println!("engines talking over {}", engine_config.transport);
+    let mut exec_rxs = Vec::new();
+    let engines: Vec<EngineHandle> = engine_dirs.iter().map(|&(engine_id, ref dir)| {
+        let engine_symbols = symbol_lookup.engine_symbols(engine_id);
+        println!("engine {} trading {:?}", engine_id,
+                 engine_symbols.iter().map(|&(s, _)| s.to_string()).collect::<Vec<String>>());
+
+        let (exec_tx, exec_rx) = transport::channel(engine_config.transport, buffer_size);
+        exec_rxs.push(exec_rx);
+
+        EngineHandle::new(engine_id, &engine_symbols, &matcher, &handler, &WallClock, exec_tx,
+                          dir.as_path(), &engine_config, hub.clone()).unwrap()
+    }).collect();
+    let engine_stats = engine_dirs.iter().zip(engines.iter()).map(|(&(engine_id, _), e)| {
+        (engine_id, e.stats.clone())
+    }).collect();
+    let (engine_txs, engine_threads): (Vec<_>, Vec<_>) = engines.into_iter().map(|e| {
+        (e.tx, e.thread)
+    }).unzip();
+    // Symbols listed at runtime are filled in by the engines as they replay
+    let router = ShardedRouter::new(symbol_lookup, engine_txs);
+
+    let users = match config.auth.users {
+        Some(ref path) => {
+            let users = UserDatabase::load(path.as_path()).unwrap_or_else(|e| {
+                panic!("invalid user database: {}", e)
+            });
+            println!("loaded {} users from {}", users.len(), path.display());
+            users
+        },
+        None => {
+            println!("no user database given, so nobody can open a trading session");
+            UserDatabase::empty()
+        }
+    };
 
-    let context = Rc::new(ServerContext::new(handle.clone(), router, wal));
-    let publisher = ExecutionPublisher::new(exec_rx, context.clone());
+    let risk = RiskManager::new(config.risk, users.risk_limits());
+    let ledger = Ledger::new(config.ledger.statement_dir.clone(), config.ledger.require_funds);
+    let context = Rc::new(ServerContext::new(handle.clone(), router, engine_stats,
+                                             Authenticator::new(users), risk, ledger));
+    let publisher = ExecutionPublisher::new(exec_rxs, context.clone());
     publisher.handle_executions();
 
-    let addr = "localhost:2468".to_socket_addrs().unwrap().next()
-        .expect("could not parse address");
-    let socket = TcpListener::bind(&addr, &handle).unwrap();
+    if config.latency.dump_interval_secs > 0 {
+        let dump_context = context.clone();
+        let interval = Duration::from_secs(config.latency.dump_interval_secs);
+        handle.spawn(reactor::Interval::new(interval, &handle).unwrap().for_each(move |_| {
+            dump_context.latency.dump();
+            Ok(())
+        }).map_err(|e| {
+            println!("latency dump timer failed: {}", e);
+        }));
+    }
 
     // Don't start listening for connections until replay is complete
     // This future has to be created lazily so that there is an active task to register when we
     // call serialization_point
     let replay_sync = future::lazy(|| ServerContext::serialization_point(context.clone()));
 
+    if dump {
+        let dump_context = context.clone();
+        let dump = replay_sync.and_then(move |_| {
+            dump_context.router.dump_books().unwrap();
+            // Wait for the engines to finish printing before we exit
+            ServerContext::serialization_point(dump_context.clone())
+        });
+
+        core.run(dump).unwrap();
+        return;
+    }
+
+    let socket = TcpListener::bind(&config.session_addr(), &handle).unwrap();
+
     let listen_context = context.clone();
     let listen = socket.incoming().for_each(move |(s, _)| {
+        if let ServerState::ShuttingDown = listen_context.state.get() {
+            return Ok(());
+        }
+
         let (reader, writer) = s.split();
         let network = capnp_rpc::twoparty::VatNetwork::new(reader, writer,
             capnp_rpc::rpc_twoparty_capnp::Side::Server, Default::default());
@@ -393,11 +838,61 @@ fn main() {
         Ok(())
     }).map_err(|_| ());
 
-    let done = replay_sync.and_then(|_| {
+    // Either signal shuts down the same way the admin interface does
+    let signal_context = context.clone();
+    let snapshot = config.shutdown.snapshot;
+    let signals = Signal::new(SIGTERM, &handle).join(Signal::new(SIGINT, &handle))
+        .and_then(|(term, int)| term.select(int).into_future().map_err(|(e, _)| e))
+        .map_err(|e| {
+            println!("not handling signals: {}", e);
+        }).and_then(move |(signal, _)| {
+            println!("received signal {}", signal.unwrap_or(0));
+            ServerContext::shutdown(signal_context, snapshot)
+        });
+    handle.spawn(signals);
+
+    let (stopped_tx, stopped_rx) = oneshot::channel();
+    *context.stopped.borrow_mut() = Some(stopped_tx);
+
+    let standby_context = context.clone();
+    let done = replay_sync.and_then(move |_| {
         println!("order replay complete");
-        context.state.set(ServerState::Running);
+
+        // Told to stop before replay was even finished
+        if let ServerState::ShuttingDown = standby_context.state.get() {
+            return future::ok(());
+        }
+
+        match primary {
+            Some(p) => {
+                println!("starting as standby of {}", p);
+                standby_context.state.set(ServerState::Standby);
+                start_standby(standby_context.clone(), p, engine_dirs, buffer_size);
+            },
+            None if read_only => {
+                println!("serving books read-only as of {:?}", replay_limit);
+                standby_context.state.set(ServerState::ReadOnly);
+            },
+            None => {
+                standby_context.state.set(ServerState::Running);
+            }
+        }
         future::ok(())
     }).and_then(|_| listen);
 
-    core.run(done).unwrap();
+    core.run(done.select(stopped_rx.map_err(|_| ())).map(|_| ()).map_err(|_| ())).unwrap();
+
+    // Dropping the reactor drops the engines' response channels too, so nothing they still send
+    // can block them from stopping
+    drop(core);
+
+    for (engine_id, thread) in engine_threads.into_iter().enumerate() {
+        match thread.join() {
+            Ok(Ok(())) => println!("engine {} stopped", engine_id),
+            Ok(Err(e)) => println!("engine {} failed: {}", engine_id, e),
+            Err(_) => println!("engine {} panicked", engine_id)
+        }
+    }
+
+    println!("shutdown complete");
 }
@@ -1,29 +1,48 @@
 use capnp;
+use admin::AdminSession;
+use auth::{AuthFailure, Authenticator, Credential, Permissions};
 use capnp::capability::Promise;
 use engine::*;
 use events::*;
+use latency::{LatencyStats, RequestKind};
+use ledger::{FundsReject, Ledger};
 use messages::*;
 use futures::{future, Future, Stream};
 use futures::sink::Sink;
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use libcix::cix_capnp as cp;
 use cp::trading_session::*;
 use libcix::order::trade_types::*;
+use replication::StandbyHandle;
+use risk::{RiskManager, RiskReject};
 use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio_core::reactor;
 use uuid::Uuid;
-use wal::Wal;
+use wal::WalPosition;
 
 type SubscripionMap = HashMap<UserId, ExecutionSubscription>;
 type SymbolMap = HashMap<Symbol, u32>;
-type OrderWait = WaitEvent<ErrorCode>;
+type RequestWait = WaitEvent<Result<Accepted, RejectReason>>;
 type SyncWait = WaitEvent<()>;
-pub type OrderMap = HashMap<OrderId, OrderWait>;
+pub type RequestMap = HashMap<RequestId, RequestWait>;
 pub type SyncMap = HashMap<u32, SyncWaitRecord>;
 pub type OpenOrderMap = HashMap<OpenOrdersSequence, RefCell<OpenOrdersContext>>;
 
+// How long a shutdown waits for clients to acknowledge being told about it
+const NOTIFY_TIMEOUT_SECS: u64 = 5;
+
+// What an engine says about a new order or cancel it went through with
+#[derive(Clone, Copy, Debug)]
+pub struct Accepted {
+    pub order_id: OrderId,
+    pub symbol: Symbol,
+    pub times: EngineTimes
+}
+
 pub struct SyncWaitRecord {
     pub event: SyncWait,
     pub pending_count: Cell<u32>
@@ -31,16 +50,40 @@ pub struct SyncWaitRecord {
 
 pub trait OrderRouter {
     fn route_order(&self, msg: EngineMessage) -> Result<(), String>;
-    fn create_order_id(&self, symbol: &Symbol, side: &OrderSide) -> Result<OrderId, String>;
     fn broadcast_message(&self, msg: EngineMessage) -> Result<(), String>;
-    fn replay_message(&self, msg: EngineMessage) -> Result<(), String>;
+    // Apply a message that a replication primary has already logged at `position` in the given
+    // engine's log
+    fn replay_message(&self, engine_id: u32, position: WalPosition, msg: EngineMessage)
+        -> Result<(), String>;
+    fn dump_books(&self) -> Result<(), String>;
+    // Tell every engine to close its logs and stop
+    fn shutdown_engines(&self, snapshot: bool) -> Result<(), String>;
     fn n_engine(&self) -> u32;
+    // Assign an ID to a new symbol and ask whichever engine it belongs on to list it
+    fn list_symbol(&self, symbol: Symbol) -> Result<u32, String>;
+    // ID and status of a symbol that is currently listed
+    fn symbol_status(&self, symbol: &Symbol) -> Option<(u32, SymbolStatus)>;
+    // Record a change an engine has made to its symbol table
+    fn update_symbol(&self, engine_id: u32, symbol: Symbol, symbol_id: u32,
+                     status: SymbolStatus);
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Eq, PartialEq)]
 pub enum ServerState {
     Loading,
-    Running
+    // Following a replication primary; no trading sessions until promoted
+    Standby,
+    // Replayed up to some point in the past; sessions can look but not trade
+    ReadOnly,
+    Running,
+    // Finishing what's in flight before stopping; nothing new is taken on
+    ShuttingDown
+}
+
+pub struct StandbyState {
+    pub handle: StandbyHandle,
+    // Completes once every replicated entry received has been handed to the router
+    pub drained: oneshot::Receiver<()>
 }
 
 // XXX: The fact that everything in here has to be wrapped in Rc and Cells seems like a really bad
@@ -57,32 +100,56 @@ pub struct ServerContext<R> where R: 'static + Clone + OrderRouter {
     pub handle: reactor::Handle,
     pub router: R,
     pub sub_map: Rc<RefCell<SubscripionMap>>,
-    pub pending_orders: Rc<RefCell<OrderMap>>,
-    pub wal: RefCell<Wal>,
+    pub pending_requests: Rc<RefCell<RequestMap>>,
+    pub request_ticket: Cell<RequestId>,
     // This is an Rc so it can be observed without sharing the entire context
     pub sync_gen: Rc<Cell<u32>>,
     pub sync_ticket: Cell<u32>,
     pub pending_syncs: RefCell<SyncMap>,
     pub state: Cell<ServerState>,
-    pub pending_open_orders: Rc<RefCell<OpenOrderMap>>
+    pub pending_open_orders: Rc<RefCell<OpenOrderMap>>,
+    pub standby: RefCell<Option<StandbyState>>,
+    // By engine ID
+    pub engine_stats: Vec<(u32, Arc<EngineStats>)>,
+    pub latency: LatencyStats,
+    pub auth: Authenticator,
+    pub risk: Rc<RiskManager>,
+    pub ledger: Rc<Ledger>,
+    // Completed once a shutdown has told the engines to stop, so whoever runs the reactor can
+    // stop it and wait for them
+    pub stopped: RefCell<Option<oneshot::Sender<()>>>
 }
 
 impl<R> ServerContext<R> where R: 'static + Clone + OrderRouter {
-    pub fn new(handle: reactor::Handle, router: R, wal: Wal) -> Self {
+    pub fn new(handle: reactor::Handle, router: R, engine_stats: Vec<(u32, Arc<EngineStats>)>,
+               auth: Authenticator, risk: RiskManager, ledger: Ledger) -> Self {
         ServerContext {
             handle: handle,
             router: router,
             sub_map: Rc::new(RefCell::new(SubscripionMap::new())),
-            pending_orders: Rc::new(RefCell::new(OrderMap::new())),
-            wal: RefCell::new(wal),
+            pending_requests: Rc::new(RefCell::new(RequestMap::new())),
+            request_ticket: Cell::new(0 as RequestId),
             sync_gen: Rc::new(Cell::new(0u32)),
             sync_ticket: Cell::new(0u32),
             pending_syncs: RefCell::new(SyncMap::new()),
             state: Cell::new(ServerState::Loading),
-            pending_open_orders: Rc::new(RefCell::new(OpenOrderMap::new()))
+            pending_open_orders: Rc::new(RefCell::new(OpenOrderMap::new())),
+            standby: RefCell::new(None),
+            engine_stats: engine_stats,
+            latency: LatencyStats::new(),
+            auth: auth,
+            risk: Rc::new(risk),
+            ledger: Rc::new(ledger),
+            stopped: RefCell::new(None)
         }
     }
 
+    pub fn next_request_id(&self) -> RequestId {
+        let request = self.request_ticket.get() + 1;
+        self.request_ticket.set(request);
+        request
+    }
+
     pub fn serialization_point<T>(ctx: T) -> SerializationPoint<Rc<Cell<u32>>>
             where T: AsRef<Self> {
         let context = ctx.as_ref();
@@ -101,12 +168,111 @@ impl<R> ServerContext<R> where R: 'static + Clone + OrderRouter {
             target: ticket
         }
     }
+
+    // Stops taking new sessions and orders, waits for the engines to finish everything already
+    // sent to them, lets subscribed clients know and then tells the engines to stop.  Fails if a
+    // shutdown has already started.
+    pub fn shutdown(ctx: Rc<Self>, snapshot: bool) -> Box<Future<Item=(), Error=()>> {
+        if let ServerState::ShuttingDown = ctx.state.get() {
+            return Box::new(future::err(()));
+        }
+
+        println!("shutting down");
+        ctx.state.set(ServerState::ShuttingDown);
+
+        // Anything a standby has received from its primary is applied before stopping
+        let drained: Box<Future<Item=(), Error=()>> = match ctx.standby.borrow_mut().take() {
+            Some(standby) => {
+                standby.handle.stop();
+                Box::new(standby.drained.then(|_| Ok(())))
+            },
+            None => Box::new(future::ok(()))
+        };
+
+        let sync_context = ctx.clone();
+        let notify_context = ctx.clone();
+        Box::new(drained.and_then(move |_| {
+            Self::serialization_point(sync_context)
+        }).and_then(move |_| {
+            println!("engines drained, notifying clients");
+            Self::notify_shutdown(notify_context.as_ref())
+        }).and_then(move |_| {
+            if let Err(e) = ctx.router.shutdown_engines(snapshot) {
+                println!("failed to stop engines: {}", e);
+            }
+
+            if let Some(stopped) = ctx.stopped.borrow_mut().take() {
+                stopped.complete(());
+            }
+
+            Ok(())
+        }))
+    }
+
+    // Waits for every subscribed client to acknowledge, or for NOTIFY_TIMEOUT_SECS to pass
+    fn notify_shutdown(&self) -> Box<Future<Item=(), Error=()>> {
+        let notices: Vec<_> = self.sub_map.borrow().iter().map(|(&user, sub)| {
+            sub.client.shutdown_request().send().promise.then(move |r| {
+                if let Err(e) = r {
+                    println!("failed to tell user {} about shutdown: {}", user, e);
+                }
+
+                Ok::<(), ()>(())
+            })
+        }).collect();
+
+        let timeout = match reactor::Timeout::new(Duration::from_secs(NOTIFY_TIMEOUT_SECS),
+                                                  &self.handle) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("can't wait for clients to acknowledge shutdown: {}", e);
+                return Box::new(future::ok(()));
+            }
+        };
+
+        Box::new(future::join_all(notices).map(|_| ()).select(timeout.map_err(|_| ()))
+                 .then(|_| Ok(())))
+    }
+}
+
+fn reject_code(reason: RejectReason) -> cp::ErrorCode {
+    match reason {
+        RejectReason::UnknownSymbol => cp::ErrorCode::UnknownSymbol,
+        RejectReason::SymbolSuspended => cp::ErrorCode::SymbolSuspended,
+        RejectReason::InvalidOrder => cp::ErrorCode::InvalidOrder,
+        RejectReason::UnknownOrder => cp::ErrorCode::UnknownOrder,
+        RejectReason::NotOrderOwner => cp::ErrorCode::NotOrderOwner,
+        RejectReason::ReadOnly => cp::ErrorCode::Unavailable,
+        RejectReason::Internal => cp::ErrorCode::Other
+    }
+}
+
+fn risk_code(reason: RiskReject) -> cp::ErrorCode {
+    match reason {
+        RiskReject::OrderQuantity => cp::ErrorCode::OrderQuantityLimit,
+        RiskReject::OrderNotional => cp::ErrorCode::OrderNotionalLimit,
+        RiskReject::OpenOrders => cp::ErrorCode::OpenOrdersLimit,
+        RiskReject::SymbolExposure => cp::ErrorCode::SymbolExposureLimit,
+        RiskReject::TotalExposure => cp::ErrorCode::TotalExposureLimit,
+        RiskReject::PriceDeviation => cp::ErrorCode::PriceDeviationLimit
+    }
+}
+
+fn funds_code(reason: FundsReject) -> cp::ErrorCode {
+    match reason {
+        FundsReject::InsufficientFunds => cp::ErrorCode::InsufficientFunds,
+        FundsReject::InsufficientPosition => cp::ErrorCode::InsufficientPosition
+    }
 }
 
 pub struct Session<R> where R: 'static + Clone + OrderRouter {
     context: Rc<ServerContext<R>>,
-    user: UserId,
-    authenticated: bool,
+    // Shared with sign ins that are still being checked
+    user: Rc<Cell<UserId>>,
+    authenticated: Rc<Cell<bool>>,
+    // Only one sign in is checked at a time, so pipelined guesses can't get ahead of the lockout
+    signing_in: Rc<Cell<bool>>,
+    permissions: Rc<RefCell<Permissions>>,
     open_order_seq: u32
 }
 
@@ -114,8 +280,10 @@ impl<R> Session<R> where R: 'static + Clone + OrderRouter {
     pub fn new(context: Rc<ServerContext<R>>) -> Self {
         Session {
             context: context,
-            user: 0u64,
-            authenticated: false,
+            user: Rc::new(Cell::new(0u64)),
+            authenticated: Rc::new(Cell::new(false)),
+            signing_in: Rc::new(Cell::new(false)),
+            permissions: Rc::new(RefCell::new(Permissions::none())),
             open_order_seq: 0u32
         }
     }
@@ -158,66 +326,172 @@ impl cp::execution_feed_subscription::Server for ExecutionSubscriptionMd {}
 impl<R> Server for Session<R> where R: 'static + Clone + OrderRouter {
     fn authenticate(&mut self, params: AuthenticateParams, mut results: AuthenticateResults)
                     -> Promise<(), capnp::Error> {
-        self.user = pry!(params.get()).get_user();
-        self.authenticated = true;
+        if self.authenticated.get() {
+            results.get().set_response(cp::AuthCode::AlreadyAuthenticated);
+            return Promise::ok(());
+        }
 
-        println!("new session for user {}", self.user);
+        if self.signing_in.get() {
+            results.get().set_response(cp::AuthCode::RateLimited);
+            return Promise::ok(());
+        }
 
-        results.get().set_response(cp::AuthCode::Ok);
-        Promise::ok(())
+        if self.context.state.get() == ServerState::ShuttingDown {
+            results.get().set_response(cp::AuthCode::Unavailable);
+            return Promise::ok(());
+        }
+
+        let credentials = pry!(pry!(params.get()).get_credentials());
+        let username = pry!(credentials.get_username());
+        let credential = match pry!(credentials.which()) {
+            cp::credentials::Password(p) => Credential::Password(pry!(p).to_string()),
+            cp::credentials::ApiKey(k) => Credential::ApiKey(pry!(k).to_string())
+        };
+
+        let context = self.context.clone();
+        let user_id = self.user.clone();
+        let authenticated = self.authenticated.clone();
+        let signing_in = self.signing_in.clone();
+        let permissions = self.permissions.clone();
+        let username = username.to_string();
+        self.signing_in.set(true);
+
+        Promise::from_future(self.context.auth.authenticate(username.as_str(), credential).then(
+                move |signed_in| {
+            signing_in.set(false);
+
+            match signed_in {
+                Ok((user, allowed)) => {
+                    // Standbys (and servers still replaying) only take sessions that can
+                    // administer them
+                    let trading = match context.state.get() {
+                        ServerState::Running | ServerState::ReadOnly => true,
+                        _ => false
+                    };
+
+                    if !trading && !allowed.can_administer() {
+                        results.get().set_response(cp::AuthCode::Unavailable);
+                        return Ok(());
+                    }
+
+                    println!("new session for {} (user {}, {:?})", username, user, allowed.role);
+                    user_id.set(user);
+                    authenticated.set(true);
+                    *permissions.borrow_mut() = allowed;
+
+                    results.get().set_response(cp::AuthCode::Ok);
+                    results.get().set_user(user);
+                },
+                Err(AuthFailure::Invalid) => {
+                    println!("failed sign in as {}", username);
+                    results.get().set_response(cp::AuthCode::Invalid);
+                },
+                Err(AuthFailure::RateLimited) => {
+                    println!("refused sign in as {} after too many failures", username);
+                    results.get().set_response(cp::AuthCode::RateLimited);
+                }
+            }
+
+            Ok(())
+        }))
     }
 
     fn new_order(&mut self, params: NewOrderParams, mut results: NewOrderResults)
                  -> Promise<(), capnp::Error> {
-        if !self.authenticated {
+        let received = Instant::now();
+
+        if !self.authenticated.get() {
             results.get().set_code(cp::ErrorCode::NotAuthenticated);
             return Promise::ok(());
         }
 
+        if self.context.state.get() != ServerState::Running {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
         let order = pry!(pry!(params.get()).get_order());
         let symbol = pry!(Symbol::from_capnp(pry!(order.get_symbol())).map_err(|e| {
             capnp::Error::failed("invalid symbol".to_string())
         }));
+
+        if !self.permissions.borrow().can_trade(&symbol) {
+            results.get().set_code(cp::ErrorCode::PermissionDenied);
+            return Promise::ok(());
+        }
         let side = OrderSide::from(pry!(order.get_side()));
-        let order_id = pry!(self.context.router.create_order_id(&symbol, &side).map_err(|e| {
-            capnp::Error::failed(e)
-        }));
+        let price = order.get_price();
+        let quantity = order.get_quantity();
 
+        // Has to be turned down before anything is reserved, since a price like this would throw
+        // off the risk and funds totals for good
+        if !(price > 0.0) || price.is_infinite() || quantity == 0 {
+            results.get().set_code(cp::ErrorCode::InvalidOrder);
+            return Promise::ok(());
+        }
+
+        let user = self.user.get();
+        let reservation = match RiskManager::reserve(&self.context.risk, user, symbol, price,
+                                                     quantity) {
+            Ok(r) => r,
+            Err(reason) => {
+                println!("rejected order from user {}: {}", user, reason);
+                results.get().set_code(risk_code(reason));
+                return Promise::ok(());
+            }
+        };
+
+        let funds = match Ledger::reserve(&self.context.ledger, user, symbol, side, price,
+                                          quantity, self.permissions.borrow().short_selling) {
+            Ok(f) => f,
+            Err(reason) => {
+                println!("rejected order from user {}: {}", user, reason);
+                results.get().set_code(funds_code(reason));
+                return Promise::ok(());
+            }
+        };
+
+        let request = self.context.next_request_id();
+
+        // The engine assigns the order ID and timestamp and logs the message before processing it
         let msg = EngineMessage::NewOrder(NewOrderMessage {
-            user: self.user,
-            order_id: order_id,
+            request: request,
+            user: user,
+            order_id: OrderId::default(),
             symbol: symbol,
             side: side,
-            price: order.get_price(),
-            quantity: order.get_quantity()
+            price: price,
+            quantity: quantity,
+            ts: OrderTime::new(0, 0)
         });
 
-        // XXX: Move the WAL write to engine threads; this would also allow order ID assignment to
-        // happen on those threads and remove some of the Rc<RefCell<T>> garbage we have going on
-        // here
-        pry!(self.context.wal.borrow_mut().write_entry(&msg).map_err(|e| {
-            capnp::Error::failed(e)
-        }));
-
         let send = pry!(self.context.router.route_order(msg).map_err(|e| {
-            capnp::Error::failed("internal error".to_string())
+            capnp::Error::failed(e)
         }));
 
         // Register this task to handle the engine's response and communicate it
         // to the client
-        let send_future = NewOrderSend::new(order_id,
-                                            self.context.pending_orders.clone());
-        self.context.pending_orders.borrow_mut().insert(order_id,
-                                                        OrderWait::new());
-
-        Promise::from_future(send_future.and_then(move |c| {
-            let ret_code = match c {
-                ErrorCode::Success => cp::ErrorCode::Ok,
-                _ => cp::ErrorCode::Other
-            };
-            println!("received ack for order {}", order_id);
-            results.get().set_code(ret_code);
-            results.get().set_id(order_id.raw());
+        let send_future = RequestSend::new(request, self.context.pending_requests.clone());
+        self.context.pending_requests.borrow_mut().insert(request, RequestWait::new());
+
+        let context = self.context.clone();
+        Promise::from_future(send_future.and_then(move |result| {
+            // An accepted order is tracked by its ack from here on
+            drop(reservation);
+            drop(funds);
+
+            match result {
+                Ok(accepted) => {
+                    println!("received ack for order {}", accepted.order_id);
+                    results.get().set_code(cp::ErrorCode::Ok);
+                    results.get().set_id(accepted.order_id.raw());
+                    context.latency.record(RequestKind::NewOrder, accepted.symbol, received,
+                                           accepted.times, Instant::now());
+                },
+                Err(reason) => {
+                    results.get().set_code(reject_code(reason));
+                }
+            }
             Ok(())
         }).map_err(|e| {
             capnp::Error::failed("internal error".to_string())
@@ -226,11 +500,23 @@ impl<R> Server for Session<R> where R: 'static + Clone + OrderRouter {
 
     fn cancel_order(&mut self, params: CancelOrderParams, mut results: CancelOrderResults)
                     -> Promise<(), capnp::Error> {
-        if !self.authenticated {
+        let received = Instant::now();
+
+        if !self.authenticated.get() {
             results.get().set_code(cp::ErrorCode::NotAuthenticated);
             return Promise::ok(());
         }
 
+        if !self.permissions.borrow().can_cancel() {
+            results.get().set_code(cp::ErrorCode::PermissionDenied);
+            return Promise::ok(());
+        }
+
+        if self.context.state.get() != ServerState::Running {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
         let raw_order_id = pry!(pry!(params.get()).get_cancel()).get_id();
         let order_id = match OrderId::from_raw(raw_order_id) {
             Ok(id) => id,
@@ -240,33 +526,59 @@ impl<R> Server for Session<R> where R: 'static + Clone + OrderRouter {
             }
         };
 
+        let request = self.context.next_request_id();
         let msg = EngineMessage::CancelOrder(CancelOrderMessage {
-            user:       self.user,
+            request:    request,
+            user:       self.user.get(),
             order_id:   order_id
         });
 
-        pry!(self.context.wal.borrow_mut().write_entry(&msg).map_err(|e| {
-            capnp::Error::failed(e)
-        }));
-
         let send = pry!(self.context.router.route_order(msg).map_err(|e| {
             capnp::Error::failed("internal error".to_string())
         }));
 
-        results.get().set_code(cp::ErrorCode::Ok);
-        Promise::ok(())
+        let send_future = RequestSend::new(request, self.context.pending_requests.clone());
+        self.context.pending_requests.borrow_mut().insert(request, RequestWait::new());
+
+        let context = self.context.clone();
+        Promise::from_future(send_future.and_then(move |result| {
+            match result {
+                Ok(accepted) => {
+                    results.get().set_code(cp::ErrorCode::Ok);
+                    context.latency.record(RequestKind::Cancel, accepted.symbol, received,
+                                           accepted.times, Instant::now());
+                },
+                Err(reason) => {
+                    results.get().set_code(reject_code(reason));
+                }
+            }
+            Ok(())
+        }).map_err(|e| {
+            capnp::Error::failed("internal error".to_string())
+        }))
     }
 
     fn get_open_orders(&mut self, params: GetOpenOrdersParams,
                        mut results: GetOpenOrdersResults)
                        -> Promise<(), capnp::Error> {
-        if !self.authenticated {
+        if !self.authenticated.get() {
             results.get().set_code(cp::ErrorCode::NotAuthenticated);
             return Promise::ok(());
         }
 
+        if !self.permissions.borrow().can_view_orders() {
+            results.get().set_code(cp::ErrorCode::PermissionDenied);
+            return Promise::ok(());
+        }
+
+        // The engines may already have stopped, and then nothing would ever answer
+        if self.context.state.get() == ServerState::ShuttingDown {
+            results.get().set_code(cp::ErrorCode::Unavailable);
+            return Promise::ok(());
+        }
+
         let seq = OpenOrdersSequence {
-            user: self.user,
+            user: self.user.get(),
             seq: self.open_order_seq
         };
 
@@ -304,24 +616,157 @@ impl<R> Server for Session<R> where R: 'static + Clone + OrderRouter {
     fn execution_subscribe(&mut self, params: ExecutionSubscribeParams,
                            mut results: ExecutionSubscribeResults)
             -> Promise<(), capnp::Error> {
-        if !self.authenticated {
+        if !self.authenticated.get() {
             results.get().set_code(cp::ErrorCode::NotAuthenticated);
             return Promise::ok(());
         }
 
         let ref mut sub_map = *(self.context.sub_map.borrow_mut());
-        if sub_map.contains_key(&self.user) {
+        if sub_map.contains_key(&self.user.get()) {
             results.get().set_code(cp::ErrorCode::AlreadySubscribed);
             return Promise::ok(());
         }
 
         let subscriber = pry!(pry!(params.get()).get_feed());
-        sub_map.insert(self.user, ExecutionSubscription::new(subscriber));
+        sub_map.insert(self.user.get(), ExecutionSubscription::new(subscriber));
 
         results.get().set_code(cp::ErrorCode::Ok);
         results.get().set_sub(cp::execution_feed_subscription::ToClient::new(
-                ExecutionSubscriptionMd::new(self.user, self.context.sub_map.clone()))
+                ExecutionSubscriptionMd::new(self.user.get(), self.context.sub_map.clone()))
                 .from_server::<::capnp_rpc::Server>());
         Promise::ok(())
     }
+
+    fn get_positions(&mut self, _params: GetPositionsParams, mut results: GetPositionsResults)
+                     -> Promise<(), capnp::Error> {
+        if !self.authenticated.get() {
+            results.get().set_code(cp::ErrorCode::NotAuthenticated);
+            return Promise::ok(());
+        }
+
+        // Anyone who can see their orders can see what came of them
+        if !self.permissions.borrow().can_view_orders() {
+            results.get().set_code(cp::ErrorCode::PermissionDenied);
+            return Promise::ok(());
+        }
+
+        let account = self.context.ledger.account(self.user.get());
+        results.get().set_code(cp::ErrorCode::Ok);
+        results.get().set_cash(account.cash);
+        results.get().set_available(account.available_cash());
+
+        let mut positions = results.get().init_positions(account.positions.len() as u32);
+        for (i, (symbol, position)) in account.positions.iter().enumerate() {
+            let mut p = positions.borrow().get(i as u32);
+            p.set_symbol(symbol.as_str());
+            p.set_quantity(position.quantity);
+            p.set_average_cost(position.average_cost);
+            p.set_realized_pnl(position.realized_pnl);
+        }
+
+        Promise::ok(())
+    }
+
+    fn admin(&mut self, _params: AdminParams, mut results: AdminResults)
+             -> Promise<(), capnp::Error> {
+        if !self.authenticated.get() {
+            results.get().set_code(cp::ErrorCode::NotAuthenticated);
+            return Promise::ok(());
+        }
+
+        // Admin calls go through the capability handed out here, so this is the one place they
+        // need checking
+        if !self.permissions.borrow().can_administer() {
+            println!("user {} isn't allowed to administer the server", self.user.get());
+            results.get().set_code(cp::ErrorCode::PermissionDenied);
+            return Promise::ok(());
+        }
+
+        results.get().set_code(cp::ErrorCode::Ok);
+        results.get().set_admin(cp::admin::ToClient::new(
+                AdminSession::new(self.context.clone()))
+                .from_server::<::capnp_rpc::Server>());
+        Promise::ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use auth::{Authenticator, UserDatabase};
+    use ledger::Ledger;
+    use risk::{RiskLimits, RiskManager};
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    // Engines that have already stopped, so anything sent to them goes unanswered
+    #[derive(Clone)]
+    struct StoppedRouter {
+        sent: Rc<RefCell<Vec<EngineMessage>>>
+    }
+
+    impl OrderRouter for StoppedRouter {
+        fn route_order(&self, msg: EngineMessage) -> Result<(), String> {
+            self.sent.borrow_mut().push(msg);
+            Ok(())
+        }
+
+        fn broadcast_message(&self, msg: EngineMessage) -> Result<(), String> {
+            self.sent.borrow_mut().push(msg);
+            Ok(())
+        }
+
+        fn replay_message(&self, _engine_id: u32, _position: WalPosition, msg: EngineMessage)
+                -> Result<(), String> {
+            self.sent.borrow_mut().push(msg);
+            Ok(())
+        }
+
+        fn dump_books(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn shutdown_engines(&self, _snapshot: bool) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn n_engine(&self) -> u32 {
+            1
+        }
+
+        fn list_symbol(&self, _symbol: Symbol) -> Result<u32, String> {
+            Err("stopped".to_string())
+        }
+
+        fn symbol_status(&self, _symbol: &Symbol) -> Option<(u32, SymbolStatus)> {
+            None
+        }
+
+        fn update_symbol(&self, _engine_id: u32, _symbol: Symbol, _symbol_id: u32,
+                         _status: SymbolStatus) {
+        }
+    }
+
+    #[test]
+    fn open_orders_are_unavailable_while_shutting_down() {
+        let core = Core::new().unwrap();
+        let router = StoppedRouter { sent: Rc::new(RefCell::new(Vec::new())) };
+        let context = Rc::new(ServerContext::new(core.handle(), router.clone(), Vec::new(),
+                                                 Authenticator::new(UserDatabase::empty()),
+                                                 RiskManager::new(RiskLimits::default(),
+                                                                  HashMap::new()),
+                                                 Ledger::new(None, false)));
+        context.state.set(ServerState::ShuttingDown);
+
+        let session = Session::new(context.clone());
+        session.authenticated.set(true);
+        session.user.set(1);
+        let client = cp::trading_session::ToClient::new(session)
+            .from_server::<::capnp_rpc::Server>();
+
+        let response = client.get_open_orders_request().send().promise.wait().unwrap();
+        assert_eq!(response.get().unwrap().get_code().unwrap() as u16,
+                   cp::ErrorCode::Unavailable as u16);
+        assert!(router.sent.borrow().is_empty());
+        assert!(context.pending_open_orders.borrow().is_empty());
+    }
 }
@@ -0,0 +1,176 @@
+use futures::{Async, Future, Poll, Sink, Stream};
+use futures::stream;
+use futures::sync::mpsc;
+use ring::{self, WaitStrategy};
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+// How messages get between sessions and engines
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportKind {
+    // futures channel, which every sender can share
+    Channel,
+    // One ring buffer per direction per engine
+    Ring(WaitStrategy)
+}
+
+impl TransportKind {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "channel" => Ok(TransportKind::Channel),
+            "ring-poll" => Ok(TransportKind::Ring(WaitStrategy::BusyPoll)),
+            "ring-park" => Ok(TransportKind::Ring(WaitStrategy::Park)),
+            _ => Err(format!("unknown transport {} (expected channel, ring-poll or ring-park)", s))
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match *self {
+            TransportKind::Channel => "channel",
+            TransportKind::Ring(WaitStrategy::BusyPoll) => "ring-poll",
+            TransportKind::Ring(WaitStrategy::Park) => "ring-park"
+        })
+    }
+}
+
+// A ring sender can't be cloned, so whoever owns one has to share it from a single thread
+pub struct Sender<T> {
+    inner: SenderKind<T>,
+    // Set between hold and flush, when sends are saved up and then sent all at once
+    holding: Cell<bool>,
+    held: RefCell<Vec<T>>
+}
+
+enum SenderKind<T> {
+    Channel(mpsc::Sender<T>),
+    Ring(ring::Producer<T>)
+}
+
+pub enum Receiver<T> {
+    Channel(mpsc::Receiver<T>),
+    Ring(ring::Consumer<T>)
+}
+
+pub fn channel<T: Send>(kind: TransportKind, capacity: usize) -> (Sender<T>, Receiver<T>) {
+    match kind {
+        TransportKind::Channel => {
+            let (tx, rx) = mpsc::channel(capacity);
+            (Sender::new(SenderKind::Channel(tx)), Receiver::Channel(rx))
+        },
+        TransportKind::Ring(strategy) => {
+            let (tx, rx) = ring::channel(capacity, strategy);
+            (Sender::new(SenderKind::Ring(tx)), Receiver::Ring(rx))
+        }
+    }
+}
+
+impl<T: Send> Sender<T> {
+    fn new(inner: SenderKind<T>) -> Self {
+        Sender {
+            inner: inner,
+            holding: Cell::new(false),
+            held: RefCell::new(Vec::new())
+        }
+    }
+
+    // Blocks while the receiver is full, unless sends are being held
+    pub fn send(&self, item: T) -> Result<(), String> {
+        if self.holding.get() {
+            self.held.borrow_mut().push(item);
+            return Ok(());
+        }
+
+        match self.inner {
+            SenderKind::Channel(ref tx) => {
+                tx.clone().send(item).wait().map(|_| ()).map_err(|e| {
+                    e.description().to_string()
+                })
+            },
+            SenderKind::Ring(ref tx) => {
+                tx.push(item).map_err(|_| "receiver is gone".to_string())
+            }
+        }
+    }
+
+    // Saves up everything sent from now until the next flush
+    pub fn hold(&self) {
+        self.holding.set(true);
+    }
+
+    // Sends everything held since hold was called in one go, and goes back to sending straight
+    // away
+    pub fn flush(&self) -> Result<(), String> {
+        self.holding.set(false);
+        let held = mem::replace(&mut *self.held.borrow_mut(), Vec::new());
+
+        if held.is_empty() {
+            return Ok(());
+        }
+
+        match self.inner {
+            SenderKind::Channel(ref tx) => {
+                let items = stream::iter(held.into_iter().map(Ok::<T, mpsc::SendError<T>>));
+                tx.clone().send_all(items).wait().map(|_| ()).map_err(|e| {
+                    e.description().to_string()
+                })
+            },
+            SenderKind::Ring(ref tx) => {
+                tx.push_all(held).map_err(|_| "receiver is gone".to_string())
+            }
+        }
+    }
+}
+
+// Gathers up everything a stream has ready, up to `max` items at a time, so that it can all be
+// handled together
+pub struct Batches<S> {
+    inner: S,
+    max: usize
+}
+
+impl<S: Stream> Batches<S> {
+    pub fn new(inner: S, max: usize) -> Self {
+        Batches {
+            inner: inner,
+            max: max
+        }
+    }
+}
+
+impl<S: Stream> Stream for Batches<S> {
+    type Item = Vec<S::Item>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, S::Error> {
+        let mut batch = Vec::new();
+
+        while batch.len() < self.max {
+            match try!(self.inner.poll()) {
+                Async::Ready(Some(item)) => batch.push(item),
+                Async::Ready(None) if batch.is_empty() => { return Ok(Async::Ready(None)); },
+                // Whatever was gathered so far goes out now and the end is picked up next time
+                Async::Ready(None) => break,
+                Async::NotReady if batch.is_empty() => { return Ok(Async::NotReady); },
+                Async::NotReady => break
+            }
+        }
+
+        Ok(Async::Ready(Some(batch)))
+    }
+}
+
+impl<T: Send> Stream for Receiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        match *self {
+            Receiver::Channel(ref mut rx) => rx.poll(),
+            Receiver::Ring(ref mut rx) => rx.poll()
+        }
+    }
+}
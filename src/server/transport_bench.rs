@@ -0,0 +1,231 @@
+extern crate bincode;
+extern crate flate2;
+extern crate futures;
+extern crate libcix;
+extern crate memmap;
+extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate time;
+
+mod messages;
+mod ring;
+mod transport;
+mod wal;
+
+use futures::{Future, Stream};
+use libcix::order::trade_types::*;
+use messages::{EngineMessage, EngineRequest, EngineTimes, NewOrderMessage, RequestId,
+               SessionMessage};
+use std::env::args;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use transport::{Receiver, Sender, TransportKind};
+
+struct BenchArgs {
+    transports: Vec<TransportKind>,
+    // Messages sent one way as fast as possible
+    count: usize,
+    // Requests that each wait for their response before the next is sent
+    round_trips: usize,
+    buffer_size: usize
+}
+
+fn parse_args() -> Result<BenchArgs, String> {
+    let mut parsed = BenchArgs {
+        transports: Vec::new(),
+        count: 1000000,
+        round_trips: 100000,
+        buffer_size: 1024
+    };
+
+    let mut cli_args = args();
+    cli_args.next();
+
+    while let Some(flag) = cli_args.next() {
+        let mut value = || cli_args.next().ok_or(format!("{} requires a value", flag));
+
+        match flag.as_str() {
+            "--transport" => {
+                parsed.transports.push(try!(TransportKind::parse(try!(value()).as_str())));
+            },
+            "--count" => {
+                parsed.count = try!(usize::from_str(try!(value()).as_str()).map_err(|_| {
+                    "invalid count".to_string()
+                }));
+            },
+            "--round-trips" => {
+                parsed.round_trips = try!(usize::from_str(try!(value()).as_str()).map_err(|_| {
+                    "invalid round trip count".to_string()
+                }));
+            },
+            "--buffer-size" => {
+                parsed.buffer_size = try!(usize::from_str(try!(value()).as_str()).map_err(|_| {
+                    "invalid buffer size".to_string()
+                }));
+            },
+            _ => { return Err(format!("unrecognized argument {}", flag)); }
+        }
+    }
+
+    if parsed.transports.is_empty() {
+        parsed.transports = vec!["channel", "ring-poll", "ring-park"].into_iter().map(|t| {
+            TransportKind::parse(t).unwrap()
+        }).collect();
+    }
+
+    if parsed.buffer_size == 0 {
+        return Err("buffer size has to be at least 1".to_string());
+    }
+
+    Ok(parsed)
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1000000000 + d.subsec_nanos() as u64
+}
+
+// About what a session sends for every order
+fn order_request(request: RequestId) -> EngineRequest {
+    EngineRequest::Live(EngineMessage::NewOrder(NewOrderMessage {
+        request: request,
+        user: 1,
+        order_id: OrderId::default(),
+        symbol: Symbol::from_str("AAPL").unwrap(),
+        side: OrderSide::Buy,
+        price: 100.0,
+        quantity: 10,
+        ts: OrderTime::new(0, 0)
+    }))
+}
+
+// Calls `f` with everything received until the sender goes away, waiting the same way an engine
+// would
+fn drain<T, F>(rx: Receiver<T>, mut f: F) where T: Send, F: FnMut(T) {
+    match rx {
+        Receiver::Channel(rx) => {
+            for item in rx.wait() {
+                match item {
+                    Ok(item) => f(item),
+                    Err(_) => break
+                }
+            }
+        },
+        Receiver::Ring(rx) => {
+            while let Some(item) = rx.pop() {
+                f(item);
+            }
+        }
+    }
+}
+
+// Waits for a single item
+fn recv<T: Send>(rx: Receiver<T>) -> Option<(T, Receiver<T>)> {
+    match rx {
+        Receiver::Channel(rx) => {
+            match rx.into_future().wait() {
+                Ok((Some(item), rx)) => Some((item, Receiver::Channel(rx))),
+                _ => None
+            }
+        },
+        Receiver::Ring(rx) => rx.pop().map(|item| (item, Receiver::Ring(rx)))
+    }
+}
+
+// Messages per second sent from one thread to another
+fn throughput(kind: TransportKind, count: usize, buffer_size: usize) -> f64 {
+    let (tx, rx) = transport::channel(kind, buffer_size);
+
+    let consumer = thread::spawn(move || {
+        let mut received = 0usize;
+        drain(rx, |_: EngineRequest| { received += 1; });
+        received
+    });
+
+    let start = Instant::now();
+    for i in 0..count {
+        tx.send(order_request(i as RequestId)).unwrap();
+    }
+    drop(tx);
+
+    let received = consumer.join().unwrap();
+    let elapsed = nanos(start.elapsed());
+    assert!(received == count, "sent {} messages but {} arrived", count, received);
+
+    count as f64 * 1e9 / elapsed as f64
+}
+
+// Time from sending an order to hearing back about it, through a thread that acks everything the
+// way an engine would.  Sorted, in nanoseconds.
+fn round_trips(kind: TransportKind, count: usize, buffer_size: usize) -> Vec<u64> {
+    let (request_tx, request_rx) = transport::channel(kind, buffer_size);
+    let (response_tx, response_rx): (Sender<SessionMessage>, Receiver<SessionMessage>) =
+        transport::channel(kind, buffer_size);
+
+    let responder = thread::spawn(move || {
+        drain(request_rx, |request: EngineRequest| {
+            if let EngineRequest::Live(EngineMessage::NewOrder(order)) = request {
+                response_tx.send(SessionMessage::NewOrderAck {
+                    request: order.request,
+                    order_id: order.order_id,
+                    symbol: order.symbol,
+                    status: ErrorCode::Success,
+                    resting: None,
+                    times: EngineTimes::new(Instant::now())
+                }).unwrap();
+            }
+        });
+    });
+
+    let mut times = Vec::with_capacity(count);
+    let mut rx = response_rx;
+
+    for i in 0..count {
+        let start = Instant::now();
+        request_tx.send(order_request(i as RequestId)).unwrap();
+
+        let (_, next) = recv(rx).expect("responder went away");
+        times.push(nanos(start.elapsed()));
+        rx = next;
+    }
+
+    drop(request_tx);
+    responder.join().unwrap();
+
+    times.sort();
+    times
+}
+
+fn percentile(sorted: &Vec<u64>, p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[i] as f64 / 1000.0
+}
+
+// usage: transport_bench [--transport <channel|ring-poll|ring-park>]... [--count N]
+//                        [--round-trips N] [--buffer-size N]
+// Compares how fast orders get from a session to an engine and back over each transport.  Every
+// transport is run unless some are given.  ring-poll needs a spare core for each side to mean
+// anything.
+fn main() {
+    let bench_args = parse_args().unwrap_or_else(|e| {
+        panic!("invalid arguments: {}", e)
+    });
+
+    println!("{:<10} {:>14} {:>10} {:>10} {:>10} {:>10}", "transport", "msgs/sec", "rtt p50",
+             "rtt p90", "rtt p99", "rtt max");
+
+    for &kind in bench_args.transports.iter() {
+        let rate = throughput(kind, bench_args.count, bench_args.buffer_size);
+        let times = round_trips(kind, bench_args.round_trips, bench_args.buffer_size);
+
+        println!("{:<10} {:>14.0} {:>8.2}us {:>8.2}us {:>8.2}us {:>8.2}us", kind.to_string(),
+                 rate, percentile(&times, 0.5), percentile(&times, 0.9),
+                 percentile(&times, 0.99), percentile(&times, 1.0));
+    }
+}
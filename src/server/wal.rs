@@ -1,52 +1,310 @@
 use libcix::order::trade_types::*;
 use messages::EngineMessage;
-use bincode::{serialize, deserialize, deserialize_from, serialized_size, Bounded}; 
+use bincode::{serialize, deserialize, deserialize_from, Bounded, Infinite};
+use flate2::Compression;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
 use memmap::{Mmap, Protection};
 use regex::Regex;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::error::Error;
 use std::ffi::OsString;
-use std::fs::{File, OpenOptions, read_dir, ReadDir};
+use std::fmt;
+use std::fs::{File, OpenOptions, read_dir, ReadDir, remove_file, rename};
+use std::io;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::ptr;
 use std::slice;
 use std::str::FromStr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::mpsc;
+use std::thread;
 use std::vec::Vec;
 
+// Identifies files that start with a header.  Older files don't have one and start straight
+// into entries; those are treated as schema version 0.
+const WAL_MAGIC: u32 = 0x4c415743;
+// Serialized size of WalHeader.  Entries start immediately after it.
+pub const WAL_HEADER_SIZE: usize = 16;
+const PAGE_SIZE: usize = 4096;
+
+// Written at the start of every file and rewritten whenever the writer commits so that readers
+// can tell exactly how much of a file has been committed, even while it's still being written to
 #[derive(Serialize, Deserialize)]
 struct WalHeader {
+    magic: u32,
+    // Schema version of every entry in the file
+    version: u32,
     bytes_used: u64
 }
 
+// Anything that can be written to a log.  Files are preallocated and zeroed.  Files without a
+// header have no record of how much was written, so the end is found by decoding zeroed memory as
+// an entry for which `is_null` is true.
+//
+// Each file records the schema version its entries were written with.  Whenever the serialized
+// form of an entry type changes, bump its SCHEMA_VERSION and teach `decode` to read the old form
+// so that existing logs can still be replayed.
+pub trait WalEntry: 'static + Clone + Serialize + DeserializeOwned + Send {
+    // Version written by this build
+    const SCHEMA_VERSION: u32;
+
+    fn is_null(&self) -> bool;
+
+    // Read a single entry written with the given schema version, leaving the reader just past it
+    fn decode<R: Read>(version: u32, reader: &mut R) -> Result<Self, String> {
+        if version != Self::SCHEMA_VERSION {
+            return Err(format!("unsupported schema version {}", version));
+        }
+
+        decode_current(reader)
+    }
+}
+
+pub fn decode_current<T: DeserializeOwned, R: Read>(reader: &mut R) -> Result<T, String> {
+    deserialize_from(reader, Infinite).map_err(|e| e.description().to_string())
+}
+
+// Location of an entry within a log directory
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+         Deserialize)]
+pub struct WalPosition {
+    pub index: u32,
+    pub offset: u64
+}
+
+impl fmt::Display for WalPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.index, self.offset)
+    }
+}
+
 enum WriteResult {
     Success,
     LogFull,
     WriteError(String)
 }
 
-pub struct WalFile {
+// Where to stop when replaying a log, for reconstructing the state of the books as of some point
+// in the past
+#[derive(Clone, Copy, Debug)]
+pub enum ReplayLimit {
+    Everything,
+    // Stop after the entry at this position
+    Position(WalPosition),
+    // Stop after this many entries
+    Count(usize),
+    // Stop at the first order accepted after this time.  Cancels aren't timestamped, so any that
+    // were logged between the last order before the limit and the first one after it are applied.
+    Time(OrderTime)
+}
+
+impl ReplayLimit {
+    // Accepts pos:<index>:<offset>, count:<n> or time:<seconds>[.<fraction>]
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        let invalid = || format!("invalid replay limit {}", spec);
+
+        match (fields[0], fields.len()) {
+            ("pos", 3) => {
+                Ok(ReplayLimit::Position(WalPosition {
+                    index: try!(u32::from_str(fields[1]).map_err(|_| invalid())),
+                    offset: try!(u64::from_str(fields[2]).map_err(|_| invalid()))
+                }))
+            },
+            ("count", 2) => {
+                Ok(ReplayLimit::Count(try!(usize::from_str(fields[1]).map_err(|_| invalid()))))
+            },
+            ("time", 2) => {
+                let secs = try!(f64::from_str(fields[1]).map_err(|_| invalid()));
+                Ok(ReplayLimit::Time(OrderTime::new(secs.trunc() as i64,
+                                                    (secs.fract() * 1e9) as i32)))
+            },
+            _ => Err(invalid())
+        }
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        match *self {
+            ReplayLimit::Everything => false,
+            _ => true
+        }
+    }
+
+    // Whether replay should stop before applying `msg`, which was logged at `position` and is the
+    // `count`th entry (counting from zero)
+    pub fn reached(&self, position: WalPosition, count: usize, msg: &EngineMessage) -> bool {
+        match *self {
+            ReplayLimit::Everything => false,
+            ReplayLimit::Position(p) => position > p,
+            ReplayLimit::Count(n) => count >= n,
+            ReplayLimit::Time(t) => {
+                if let EngineMessage::NewOrder(ref order) = *msg {
+                    order.ts > t
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+// How closed files are compressed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    Gzip,
+    Zlib
+}
+
+impl ArchiveFormat {
+    // None means files are left uncompressed
+    pub fn parse(spec: &str) -> Result<Option<Self>, String> {
+        match spec {
+            "none" => Ok(None),
+            "gzip" => Ok(Some(ArchiveFormat::Gzip)),
+            "zlib" => Ok(Some(ArchiveFormat::Zlib)),
+            _ => Err(format!("unknown archive format {}", spec))
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            ArchiveFormat::Gzip => "gz",
+            ArchiveFormat::Zlib => "zlib"
+        }
+    }
+
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Some(ArchiveFormat::Gzip),
+            Some("zlib") => Some(ArchiveFormat::Zlib),
+            _ => None
+        }
+    }
+
+    fn compress(&self, bytes: &[u8], out: File) -> io::Result<()> {
+        let f = match *self {
+            ArchiveFormat::Gzip => {
+                let mut encoder = GzEncoder::new(out, Compression::Default);
+                try!(encoder.write_all(bytes));
+                try!(encoder.finish())
+            },
+            ArchiveFormat::Zlib => {
+                let mut encoder = ZlibEncoder::new(out, Compression::Default);
+                try!(encoder.write_all(bytes));
+                try!(encoder.finish())
+            }
+        };
+
+        f.sync_all()
+    }
+
+    fn decompress(&self, f: &File) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+
+        match *self {
+            ArchiveFormat::Gzip => { try!(try!(GzDecoder::new(f)).read_to_end(&mut bytes)); },
+            ArchiveFormat::Zlib => { try!(ZlibDecoder::new(f).read_to_end(&mut bytes)); }
+        }
+
+        Ok(bytes)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WalConfig {
+    pub segment_size: usize,
+    // Compress files once they've been rotated away from
+    pub archive: Option<ArchiveFormat>
+}
+
+// Backing memory for a file.  Files being written are mapped, while archived ones are
+// decompressed into memory and can only be read.
+enum Storage {
+    Mapped(Mmap),
+    Archived(Vec<u8>)
+}
+
+impl Storage {
+    fn len(&self) -> usize {
+        match *self {
+            Storage::Mapped(ref m) => m.len(),
+            Storage::Archived(ref v) => v.len()
+        }
+    }
+
+    unsafe fn as_slice(&self) -> &[u8] {
+        match *self {
+            Storage::Mapped(ref m) => m.as_slice(),
+            Storage::Archived(ref v) => v.as_slice()
+        }
+    }
+
+    unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        match *self {
+            Storage::Mapped(ref mut m) => m.as_mut_slice(),
+            Storage::Archived(ref mut v) => v.as_mut_slice()
+        }
+    }
+
+    fn flush_range(&mut self, offset: usize, len: usize) -> io::Result<()> {
+        match *self {
+            Storage::Mapped(ref mut m) => m.flush_range(offset, len),
+            Storage::Archived(_) => Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Storage::Mapped(ref mut m) => m.flush(),
+            Storage::Archived(_) => Ok(())
+        }
+    }
+}
+
+pub struct WalFile<T> where T: WalEntry {
     f: File,
-    mem: Mmap,
+    mem: Storage,
     cursor: usize,
-    capacity: usize
+    capacity: usize,
+    // End of the committed entries according to the header, or None for files without one
+    committed: Option<usize>,
+    // Schema version of the entries in this file
+    version: u32,
+    phantom: PhantomData<T>
 }
 
-impl WalFile {
+impl<T> WalFile<T> where T: WalEntry {
     fn open_impl<P: AsRef<Path>>(path: P, size: usize, create: bool, writable: bool)
                 -> Result<Self, String> {
+        if let Some(format) = ArchiveFormat::from_path(path.as_ref()) {
+            if create || writable {
+                return Err(format!("{} is archived and can't be written to",
+                                   path.as_ref().display()));
+            }
+
+            let f = try!(File::open(path.as_ref()).map_err(|e| {
+                "failed to open file".to_string()
+            }));
+            let bytes = try!(format.decompress(&f).map_err(|e| {
+                format!("failed to decompress file ({})", e.description())
+            }));
+
+            return Self::from_storage(f, Storage::Archived(bytes), false);
+        }
+
         let f = try!(OpenOptions::new().create_new(create).read(true).write(writable)
                      .open(path.as_ref()).map_err(|e| {
             "failed to create file".to_string()
         }));
 
-        let mut file_size = size;
-
         if create {
-            try!(f.set_len(file_size as u64).map_err(|e| {
+            try!(f.set_len(size as u64).map_err(|e| {
                 "failed to size file".to_string()
             }));
-        } else {
-            file_size = try!(f.metadata().map_err(|e| {
-                "failed to read file size".to_string()
-            })).len() as usize;
         }
 
         let prot = if writable {
@@ -59,23 +317,170 @@ impl WalFile {
             format!("failed to map file ({})", e.description())
         }));
 
-        Ok(WalFile {
+        Self::from_storage(f, Storage::Mapped(mem), create)
+    }
+
+    fn from_storage(f: File, mem: Storage, create: bool) -> Result<Self, String> {
+        let file_size = mem.len();
+        let mut wal = WalFile {
             f: f,
             mem: mem,
             cursor: 0 as usize,
-            capacity: file_size
-        })
+            capacity: file_size,
+            committed: None,
+            version: 0,
+            phantom: PhantomData
+        };
+
+        if create {
+            wal.cursor = WAL_HEADER_SIZE;
+            wal.committed = Some(WAL_HEADER_SIZE);
+            wal.version = T::SCHEMA_VERSION;
+            try!(wal.write_header());
+        } else if let Some(header) = wal.read_header() {
+            if header.version > T::SCHEMA_VERSION {
+                return Err(format!("wal schema version {} is newer than this build supports ({})",
+                                   header.version, T::SCHEMA_VERSION));
+            }
+
+            if header.bytes_used as usize > file_size {
+                return Err(format!("wal header claims {} bytes in a {} byte file",
+                                   header.bytes_used, file_size));
+            }
+
+            wal.cursor = WAL_HEADER_SIZE;
+            wal.committed = Some(header.bytes_used as usize);
+            wal.version = header.version;
+        }
+
+        Ok(wal)
+    }
+
+    fn read_header(&self) -> Option<WalHeader> {
+        if self.capacity < WAL_HEADER_SIZE {
+            return None;
+        }
+
+        let header = deserialize::<WalHeader>(&(unsafe { self.mem.as_slice() }[..WAL_HEADER_SIZE]));
+        // Pairs with the fence in write_header so that everything the header covers is visible
+        fence(Ordering::Acquire);
+
+        match header {
+            Ok(h) => if h.magic == WAL_MAGIC { Some(h) } else { None },
+            Err(_) => None
+        }
+    }
+
+    fn write_header(&mut self) -> Result<(), String> {
+        let header = WalHeader {
+            magic: WAL_MAGIC,
+            version: self.version,
+            bytes_used: self.committed.unwrap_or(self.cursor) as u64
+        };
+        let bytes = try!(serialize(&header, Bounded(WAL_HEADER_SIZE as u64)).map_err(|e| {
+            format!("failed to serialize wal header: {}", e.description())
+        }));
+
+        // Entries have to land before the header that claims them
+        fence(Ordering::Release);
+
+        {
+            let raw_bytes = unsafe { self.mem.as_mut_slice() };
+            raw_bytes[..bytes.len()].clone_from_slice(bytes.as_slice());
+        }
+
+        try!(self.mem.flush_range(0, bytes.len()).map_err(|e| {
+            format!("failed to flush wal header ({})", e.description())
+        }));
+
+        Ok(())
+    }
+
+    // Moves the header up to cover everything written since it was last updated
+    fn commit(&mut self) -> Result<(), String> {
+        match self.committed {
+            Some(committed) if committed != self.cursor => {
+                self.committed = Some(self.cursor);
+                self.write_header()
+            },
+            _ => Ok(())
+        }
+    }
+
+    // Picks up entries committed by a writer since the file was opened
+    pub fn refresh(&mut self) {
+        if self.committed.is_some() {
+            self.committed = self.read_header().map(|h| h.bytes_used as usize);
+        }
+    }
+
+    // Throws away everything from `offset` onwards.  The file has to have been opened writable.
+    pub fn truncate(&mut self, offset: usize) -> Result<(), String> {
+        let start = if self.committed.is_some() { WAL_HEADER_SIZE } else { 0 };
+        if offset < start || offset > self.capacity {
+            return Err(format!("can't truncate a {} byte file at {}", self.capacity, offset));
+        }
+
+        {
+            let raw_bytes = unsafe { self.mem.as_mut_slice() };
+            for b in raw_bytes[offset..].iter_mut() {
+                *b = 0;
+            }
+        }
+
+        try!(self.mem.flush().map_err(|e| {
+            format!("failed to flush truncated file ({})", e.description())
+        }));
+
+        self.cursor = offset;
+        if self.committed.is_some() {
+            self.committed = Some(offset);
+            try!(self.write_header());
+        }
+
+        Ok(())
+    }
+
+    // End of the committed entries, which for files without a header is only known once they've
+    // been read through
+    pub fn committed(&self) -> usize {
+        self.committed.unwrap_or(self.capacity)
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Whether the file records how much of it has been written
+    pub fn has_header(&self) -> bool {
+        self.committed.is_some()
     }
 
     fn create<P: AsRef<Path>>(path: P, size: usize) -> Result<Self, String> {
         Self::open_impl(path, size, true, true)
     }
 
+    // Touch every page so that the first writes to each one don't have to fault it in
+    fn prefault(&mut self) {
+        let raw_bytes = unsafe { self.mem.as_mut_slice() };
+        let mut offset = 0;
+
+        // Write back whatever is there (the first page has the header in it), volatile so that it
+        // isn't optimized away
+        while offset < raw_bytes.len() {
+            unsafe {
+                let b = ptr::read_volatile(&raw_bytes[offset]);
+                ptr::write_volatile(&mut raw_bytes[offset], b);
+            }
+            offset += PAGE_SIZE;
+        }
+    }
+
     pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> Result<Self, String> {
         Self::open_impl(path, 0, false, writable)
     }
 
-    fn write_entry(&mut self, entry: &EngineMessage) -> WriteResult {
+    fn write_entry(&mut self, entry: &T) -> WriteResult {
         match serialize(entry, Bounded((self.capacity - self.cursor) as u64)) {
             Ok(bytes) => {
                 {
@@ -83,8 +488,12 @@ impl WalFile {
                     raw_bytes[self.cursor..(self.cursor + bytes.len())].clone_from_slice(bytes.as_slice());
                 }
 
-                self.mem.flush_range(self.cursor, bytes.len());
+                if let Err(e) = self.mem.flush_range(self.cursor, bytes.len()) {
+                    return WriteResult::WriteError(format!("failed to flush entry ({})",
+                                                           e.description()));
+                }
                 self.cursor += bytes.len();
+
                 WriteResult::Success
             },
             Err(e) => {
@@ -96,103 +505,246 @@ impl WalFile {
         }
     }
 
-    fn advance_entry(&mut self) -> Option<Result<EngineMessage, String>> {
-        if self.cursor == self.capacity {
+    // Writes back every dirty page along with the header and waits for it all to reach the disk
+    fn sync(&mut self) -> Result<(), String> {
+        try!(self.mem.flush().map_err(|e| {
+            format!("failed to flush file ({})", e.description())
+        }));
+
+        try!(self.commit());
+
+        self.f.sync_all().map_err(|e| format!("failed to sync file ({})", e.description()))
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn advance_entry(&mut self) -> Option<Result<T, String>> {
+        let end = self.committed.unwrap_or(self.capacity);
+
+        if self.cursor >= end {
             return None;
         }
 
-        match deserialize::<EngineMessage>(&(unsafe { self.mem.as_mut_slice() }[self.cursor..self.capacity])) {
-            Ok(ref msg) => {
-                // This is a very hacky way of checking for the end of the log.
-                // Really we should track in a header how far we've written or something like that
-                // but this will match zeroed out memory and tell us where to stop reading.
-                if let EngineMessage::NullMessage = *msg {
+        // Decoding advances the slice past the entry, which tells us how big it was even if it was
+        // written in an older format
+        let (decoded, remaining) = {
+            let mut bytes = &(unsafe { self.mem.as_slice() }[self.cursor..end]);
+            let decoded = T::decode(self.version, &mut bytes);
+            (decoded, bytes.len())
+        };
+
+        match decoded {
+            Ok(msg) => {
+                // Files with a header stop at the committed end above, but older files have
+                // nothing else to go on.  This will match zeroed out memory and tell us where to
+                // stop reading.
+                if msg.is_null() {
                     None
                 } else {
-                    // Is this really the best way to advance the cursor?
-                    // I don't see anything in the bincode documentation that provides the byte count
-                    // as part of the deserialization call
-                    self.cursor += serialized_size(msg) as usize;
-                    //Some(Ok((*msg).clone()))
-                    Some(Ok((*msg).clone()))
+                    self.cursor = end - remaining;
+                    Some(Ok(msg))
                 }
             },
             Err(e) => {
-                Some(Err(format!("invalid read at position {}: {}",
-                                 self.cursor, e.description())))
+                Some(Err(format!("invalid read at position {}: {}", self.cursor, e)))
             }
         }
     }
 
     fn advance_to_end(&mut self) -> Result<(), String> {
-        self.last().map(|msg| {
-            msg.map(|_| ())
-        }).unwrap_or(Ok(()))
+        // A failed read doesn't move the cursor, so stop at the first one
+        while let Some(msg) = self.next() {
+            try!(msg);
+        }
+
+        Ok(())
     }
 }
 
-impl Iterator for WalFile {
-    type Item = Result<EngineMessage, String>;
+impl<T> Iterator for WalFile<T> where T: WalEntry {
+    type Item = Result<T, String>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.advance_entry()
     }
 }
 
-pub struct Wal {
-    dir: PathBuf,
+// Where the next file is prepared.  It doesn't match the wal_N pattern so nothing treats it as part
+// of the log until it's renamed into place.
+fn prepared_path<P: AsRef<Path>>(dir: P, index: u32) -> PathBuf {
+    dir.as_ref().join(format!("wal_{}.next", index))
+}
+
+// Creates, sizes and faults in the next file on a background thread so that rotating the log only
+// has to rename it into place
+struct Preparer<T> where T: WalEntry {
     index: u32,
-    file_size: usize,
-    // For now just use one file and rotate as needed
-    // In the future we might want to have a background thread that rotates logs
-    // and prepares upcoming files in advance.
-    wal: WalFile
+    rx: mpsc::Receiver<Result<WalFile<T>, String>>
 }
 
-impl Wal {
-    fn next_file<P: AsRef<Path>>(dir: P, file_size: usize, start_index: u32) ->
-            Result<(WalFile, u32), String> {
-        let mut index = start_index;
-        loop {
-            let wal_path = dir.as_ref().join(format!("wal_{}", index));
-            let path_name = wal_path.to_str().unwrap_or("<unknown>").to_string();
-
-            if wal_path.exists() {
-                // XXX: If there's still room in the log, reuse it
-                // This would require either maintaining the log size in the log
-                // file itself, or reading through to find the first available
-                // location.
-                println!("wal already exists at {}", path_name);
-                index += 1;
-                continue;
+impl<T> Preparer<T> where T: WalEntry {
+    fn start(dir: &Path, file_size: usize, index: u32) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let path = prepared_path(dir, index);
+
+        thread::spawn(move || {
+            // Left over from a previous run that stopped before using it
+            if path.exists() {
+                let _ = remove_file(path.as_path());
             }
 
-            let wal = try!(WalFile::create(wal_path, file_size).map_err(|e| {
-                format!("failed to rotate wal to {}: {}", path_name, e)
-            }));
+            let prepared = WalFile::create(path.as_path(), file_size).map(|mut wal| {
+                wal.prefault();
+                wal
+            });
 
-            println!("opened wal at {}", path_name);
+            // Nobody is waiting if the log was closed first
+            let _ = tx.send(prepared);
+        });
 
-            return Ok((wal, index + 1))
+        Preparer {
+            index: index,
+            rx: rx
         }
     }
 
+    // Waits for the file if it isn't ready yet and moves it into place as wal_N
+    fn finish(self, dir: &Path) -> Result<WalFile<T>, String> {
+        let wal = try!(try!(self.rx.recv().map_err(|_| {
+            "wal preparer thread died".to_string()
+        })));
+
+        let path = dir.join(format!("wal_{}", self.index));
+        if path.exists() {
+            let _ = remove_file(prepared_path(dir, self.index));
+            return Err(format!("{} already exists", path.display()));
+        }
+
+        try!(rename(prepared_path(dir, self.index), path.as_path()).map_err(|e| {
+            format!("failed to move prepared wal file into place: {}", e)
+        }));
+
+        Ok(wal)
+    }
+}
+
+pub struct Wal<T> where T: WalEntry {
+    dir: PathBuf,
+    // Index of the file currently being written
+    index: u32,
+    config: WalConfig,
+    wal: WalFile<T>,
+    // Getting the file after this one ready
+    next: Option<Preparer<T>>
+}
+
+impl<T> Wal<T> where T: WalEntry {
+    // Creates exactly wal_<index>.  Skipping ahead would leave a gap in the log, so an existing
+    // file is an error.
+    fn next_file<P: AsRef<Path>>(dir: P, file_size: usize, index: u32) ->
+            Result<WalFile<T>, String> {
+        let wal_path = dir.as_ref().join(format!("wal_{}", index));
+        let path_name = wal_path.to_str().unwrap_or("<unknown>").to_string();
+
+        if segment_path(dir.as_ref(), index).exists() {
+            return Err(format!("wal already exists at {}", path_name));
+        }
+
+        let wal = try!(WalFile::create(wal_path, file_size).map_err(|e| {
+            format!("failed to create wal at {}: {}", path_name, e)
+        }));
+
+        println!("opened wal at {}", path_name);
+
+        Ok(wal)
+    }
+
+    // Opens the newest file to carry on writing where it left off.  Returns None if entries can't
+    // be appended to it, in which case the caller starts the next file instead.  Anything that
+    // leaves the end of the log unclear is an error rather than a reason to start a new file,
+    // since whatever follows would be ambiguous.
+    fn resume<P: AsRef<Path>>(dir: P, index: u32) -> Result<Option<WalFile<T>>, String> {
+        // Archiving only happens after rotating away from a file so the next one must have been
+        // lost after that
+        if is_archived(dir.as_ref(), index) {
+            println!("wal file {} has already been archived, starting wal file {}", index,
+                     index + 1);
+            return Ok(None);
+        }
+
+        let mut wal: WalFile<T> = try!(open_file(dir.as_ref(), index, true).map_err(|e| {
+            format!("can't open newest wal file {}: {}", index, e)
+        }));
+
+        // Entries in a file all have to be in the same format
+        if wal.version != T::SCHEMA_VERSION {
+            println!("wal file {} uses schema version {} instead of {}, starting wal file {}",
+                     index, wal.version, T::SCHEMA_VERSION, index + 1);
+            return Ok(None);
+        }
+
+        try!(wal.advance_to_end().map_err(|e| {
+            format!("wal file {} is damaged ({}), run walcheck --repair", index, e)
+        }));
+
+        if wal.cursor != wal.committed() {
+            return Err(format!("wal file {} claims {} bytes but its entries end at {}, run \
+                                walcheck --repair", index, wal.committed(), wal.cursor));
+        }
+
+        println!("resuming wal file {} at position {}/{}", index, wal.cursor, wal.capacity);
+
+        Ok(Some(wal))
+    }
+
     fn rotate(&mut self) -> Result<(), String> {
         println!("rotating wal file from {}", self.index);
 
+        // Nothing commits this file once it's been rotated away from
+        try!(self.wal.commit());
+
+        let prepared = match self.next.take() {
+            Some(ref p) if p.index != self.index + 1 => None,
+            Some(p) => match p.finish(self.dir.as_path()) {
+                Ok(wal) => Some((wal, self.index + 1)),
+                Err(e) => {
+                    println!("couldn't use prepared wal file: {}", e);
+                    None
+                }
+            },
+            None => None
+        };
+
         // File and Mmap both automatically clean up when they go out of scope
-        let (next_wal, next_index) = try!(Self::next_file(self.dir.as_path(), self.file_size,
-                                                          self.index));
+        let (next_wal, next_index) = match prepared {
+            Some(p) => p,
+            None => (try!(Self::next_file(self.dir.as_path(), self.config.segment_size,
+                                          self.index + 1)),
+                     self.index + 1)
+        };
 
         println!("rotated wal file to {}", next_index);
 
+        let closed = self.index;
         self.wal = next_wal;
         self.index = next_index;
+        self.next = Some(Preparer::start(self.dir.as_path(), self.config.segment_size,
+                                         next_index + 1));
+
+        if let Some(format) = self.config.archive {
+            archive_in_background::<T>(self.dir.as_path(), closed, format);
+        }
 
         Ok(())
     }
 
-    pub fn new<P: AsRef<Path>>(dir: P, file_size: usize) -> Result<Self, String> {
+    pub fn new<P: AsRef<Path>>(dir: P, config: WalConfig) -> Result<Self, String> {
         if !dir.as_ref().is_dir() {
             return Err("directory does not exist".to_string());
         }
@@ -200,40 +752,54 @@ impl Wal {
         let mut dir_buf = PathBuf::new();
         dir_buf.push(dir.as_ref());
 
-        let (wal_file, first_index) = try!(try!(Wal::get_all_files(dir.as_ref())).iter().last().map(|index| {
-            println!("opening most recent wal file {}", *index);
-            (Wal::open_file(dir.as_ref(), *index, true), *index)
-        }).and_then(|(wal, index)| {
-            match wal.map(|mut w| { w.advance_to_end(); w }) {
-                Ok(w) => {
-                    println!("resuming wal file {} at position {}/{}", index, w.cursor, w.capacity);
-                    Some(Ok((w, index)))
-                },
-                Err(e) => None
+        let file_size = config.segment_size;
+        let files = try!(get_all_files(dir.as_ref()));
+
+        let (wal_file, first_index) = match files.last() {
+            None => {
+                println!("no wal files in {}, creating the first one", dir_buf.display());
+                (try!(Self::next_file(dir_buf.as_path(), file_size, 0u32)), 0u32)
+            },
+            Some(&index) => match try!(Self::resume(dir_buf.as_path(), index)) {
+                Some(wal) => (wal, index),
+                None => (try!(Self::next_file(dir_buf.as_path(), file_size, index + 1)), index + 1)
             }
-        }).unwrap_or_else(|| {
-            println!("creating new wal file");
-            Self::next_file(dir_buf.as_path(), file_size, 0u32)
-        }));
+        };
 
-        let mut wal = Wal {
+        // Catch up on closed files that didn't get archived before the last shutdown
+        if let Some(format) = config.archive {
+            for &index in files.iter().filter(|&&i| i < first_index) {
+                if !is_archived(dir_buf.as_path(), index) {
+                    archive_in_background::<T>(dir_buf.as_path(), index, format);
+                }
+            }
+        }
+
+        let next = Preparer::start(dir_buf.as_path(), file_size, first_index + 1);
+
+        Ok(Wal {
             dir: dir_buf,
             index: first_index,
-            file_size: file_size,
-            wal: wal_file
-        };
-
-        Ok(wal)
+            config: config,
+            wal: wal_file,
+            next: Some(next)
+        })
     }
 
-    pub fn write_entry(&mut self, entry: &EngineMessage) -> Result<(), String> {
+    // Returns the position at which the entry was written.  The entry is on disk by then but
+    // isn't committed until the next call to `commit`; readers don't see it before that and it's
+    // dropped on restart if the process dies first.
+    pub fn write_entry(&mut self, entry: &T) -> Result<WalPosition, String> {
+        let mut position = self.position();
+
         match self.wal.write_entry(entry) {
-            WriteResult::Success => Ok(()),
+            WriteResult::Success => Ok(position),
             WriteResult::WriteError(s) => Err(s),
             WriteResult::LogFull => {
                 try!(self.rotate());
+                position = self.position();
                 match self.wal.write_entry(entry) {
-                    WriteResult::Success => Ok(()),
+                    WriteResult::Success => Ok(position),
                     WriteResult::WriteError(s) => Err(s),
                     WriteResult::LogFull => Err("log files too small for entry".to_string())
                 }
@@ -241,64 +807,181 @@ impl Wal {
         }
     }
 
-    fn get_all_files<P: AsRef<Path>>(dir: P) -> Result<Vec<u32>, String> {
-        let path_name = dir.as_ref().to_str().unwrap_or("<unknown>").to_string();
-        let dir_iter: ReadDir = try!(read_dir(dir).map_err(|e| {
-            format!("failed to walk directory {}", path_name)
+    // Rewrites the header to cover every entry written so far.  Each commit costs an msync on top
+    // of the one each entry gets when it's written, so callers writing a batch of entries should
+    // commit once at the end of it rather than after each one, and only tell anyone about the
+    // entries afterwards.
+    pub fn commit(&mut self) -> Result<(), String> {
+        self.wal.commit()
+    }
+
+    // Makes sure everything written so far is on disk and the header says so, and throws away the
+    // file that was being prepared.  Returns the position the next entry would have gone at.
+    pub fn close(mut self) -> Result<WalPosition, String> {
+        let position = self.position();
+
+        try!(self.wal.sync().map_err(|e| {
+            format!("failed to close wal file {}: {}", self.index, e)
         }));
 
-        let wal_regex = Regex::new(r"^wal_(\d+)$").unwrap();
-        let mut wal_files: Vec<u32> = dir_iter.filter_map(|item| {
-            let entry = item.unwrap();
-            if entry.file_type().unwrap().is_file() {
-                wal_regex.captures(entry.path().file_name().unwrap().to_str().unwrap()).map(|c| {
-                    u32::from_str(&c[1]).unwrap()
-                })
-            } else {
-                None
+        if let Some(next) = self.next.take() {
+            // Wait for it so that the preparer thread doesn't recreate it after it's removed
+            let _ = next.rx.recv();
+            let _ = remove_file(prepared_path(self.dir.as_path(), next.index));
+        }
+
+        println!("closed wal in {} at {}", self.dir.display(), position);
+
+        Ok(position)
+    }
+
+    // Position at which the next entry will be written
+    pub fn position(&self) -> WalPosition {
+        WalPosition {
+            index: self.index,
+            offset: self.wal.cursor() as u64
+        }
+    }
+}
+
+// This doesn't depend on the entry type so leave it out of the generic impls to avoid having to
+// name one at every call site
+pub fn get_all_files<P: AsRef<Path>>(dir: P) -> Result<Vec<u32>, String> {
+    let path_name = dir.as_ref().to_str().unwrap_or("<unknown>").to_string();
+    let dir_iter: ReadDir = try!(read_dir(dir).map_err(|e| {
+        format!("failed to walk directory {}", path_name)
+    }));
+
+    // Archived files count too.  A file can briefly exist in both forms while it's being archived.
+    let wal_regex = Regex::new(r"^wal_(\d+)(\.gz|\.zlib)?$").unwrap();
+    let mut wal_files: Vec<u32> = dir_iter.filter_map(|item| {
+        let entry = item.unwrap();
+        if entry.file_type().unwrap().is_file() {
+            wal_regex.captures(entry.path().file_name().unwrap().to_str().unwrap()).map(|c| {
+                u32::from_str(&c[1]).unwrap()
+            })
+        } else {
+            None
+        }
+    }).collect();
+    wal_files.sort();
+    wal_files.dedup();
+
+    Ok(wal_files)
+}
+
+// Path to wal_<index> in whichever form it exists, preferring the uncompressed one
+pub fn segment_path<P: AsRef<Path>>(dir: P, index: u32) -> PathBuf {
+    let path = dir.as_ref().join(format!("wal_{}", index));
+
+    if !path.exists() {
+        for format in [ArchiveFormat::Gzip, ArchiveFormat::Zlib].iter() {
+            let archived = dir.as_ref().join(format!("wal_{}.{}", index, format.extension()));
+            if archived.exists() {
+                return archived;
             }
-        }).collect();
-        wal_files.sort();
+        }
+    }
+
+    path
+}
+
+pub fn is_archived<P: AsRef<Path>>(dir: P, index: u32) -> bool {
+    ArchiveFormat::from_path(segment_path(dir, index).as_path()).is_some()
+}
 
-        Ok(wal_files)
+pub fn open_file<P: AsRef<Path>, T: WalEntry>(dir: P, index: u32, writable: bool)
+        -> Result<WalFile<T>, String> {
+    match WalFile::open(segment_path(dir.as_ref(), index).as_path(), writable) {
+        // It may have been archived between finding it and opening it
+        Err(_) if !writable => WalFile::open(segment_path(dir.as_ref(), index).as_path(), false),
+        result => result
     }
+}
+
+// Replaces a closed file with a compressed copy of just the part that was written.  The copy is
+// moved into place before the original is removed so that readers can always find one or the
+// other.
+pub fn archive_file<P: AsRef<Path>, T: WalEntry>(dir: P, index: u32, format: ArchiveFormat)
+        -> Result<(), String> {
+    let path = dir.as_ref().join(format!("wal_{}", index));
+    let archived = dir.as_ref().join(format!("wal_{}.{}", index, format.extension()));
+    let staging = dir.as_ref().join(format!("wal_{}.{}.tmp", index, format.extension()));
+
+    let mut wal: WalFile<T> = try!(WalFile::open(path.as_path(), false));
+    try!(wal.advance_to_end());
 
-    fn open_file<P: AsRef<Path>>(dir: P, index: u32, writable: bool) -> Result<WalFile, String> {
-        let mut path = Path::new(dir.as_ref()).to_path_buf();
-        let basename = format!("wal_{}", index);
+    let out = try!(File::create(staging.as_path()).map_err(|e| {
+        format!("failed to create {}: {}", staging.display(), e)
+    }));
+    try!(format.compress(&(unsafe { wal.mem.as_slice() }[..wal.cursor]), out).map_err(|e| {
+        format!("failed to compress {}: {}", path.display(), e)
+    }));
 
-        path.push(basename);
+    try!(rename(staging.as_path(), archived.as_path()).map_err(|e| {
+        format!("failed to move {} into place: {}", archived.display(), e)
+    }));
+    try!(remove_file(path.as_path()).map_err(|e| {
+        format!("failed to remove {}: {}", path.display(), e)
+    }));
 
-        WalFile::open(path.as_path(), writable)
+    println!("archived {} to {}", path.display(), archived.display());
+
+    Ok(())
+}
+
+// Archive on a background thread, since compressing a whole file takes a while
+fn archive_in_background<T: WalEntry>(dir: &Path, index: u32, format: ArchiveFormat) {
+    let dir = dir.to_path_buf();
+
+    thread::spawn(move || {
+        if let Err(e) = archive_file::<_, T>(dir.as_path(), index, format) {
+            println!("failed to archive wal file {} in {}: {}", index, dir.display(), e);
+        }
+    });
+}
+
+// Position of the last entry in a log directory, or None if it's empty
+pub fn last_entry_position<P: AsRef<Path>>(dir: P) -> Result<Option<WalPosition>, String> {
+    let mut reader = try!(WalDirectoryReader::<EngineMessage>::new(dir));
+    let mut last = None;
+
+    while let Some(entry) = reader.next_entry() {
+        let (position, _) = try!(entry);
+        last = Some(position);
     }
+
+    Ok(last)
 }
 
-pub struct WalDirectoryReader {
+pub struct WalDirectoryReader<T> where T: WalEntry {
     dir: OsString,
     files: Vec<u32>,
     file_index: usize,
-    reader: Option<WalFile>
+    reader: Option<WalFile<T>>
 }
 
-impl WalDirectoryReader {
+impl<T> WalDirectoryReader<T> where T: WalEntry {
     pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
         Ok(WalDirectoryReader {
             dir: dir.as_ref().as_os_str().to_os_string(),
-            files: try!(Wal::get_all_files(dir)),
+            files: try!(get_all_files(dir)),
             file_index: 0usize,
             reader: None
         })
     }
-}
-
-impl Iterator for WalDirectoryReader {
-    type Item = Result<EngineMessage, String>;
 
-    fn next(&mut self) -> Option<Self::Item> {
+    // Like `next` but also reports where the entry was read from
+    pub fn next_entry(&mut self) -> Option<Result<(WalPosition, T), String>> {
         loop {
             if let Some(ref mut reader) = self.reader {
+                let position = WalPosition {
+                    index: self.files[self.file_index - 1],
+                    offset: reader.cursor() as u64
+                };
+
                 if let Some(msg) = reader.next() {
-                    return Some(msg);
+                    return Some(msg.map(|m| (position, m)));
                 }
             }
 
@@ -306,8 +989,8 @@ impl Iterator for WalDirectoryReader {
                 return None;
             }
 
-            self.reader = Some(match Wal::open_file(Path::new(&self.dir),
-                                                    self.files[self.file_index], false) {
+            self.reader = Some(match open_file(Path::new(&self.dir),
+                                               self.files[self.file_index], false) {
                 Ok(r) => r,
                 Err(e) => {
                     return Some(Err(e));
@@ -320,3 +1003,136 @@ impl Iterator for WalDirectoryReader {
         unreachable!()
     }
 }
+
+impl<T> Iterator for WalDirectoryReader<T> where T: WalEntry {
+    type Item = Result<T, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|entry| entry.map(|(_, msg)| msg))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use messages::EngineMessage;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::path::PathBuf;
+    use std::process;
+    use super::*;
+
+    pub const CONFIG: WalConfig = WalConfig {
+        segment_size: 4096,
+        archive: None
+    };
+
+    // An empty directory for a test to write logs into.  Every binary that includes this module
+    // runs the same tests, so the process ID keeps them from sharing one.
+    pub fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cix_{}_{}", name, process::id()));
+        let _ = remove_dir_all(dir.as_path());
+        create_dir_all(dir.as_path()).unwrap();
+        dir
+    }
+
+    fn entry(seq: u32) -> EngineMessage {
+        EngineMessage::SerializationMessage(seq)
+    }
+
+    // Whatever the file has committed that hasn't been read yet
+    fn read_seqs(file: &mut WalFile<EngineMessage>) -> Vec<u32> {
+        file.by_ref().map(|e| match e.unwrap() {
+            EngineMessage::SerializationMessage(seq) => seq,
+            msg => panic!("unexpected entry {:?}", msg)
+        }).collect()
+    }
+
+    #[test]
+    fn entries_are_only_visible_once_committed() {
+        let dir = scratch_dir("wal_commit");
+        let mut wal = Wal::<EngineMessage>::new(dir.as_path(), CONFIG).unwrap();
+        wal.write_entry(&entry(1)).unwrap();
+        wal.write_entry(&entry(2)).unwrap();
+
+        let mut reader: WalFile<EngineMessage> = open_file(dir.as_path(), 0, false).unwrap();
+        assert!(reader.has_header());
+        assert_eq!(reader.version(), EngineMessage::SCHEMA_VERSION);
+        assert_eq!(reader.committed(), WAL_HEADER_SIZE);
+        assert!(read_seqs(&mut reader).is_empty());
+
+        wal.commit().unwrap();
+        reader.refresh();
+        assert_eq!(read_seqs(&mut reader), vec![1, 2]);
+
+        // Closing commits too
+        wal.write_entry(&entry(3)).unwrap();
+        let end = wal.close().unwrap();
+        reader.refresh();
+        assert_eq!(read_seqs(&mut reader), vec![3]);
+        assert_eq!(reader.cursor() as u64, end.offset);
+        assert_eq!(reader.committed(), reader.cursor());
+    }
+
+    #[test]
+    fn rotating_commits_the_old_file() {
+        let dir = scratch_dir("wal_rotate");
+        let mut wal = Wal::<EngineMessage>::new(dir.as_path(), CONFIG).unwrap();
+
+        let mut seq = 0;
+        while wal.position().index == 0 {
+            wal.write_entry(&entry(seq)).unwrap();
+            seq += 1;
+        }
+
+        // The last entry went into the second file, which hasn't been committed
+        let mut first: WalFile<EngineMessage> = open_file(dir.as_path(), 0, false).unwrap();
+        assert_eq!(read_seqs(&mut first), (0..seq - 1).collect::<Vec<u32>>());
+
+        let mut second: WalFile<EngineMessage> = open_file(dir.as_path(), 1, false).unwrap();
+        assert!(read_seqs(&mut second).is_empty());
+
+        wal.close().unwrap();
+        second.refresh();
+        assert_eq!(read_seqs(&mut second), vec![seq - 1]);
+    }
+
+    #[test]
+    fn reopening_resumes_at_the_committed_tail() {
+        let dir = scratch_dir("wal_resume");
+
+        let mut wal = Wal::<EngineMessage>::new(dir.as_path(), CONFIG).unwrap();
+        wal.write_entry(&entry(1)).unwrap();
+        wal.write_entry(&entry(2)).unwrap();
+        let end = wal.close().unwrap();
+
+        let mut wal = Wal::<EngineMessage>::new(dir.as_path(), CONFIG).unwrap();
+        assert_eq!(wal.position(), end);
+        wal.write_entry(&entry(3)).unwrap();
+        wal.close().unwrap();
+
+        assert_eq!(get_all_files(dir.as_path()).unwrap(), vec![0]);
+        let mut file: WalFile<EngineMessage> = open_file(dir.as_path(), 0, false).unwrap();
+        assert_eq!(read_seqs(&mut file), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn uncommitted_entries_are_dropped_on_restart() {
+        let dir = scratch_dir("wal_crash");
+
+        let mut wal = Wal::<EngineMessage>::new(dir.as_path(), CONFIG).unwrap();
+        wal.write_entry(&entry(1)).unwrap();
+        wal.commit().unwrap();
+        let committed = wal.position();
+        // Never committed, as if the process died before the end of the batch
+        wal.write_entry(&entry(2)).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::<EngineMessage>::new(dir.as_path(), CONFIG).unwrap();
+        assert_eq!(wal.position(), committed);
+        wal.write_entry(&entry(3)).unwrap();
+        wal.close().unwrap();
+
+        let mut file: WalFile<EngineMessage> = open_file(dir.as_path(), 0, false).unwrap();
+        assert_eq!(read_seqs(&mut file), vec![1, 3]);
+    }
+}
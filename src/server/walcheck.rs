@@ -0,0 +1,292 @@
+extern crate bincode;
+extern crate flate2;
+extern crate futures;
+extern crate libcix;
+extern crate memmap;
+extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate time;
+extern crate tokio_core;
+extern crate toml;
+
+mod config;
+mod engine;
+mod journal;
+mod messages;
+mod replication;
+mod ring;
+mod risk;
+mod transport;
+mod wal;
+
+use config::ServerConfig;
+use libcix::book::BasicMatcher;
+use libcix::order::trade_types::*;
+use messages::{EngineMessage, JournalEntry};
+use std::collections::HashMap;
+use std::env::args;
+use std::fs::remove_file;
+use std::io::{stdin, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use wal::{WalEntry, WalFile, WalPosition};
+
+struct CheckArgs {
+    wal_dir: PathBuf,
+    symbols: Vec<Symbol>,
+    repair: bool,
+    // Don't ask before truncating
+    assume_yes: bool
+}
+
+fn parse_symbols<'a, I: Iterator<Item=&'a str>>(names: I) -> Result<Vec<Symbol>, String> {
+    names.map(|s| Symbol::from_str(s).map_err(|_| format!("invalid symbol {}", s))).collect()
+}
+
+fn parse_args() -> Result<CheckArgs, String> {
+    let mut parsed = CheckArgs {
+        wal_dir: PathBuf::new(),
+        symbols: Vec::new(),
+        repair: false,
+        assume_yes: false
+    };
+    let mut wal_dir = None;
+    // Symbols given on the command line win over the config file's, wherever they appear
+    let mut listed = None;
+    let mut configured = None;
+
+    let mut cli_args = args();
+    cli_args.next();
+
+    while let Some(flag) = cli_args.next() {
+        let mut value = || cli_args.next().ok_or(format!("{} requires a value", flag));
+
+        match flag.as_str() {
+            "--config" => {
+                let config = try!(ServerConfig::load(Path::new(try!(value()).as_str())));
+                configured = Some(try!(parse_symbols(config.symbols.iter().map(|s| {
+                    s.name.as_str()
+                }))));
+            },
+            "--symbols" => { listed = Some(try!(parse_symbols(try!(value()).split(',')))); },
+            "--repair" => { parsed.repair = true; },
+            "--yes" => { parsed.assume_yes = true; },
+            _ if flag.starts_with("--") => {
+                return Err(format!("unrecognized argument {}", flag));
+            },
+            _ => { wal_dir = Some(PathBuf::from(flag.clone())); }
+        }
+    }
+
+    parsed.wal_dir = try!(wal_dir.ok_or("no wal directory given".to_string()));
+    // Has to match what the server was started with since symbol IDs are positional
+    parsed.symbols = try!(listed.or(configured).ok_or(
+        "give the server's symbols with --config or --symbols".to_string()));
+
+    Ok(parsed)
+}
+
+// Results of reading every segment in order
+struct ScanResult {
+    segments: Vec<u32>,
+    entries: usize,
+    problems: Vec<String>,
+    // First entry that couldn't be read.  Everything from here on is lost.
+    damaged: Option<(WalPosition, String)>
+}
+
+fn scan_segments(dir: &Path) -> Result<ScanResult, String> {
+    let mut result = ScanResult {
+        segments: try!(wal::get_all_files(dir)),
+        entries: 0,
+        problems: Vec::new(),
+        damaged: None
+    };
+    // Last order sequence number seen for each symbol ID
+    let mut order_seqs: HashMap<u32, u64> = HashMap::new();
+
+    for pair in result.segments.windows(2) {
+        if pair[1] != pair[0] + 1 {
+            result.problems.push(format!("segments wal_{} to wal_{} are missing",
+                                         pair[0] + 1, pair[1] - 1));
+        }
+    }
+
+    for &index in result.segments.iter() {
+        let mut file: WalFile<EngineMessage> = match wal::open_file(dir, index, false) {
+            Ok(f) => f,
+            Err(e) => {
+                result.damaged = Some((WalPosition { index: index, offset: 0 }, e));
+                break;
+            }
+        };
+
+        loop {
+            let position = WalPosition {
+                index: index,
+                offset: file.cursor() as u64
+            };
+
+            let msg = match file.next() {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    result.damaged = Some((position, e));
+                    break;
+                },
+                None => break
+            };
+
+            result.entries += 1;
+
+            if let EngineMessage::NewOrder(ref order) = msg {
+                let symbol_id = order.order_id.symbol_id();
+                let seq = order.order_id.sequence();
+
+                if let Some(&last) = order_seqs.get(&symbol_id) {
+                    if seq <= last {
+                        result.problems.push(format!(
+                                "{}: order {} has sequence {} after {} for symbol {}",
+                                position, order.order_id, seq, last, order.symbol));
+                    }
+                }
+
+                order_seqs.insert(symbol_id, seq);
+            }
+        }
+
+        if result.damaged.is_some() {
+            break;
+        }
+    }
+
+    Ok(result)
+}
+
+fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+// Drops everything from `position` onwards, including any later segments
+fn truncate_from<T: WalEntry>(dir: &Path, segments: &Vec<u32>, position: WalPosition)
+        -> Result<(), String> {
+    let mut file: WalFile<T> = try!(wal::open_file(dir, position.index, true));
+    try!(file.truncate(position.offset as usize));
+    println!("truncated {}/wal_{} at offset {}", dir.display(), position.index, position.offset);
+
+    for &index in segments.iter().filter(|&&i| i > position.index) {
+        let path = wal::segment_path(dir, index);
+        try!(remove_file(path.as_path()).map_err(|e| {
+            format!("failed to remove {}: {}", path.display(), e)
+        }));
+        println!("removed {}", path.display());
+    }
+
+    Ok(())
+}
+
+// The output journal can't refer to input entries that no longer exist, so cut it back to the
+// first output produced by anything at or after `position`
+fn truncate_journal(dir: &Path, position: WalPosition) -> Result<(), String> {
+    let journal_dir = dir.join("journal");
+    if !journal_dir.is_dir() {
+        return Ok(());
+    }
+
+    let segments = try!(wal::get_all_files(journal_dir.as_path()));
+
+    for &index in segments.iter() {
+        let mut file: WalFile<JournalEntry> = try!(wal::open_file(journal_dir.as_path(), index,
+                                                                  false));
+        loop {
+            let offset = file.cursor();
+
+            let cut = WalPosition { index: index, offset: offset as u64 };
+
+            match file.next() {
+                Some(Ok(entry)) => {
+                    if entry.input >= position {
+                        return truncate_from::<JournalEntry>(journal_dir.as_path(), &segments,
+                                                             cut);
+                    }
+                },
+                Some(Err(e)) => {
+                    println!("journal is damaged at {}: {}", cut, e);
+                    return truncate_from::<JournalEntry>(journal_dir.as_path(), &segments, cut);
+                },
+                None => break
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// usage: walcheck (--config <server config> | --symbols SYM,SYM,...) [--repair [--yes]]
+//                 <engine wal directory>
+// Reads every segment in order, checking that none are missing, that every entry decodes and that
+// order sequence numbers only go up, then replays the log into a scratch engine to catch entries
+// that only make sense in context.  With --repair a damaged tail is truncated after confirmation.
+// Exits non-zero if anything is wrong and wasn't repaired.
+fn main() {
+    let check_args = parse_args().unwrap_or_else(|e| {
+        panic!("invalid arguments: {}", e)
+    });
+    let dir = check_args.wal_dir.as_path();
+
+    let scan = scan_segments(dir).unwrap_or_else(|e| {
+        panic!("failed to read {}: {}", dir.display(), e)
+    });
+    println!("read {} entries from {} segments", scan.entries, scan.segments.len());
+
+    for problem in scan.problems.iter() {
+        println!("{}", problem);
+    }
+
+    // Replay stops at the first unreadable entry, which is exactly what the server would do too
+    // Symbol IDs are positions in the server's symbol list.  Giving the scratch engine every
+    // symbol means this works for any engine's directory no matter how symbols are sharded.
+    let symbol_ids = check_args.symbols.iter().enumerate().map(|(i, s)| (*s, i as u32)).collect();
+    let replay = engine::check_replay(&symbol_ids, BasicMatcher{}, dir).unwrap_or_else(|e| {
+        panic!("failed to replay {}: {}", dir.display(), e)
+    });
+    println!("replayed {} entries", replay.replayed);
+
+    for &(position, ref problem) in replay.problems.iter() {
+        println!("{}: {}", position, problem);
+    }
+
+    let mut sound = scan.problems.is_empty() && replay.problems.is_empty();
+
+    if let Some((position, ref e)) = scan.damaged {
+        println!("log is unreadable from {}: {}", position, e);
+
+        let repaired = if check_args.repair &&
+                (check_args.assume_yes ||
+                 confirm(&format!("discard everything from {} onwards?", position))) {
+            match truncate_from::<EngineMessage>(dir, &scan.segments, position)
+                    .and_then(|_| truncate_journal(dir, position)) {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("repair failed: {}", e);
+                    false
+                }
+            }
+        } else {
+            false
+        };
+
+        sound = sound && repaired;
+    }
+
+    if sound {
+        println!("{} is sound", dir.display());
+    } else {
+        exit(1);
+    }
+}
@@ -0,0 +1,188 @@
+extern crate bincode;
+extern crate flate2;
+extern crate futures;
+extern crate libcix;
+extern crate memmap;
+extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate time;
+extern crate tokio_core;
+extern crate toml;
+
+mod config;
+mod engine;
+mod journal;
+mod messages;
+mod replication;
+mod ring;
+mod risk;
+mod transport;
+mod wal;
+
+use config::{ServerConfig, MIN_WAL_SEGMENT_SIZE};
+use messages::{EngineMessage, JournalEntry};
+use std::collections::HashMap;
+use std::env::args;
+use std::fs::{create_dir_all, rename};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use wal::{Wal, WalConfig, WalDirectoryReader, WalEntry, WalFile, WalPosition};
+
+struct MigrateArgs {
+    wal_dir: PathBuf,
+    segment_size: usize
+}
+
+fn parse_args() -> Result<MigrateArgs, String> {
+    let mut parsed = MigrateArgs {
+        wal_dir: PathBuf::new(),
+        segment_size: 0
+    };
+    let mut wal_dir = None;
+    // A size given on the command line wins over the config file's, wherever they appear
+    let mut listed = None;
+    let mut configured = None;
+
+    let mut cli_args = args();
+    cli_args.next();
+
+    while let Some(flag) = cli_args.next() {
+        let mut value = || cli_args.next().ok_or(format!("{} requires a value", flag));
+
+        match flag.as_str() {
+            "--config" => {
+                let config = try!(ServerConfig::load(Path::new(try!(value()).as_str())));
+                configured = Some(config.wal.segment_size);
+            },
+            "--segment-size" => {
+                listed = Some(try!(usize::from_str(try!(value()).as_str()).map_err(|_| {
+                    "invalid segment size".to_string()
+                })));
+            },
+            _ if flag.starts_with("--") => {
+                return Err(format!("unrecognized argument {}", flag));
+            },
+            _ => { wal_dir = Some(PathBuf::from(flag.clone())); }
+        }
+    }
+
+    parsed.wal_dir = try!(wal_dir.ok_or("no wal directory given".to_string()));
+    // Standbys have to end up with the same segment size as their primary, so don't guess
+    parsed.segment_size = try!(listed.or(configured).ok_or(
+        "give the server's segment size with --config or --segment-size".to_string()));
+
+    if parsed.segment_size < MIN_WAL_SEGMENT_SIZE {
+        return Err(format!("segment size has to be at least {} bytes", MIN_WAL_SEGMENT_SIZE));
+    }
+
+    Ok(parsed)
+}
+
+fn is_current<T: WalEntry>(dir: &Path) -> Result<bool, String> {
+    for index in try!(wal::get_all_files(dir)) {
+        let file: WalFile<T> = try!(wal::open_file(dir, index, false));
+        if file.version() != T::SCHEMA_VERSION {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Rewrites every entry in `src` into a fresh log in `dest`, passing each through `convert` first.
+// Returns where each entry ended up.
+fn copy_log<T, F>(src: &Path, dest: &Path, segment_size: usize, mut convert: F)
+        -> Result<HashMap<WalPosition, WalPosition>, String>
+        where T: WalEntry,
+              F: FnMut(WalPosition, T) -> Result<T, String> {
+    try!(create_dir_all(dest).map_err(|e| {
+        format!("failed to create {}: {}", dest.display(), e)
+    }));
+
+    let mut reader = try!(WalDirectoryReader::<T>::new(src));
+    // Leave archiving to the server, which will catch up on these when it starts
+    let mut out = try!(Wal::<T>::new(dest, WalConfig {
+        segment_size: segment_size,
+        archive: None
+    }));
+    let mut moved = HashMap::new();
+
+    while let Some(entry) = reader.next_entry() {
+        let (position, msg) = try!(entry);
+        let converted = try!(convert(position, msg));
+        moved.insert(position, try!(out.write_entry(&converted)));
+    }
+
+    // This also gets rid of the next segment, which was being prepared in the background
+    try!(out.close());
+
+    Ok(moved)
+}
+
+// usage: walmigrate (--config <server config> | --segment-size BYTES) <engine wal directory>
+// Rewrites an engine's input log and output journal in the newest schema.  The migrated copy is
+// built alongside the original, which is then kept as <dir>.orig.  Entry positions change, so the
+// journal's references into the input log are remapped, and standbys have to be migrated with the
+// same segment size or re-seeded from the primary.  The server must not be running.
+fn main() {
+    let migrate_args = parse_args().unwrap_or_else(|e| {
+        panic!("invalid arguments: {}", e)
+    });
+    let dir = migrate_args.wal_dir.as_path();
+    let journal_dir = dir.join("journal");
+    let staging = PathBuf::from(format!("{}.migrating", dir.display()));
+    let backup = PathBuf::from(format!("{}.orig", dir.display()));
+
+    let current = is_current::<EngineMessage>(dir).and_then(|input| {
+        if input && journal_dir.is_dir() {
+            is_current::<JournalEntry>(journal_dir.as_path())
+        } else {
+            Ok(input)
+        }
+    }).unwrap_or_else(|e| {
+        panic!("failed to read {}: {}", dir.display(), e)
+    });
+
+    if current {
+        println!("{} is already at the current schema version", dir.display());
+        return;
+    }
+
+    if staging.exists() || backup.exists() {
+        panic!("{} or {} already exists, remove it first", staging.display(), backup.display());
+    }
+
+    // Decoding already brings old entries up to date so they're written back out unchanged
+    let moved = copy_log::<EngineMessage, _>(dir, staging.as_path(), migrate_args.segment_size,
+                                             |_, msg| Ok(msg)).unwrap_or_else(|e| {
+        panic!("failed to migrate input log: {}", e)
+    });
+    println!("migrated {} input entries", moved.len());
+
+    if journal_dir.is_dir() {
+        let journaled = copy_log::<JournalEntry, _>(journal_dir.as_path(),
+                                                    staging.join("journal").as_path(),
+                                                    migrate_args.segment_size,
+                                                    |from, entry| {
+            match moved.get(&entry.input) {
+                Some(&input) => Ok(JournalEntry { input: input, .. entry }),
+                None => Err(format!("journal entry at {} refers to missing input {}", from,
+                                    entry.input))
+            }
+        }).unwrap_or_else(|e| {
+            panic!("failed to migrate output journal: {}", e)
+        });
+        println!("migrated {} journal entries", journaled.len());
+    }
+
+    rename(dir, backup.as_path()).unwrap_or_else(|e| {
+        panic!("failed to move {} aside: {}", dir.display(), e)
+    });
+    rename(staging.as_path(), dir).unwrap_or_else(|e| {
+        panic!("failed to move migrated log into place: {}", e)
+    });
+
+    println!("migrated {}, original kept at {}", dir.display(), backup.display());
+}
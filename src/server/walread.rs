@@ -1,4 +1,5 @@
 extern crate bincode;
+extern crate flate2;
 extern crate libcix;
 extern crate memmap;
 extern crate regex;
@@ -10,23 +11,669 @@ mod messages;
 mod wal;
 
 use libcix::order::trade_types::*;
-use messages::EngineMessage;
+use messages::{EngineMessage, FundsChange, JournalEntry};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
 use std::env::args;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::Duration;
+use wal::{WalFile, WalPosition};
 
-fn print_entries<L>(iter: L) where L: Iterator<Item=Result<EngineMessage, String>> {
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum EntryType {
+    NewOrder,
+    Cancel,
+    Serialization,
+    OpenOrders,
+    List,
+    Suspend,
+    Resume,
+    Delist,
+    Deposit,
+    Withdrawal,
+    SetBalance
+}
+
+impl EntryType {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "new" => Ok(EntryType::NewOrder),
+            "cancel" => Ok(EntryType::Cancel),
+            "serialization" => Ok(EntryType::Serialization),
+            "open_orders" => Ok(EntryType::OpenOrders),
+            "list" => Ok(EntryType::List),
+            "suspend" => Ok(EntryType::Suspend),
+            "resume" => Ok(EntryType::Resume),
+            "delist" => Ok(EntryType::Delist),
+            "deposit" => Ok(EntryType::Deposit),
+            "withdrawal" => Ok(EntryType::Withdrawal),
+            "set_balance" => Ok(EntryType::SetBalance),
+            _ => Err(format!("unknown message type {}", s))
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            EntryType::NewOrder => "new",
+            EntryType::Cancel => "cancel",
+            EntryType::Serialization => "serialization",
+            EntryType::OpenOrders => "open_orders",
+            EntryType::List => "list",
+            EntryType::Suspend => "suspend",
+            EntryType::Resume => "resume",
+            EntryType::Delist => "delist",
+            EntryType::Deposit => "deposit",
+            EntryType::Withdrawal => "withdrawal",
+            EntryType::SetBalance => "set_balance"
+        }
+    }
+}
+
+// Flattened view of a log entry with whatever fields that message type carries
+struct Record {
+    position: WalPosition,
+    entry_type: EntryType,
+    user: Option<UserId>,
+    symbol: Option<Symbol>,
+    order_id: Option<OrderId>,
+    side: Option<OrderSide>,
+    price: Option<Price>,
+    quantity: Option<Quantity>,
+    // Only new orders are stamped, so everything else inherits the time of the last new order
+    // before it.  Engines stamp orders in log order so this is a reasonable lower bound.
+    ts: Option<OrderTime>,
+    detail: Option<u32>,
+    // Change to a user's cash
+    amount: Option<f64>
+}
+
+// Turns raw messages into records, remembering what it needs from earlier entries
+#[derive(Default)]
+struct Decoder {
+    symbols: HashMap<u32, Symbol>,
+    last_ts: Option<OrderTime>
+}
+
+impl Decoder {
+    fn decode(&mut self, position: WalPosition, msg: &EngineMessage) -> Option<Record> {
+        let mut record = Record {
+            position: position,
+            entry_type: EntryType::Serialization,
+            user: None,
+            symbol: None,
+            order_id: None,
+            side: None,
+            price: None,
+            quantity: None,
+            ts: self.last_ts,
+            detail: None,
+            amount: None
+        };
+
+        match *msg {
+            EngineMessage::NullMessage => {
+                return None;
+            },
+            EngineMessage::NewOrder(ref data) => {
+                self.symbols.insert(data.order_id.symbol_id(), data.symbol);
+                self.last_ts = Some(data.ts);
+
+                record.entry_type = EntryType::NewOrder;
+                record.user = Some(data.user);
+                record.symbol = Some(data.symbol);
+                record.order_id = Some(data.order_id);
+                record.side = Some(data.side);
+                record.price = Some(data.price);
+                record.quantity = Some(data.quantity);
+                record.ts = Some(data.ts);
+            },
+            EngineMessage::CancelOrder(ref data) => {
+                record.entry_type = EntryType::Cancel;
+                record.user = Some(data.user);
+                record.symbol = self.symbols.get(&data.order_id.symbol_id()).cloned();
+                record.order_id = Some(data.order_id);
+                record.side = Some(data.order_id.side());
+            },
+            EngineMessage::SerializationMessage(id) => {
+                record.entry_type = EntryType::Serialization;
+                record.detail = Some(id);
+            },
+            EngineMessage::GetOpenOrdersMessaage(seq) => {
+                record.entry_type = EntryType::OpenOrders;
+                record.user = Some(seq.user);
+                record.detail = Some(seq.seq);
+            },
+            EngineMessage::ListSymbol(ref data) => {
+                self.symbols.insert(data.symbol_id, data.symbol);
+
+                record.entry_type = EntryType::List;
+                record.symbol = Some(data.symbol);
+                record.detail = Some(data.symbol_id);
+            },
+            EngineMessage::SuspendSymbol(ref data) => {
+                record.entry_type = EntryType::Suspend;
+                record.symbol = Some(data.symbol);
+                record.detail = Some(data.symbol_id);
+            },
+            EngineMessage::ResumeSymbol(ref data) => {
+                record.entry_type = EntryType::Resume;
+                record.symbol = Some(data.symbol);
+                record.detail = Some(data.symbol_id);
+            },
+            EngineMessage::DelistSymbol(ref data) => {
+                record.entry_type = EntryType::Delist;
+                record.symbol = Some(data.symbol);
+                record.detail = Some(data.symbol_id);
+            },
+            EngineMessage::Funds(ref data) => {
+                record.entry_type = match data.change {
+                    FundsChange::Deposit => EntryType::Deposit,
+                    FundsChange::Withdrawal => EntryType::Withdrawal,
+                    FundsChange::SetBalance => EntryType::SetBalance
+                };
+                record.user = Some(data.user);
+                record.amount = Some(data.amount);
+            }
+        }
+
+        Some(record)
+    }
+}
+
+#[derive(Default)]
+struct Filter {
+    user: Option<UserId>,
+    symbol: Option<Symbol>,
+    order_id: Option<u64>,
+    entry_type: Option<EntryType>,
+    // Inclusive bounds in seconds since the epoch
+    from: Option<i64>,
+    to: Option<i64>
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        if self.user.is_some() && record.user != self.user {
+            return false;
+        }
+
+        if self.symbol.is_some() && record.symbol != self.symbol {
+            return false;
+        }
+
+        if let Some(raw) = self.order_id {
+            if record.order_id.map(|id| id.raw()) != Some(raw) {
+                return false;
+            }
+        }
+
+        if self.entry_type.is_some() && Some(record.entry_type) != self.entry_type {
+            return false;
+        }
+
+        if self.from.is_some() || self.to.is_some() {
+            let secs = match record.ts {
+                Some(ts) => ts.sec,
+                None => { return false; }
+            };
+
+            if self.from.map(|from| secs < from).unwrap_or(false) ||
+               self.to.map(|to| secs > to).unwrap_or(false) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Csv
+}
+
+impl OutputFormat {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("unknown output format {}", s))
+        }
+    }
+
+    fn print_header(&self) {
+        if *self == OutputFormat::Csv {
+            println!("index,offset,type,ts,user,symbol,order_id,side,price,quantity,detail,\
+                      amount");
+        }
+    }
+
+    fn print(&self, record: &Record) {
+        match *self {
+            OutputFormat::Text => print_text(record),
+            OutputFormat::Json => print_json(record),
+            OutputFormat::Csv => print_csv(record)
+        }
+    }
+}
+
+fn symbol_str(symbol: &Symbol) -> &str {
+    symbol.as_str().trim_right_matches('\0')
+}
+
+fn side_str(side: OrderSide) -> &'static str {
+    match side {
+        OrderSide::Buy => "buy",
+        OrderSide::Sell => "sell"
+    }
+}
+
+fn ts_str(ts: &OrderTime) -> String {
+    format!("{}.{:09}", ts.sec, ts.nsec)
+}
+
+fn opt_str<T, F>(value: Option<T>, f: F) -> String where F: FnOnce(T) -> String {
+    value.map(f).unwrap_or(String::new())
+}
+
+fn print_text(record: &Record) {
+    let mut line = format!("{} {}", record.position, record.entry_type.name());
+
+    if let Some(ts) = record.ts {
+        line.push_str(&format!(" ts={}", ts_str(&ts)));
+    }
+    if let Some(user) = record.user {
+        line.push_str(&format!(" user={}", user));
+    }
+    if let Some(ref symbol) = record.symbol {
+        line.push_str(&format!(" symbol={}", symbol_str(symbol)));
+    }
+    if let Some(id) = record.order_id {
+        line.push_str(&format!(" order={}", id));
+    }
+    if let Some(side) = record.side {
+        line.push_str(&format!(" side={}", side_str(side)));
+    }
+    if let Some(price) = record.price {
+        line.push_str(&format!(" price={}", price));
+    }
+    if let Some(quantity) = record.quantity {
+        line.push_str(&format!(" quantity={}", quantity));
+    }
+    if let Some(detail) = record.detail {
+        line.push_str(&format!(" seq={}", detail));
+    }
+    if let Some(amount) = record.amount {
+        line.push_str(&format!(" amount={}", amount));
+    }
+
+    println!("{}", line);
+}
+
+// Symbols are restricted to plain ASCII but escape anyway so a corrupt log can't produce
+// invalid output
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+
+    out
+}
+
+fn print_json(record: &Record) {
+    let mut fields = vec![
+        format!("\"index\":{}", record.position.index),
+        format!("\"offset\":{}", record.position.offset),
+        format!("\"type\":\"{}\"", record.entry_type.name())
+    ];
+
+    if let Some(ts) = record.ts {
+        fields.push(format!("\"ts\":{}", ts_str(&ts)));
+    }
+    if let Some(user) = record.user {
+        fields.push(format!("\"user\":{}", user));
+    }
+    if let Some(ref symbol) = record.symbol {
+        fields.push(format!("\"symbol\":\"{}\"", json_escape(symbol_str(symbol))));
+    }
+    if let Some(id) = record.order_id {
+        fields.push(format!("\"order_id\":{}", id));
+    }
+    if let Some(side) = record.side {
+        fields.push(format!("\"side\":\"{}\"", side_str(side)));
+    }
+    if let Some(price) = record.price {
+        fields.push(format!("\"price\":{}", price));
+    }
+    if let Some(quantity) = record.quantity {
+        fields.push(format!("\"quantity\":{}", quantity));
+    }
+    if let Some(detail) = record.detail {
+        fields.push(format!("\"detail\":{}", detail));
+    }
+    if let Some(amount) = record.amount {
+        fields.push(format!("\"amount\":{}", amount));
+    }
+
+    println!("{{{}}}", fields.join(","));
+}
+
+fn print_csv(record: &Record) {
+    println!("{},{},{},{},{},{},{},{},{},{},{},{}",
+             record.position.index,
+             record.position.offset,
+             record.entry_type.name(),
+             opt_str(record.ts, |ts| ts_str(&ts)),
+             opt_str(record.user, |u| u.to_string()),
+             opt_str(record.symbol, |s| symbol_str(&s).to_string()),
+             opt_str(record.order_id, |id| id.to_string()),
+             opt_str(record.side, |s| side_str(s).to_string()),
+             opt_str(record.price, |p| p.to_string()),
+             opt_str(record.quantity, |q| q.to_string()),
+             opt_str(record.detail, |d| d.to_string()),
+             opt_str(record.amount, |a| a.to_string()));
+}
+
+struct SegmentStats {
+    index: u32,
+    version: u32,
+    capacity: usize,
+    used: usize,
+    entries: usize
+}
+
+#[derive(Default)]
+struct Summary {
+    per_type: BTreeMap<&'static str, usize>,
+    per_symbol: BTreeMap<String, usize>,
+    per_user: BTreeMap<UserId, usize>,
+    segments: Vec<SegmentStats>
+}
+
+impl Summary {
+    fn add(&mut self, record: &Record) {
+        *self.per_type.entry(record.entry_type.name()).or_insert(0) += 1;
+
+        if let Some(ref symbol) = record.symbol {
+            *self.per_symbol.entry(symbol_str(symbol).to_string()).or_insert(0) += 1;
+        }
+
+        if let Some(user) = record.user {
+            *self.per_user.entry(user).or_insert(0) += 1;
+        }
+    }
+
+    fn print(&self) {
+        println!("entries by type:");
+        for (name, count) in self.per_type.iter() {
+            println!("  {:<16} {}", name, count);
+        }
+
+        println!("entries by symbol:");
+        for (symbol, count) in self.per_symbol.iter() {
+            println!("  {:<16} {}", symbol, count);
+        }
+
+        println!("entries by user:");
+        for (user, count) in self.per_user.iter() {
+            println!("  {:<16} {}", user, count);
+        }
+
+        println!("segments:");
+        for segment in self.segments.iter() {
+            let fill = if segment.capacity > 0 {
+                100f64 * segment.used as f64 / segment.capacity as f64
+            } else {
+                0f64
+            };
+
+            println!("  wal_{:<12} {} entries, {}/{} bytes ({:.1}% full), schema version {}",
+                     segment.index, segment.entries, segment.used, segment.capacity, fill,
+                     segment.version);
+        }
+    }
+}
+
+struct Options {
+    path: PathBuf,
+    journal: bool,
+    filter: Filter,
+    format: OutputFormat,
+    summary: bool,
+    follow: bool
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        path: PathBuf::new(),
+        journal: false,
+        filter: Filter::default(),
+        format: OutputFormat::Text,
+        summary: false,
+        follow: false
+    };
+    let mut path = None;
+
+    let mut cli_args = args();
+    cli_args.next();
+
+    while let Some(flag) = cli_args.next() {
+        let mut value = || cli_args.next().ok_or(format!("{} requires a value", flag));
+
+        match flag.as_str() {
+            "--journal" => { options.journal = true; },
+            "--user" => {
+                options.filter.user = Some(try!(UserId::from_str(try!(value()).as_str())
+                                                .map_err(|_| "invalid user".to_string())));
+            },
+            "--symbol" => {
+                options.filter.symbol = Some(try!(Symbol::from_str(try!(value()).as_str())
+                                                  .map_err(|_| "invalid symbol".to_string())));
+            },
+            "--order" => {
+                options.filter.order_id = Some(try!(u64::from_str(try!(value()).as_str())
+                                                    .map_err(|_| "invalid order id".to_string())));
+            },
+            "--type" => {
+                options.filter.entry_type = Some(try!(EntryType::parse(try!(value()).as_str())));
+            },
+            "--from" => {
+                options.filter.from = Some(try!(i64::from_str(try!(value()).as_str())
+                                                .map_err(|_| "invalid start time".to_string())));
+            },
+            "--to" => {
+                options.filter.to = Some(try!(i64::from_str(try!(value()).as_str())
+                                              .map_err(|_| "invalid end time".to_string())));
+            },
+            "--format" => { options.format = try!(OutputFormat::parse(try!(value()).as_str())); },
+            "--summary" => { options.summary = true; },
+            "--follow" => { options.follow = true; },
+            _ if flag.starts_with("--") => {
+                return Err(format!("unrecognized argument {}", flag));
+            },
+            _ => { path = Some(PathBuf::from(flag.clone())); }
+        }
+    }
+
+    options.path = try!(path.ok_or("no log path given".to_string()));
+
+    if options.follow && (options.summary || options.journal) {
+        return Err("--follow can't be combined with --summary or --journal".to_string());
+    }
+
+    Ok(options)
+}
+
+// Segments to read as (index, path).  A single file is numbered from its name if it looks like
+// a log segment so that positions match what the server reports.
+fn list_segments(path: &Path) -> Result<Vec<(u32, PathBuf)>, String> {
+    if path.is_dir() {
+        Ok(try!(wal::get_all_files(path)).into_iter().map(|index| {
+            (index, wal::segment_path(path, index))
+        }).collect())
+    } else {
+        let wal_regex = Regex::new(r"^wal_(\d+)(\.gz|\.zlib)?$").unwrap();
+        let index = path.file_name().and_then(|name| name.to_str()).and_then(|name| {
+            wal_regex.captures(name).map(|c| u32::from_str(&c[1]).unwrap())
+        }).unwrap_or(0);
+
+        Ok(vec![(index, path.to_path_buf())])
+    }
+}
+
+// Decodes, filters and then either prints or counts a single entry
+fn emit(options: &Options, decoder: &mut Decoder, summary: &mut Summary, position: WalPosition,
+        msg: &EngineMessage) {
+    let record = match decoder.decode(position, msg) {
+        Some(r) => r,
+        None => { return; }
+    };
+
+    if !options.filter.matches(&record) {
+        return;
+    }
+
+    if options.summary {
+        summary.add(&record);
+    } else {
+        options.format.print(&record);
+    }
+}
+
+fn read_entries(options: &Options) -> Result<(), String> {
+    let mut decoder = Decoder::default();
+    let mut summary = Summary::default();
+
+    if !options.summary {
+        options.format.print_header();
+    }
+
+    for (index, path) in try!(list_segments(options.path.as_path())) {
+        let mut file: WalFile<EngineMessage> = try!(WalFile::open(path.as_path(), false));
+        let mut entries = 0usize;
+
+        loop {
+            let position = WalPosition {
+                index: index,
+                offset: file.cursor() as u64
+            };
+
+            let msg = match file.next() {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    println!("failed to read entry at {}: {}", position, e);
+                    break;
+                },
+                None => break
+            };
+
+            entries += 1;
+            emit(options, &mut decoder, &mut summary, position, &msg);
+        }
+
+        summary.segments.push(SegmentStats {
+            index: index,
+            version: file.version(),
+            capacity: file.capacity(),
+            used: file.cursor(),
+            entries: entries
+        });
+    }
+
+    if options.summary {
+        summary.print();
+    }
+
+    Ok(())
+}
+
+const FOLLOW_POLL_MS: u64 = 100;
+
+// Tails a live log starting from the beginning of the given segment, or the newest segment if
+// given a directory.  A segment is finished once the writer has rotated to the next one, which
+// it only does after the current one fills up, so anything committed before the next segment
+// appeared is still read.
+fn follow_entries(options: &Options) -> Result<(), String> {
+    let path = options.path.as_path();
+    let (dir, mut index) = if path.is_dir() {
+        (path.to_path_buf(), try!(wal::get_all_files(path)).last().cloned().unwrap_or(0))
+    } else {
+        let (index, _) = try!(list_segments(path))[0].clone();
+        (path.parent().map(|p| p.to_path_buf()).unwrap_or(PathBuf::from(".")), index)
+    };
+
+    let mut decoder = Decoder::default();
+    let mut summary = Summary::default();
+    let poll = Duration::from_millis(FOLLOW_POLL_MS);
+
+    options.format.print_header();
+
+    loop {
+        let segment_path = wal::segment_path(dir.as_path(), index);
+
+        // The server may not have created the segment yet
+        while !segment_path.exists() {
+            sleep(poll);
+        }
+
+        let mut file: WalFile<EngineMessage> = try!(WalFile::open(segment_path.as_path(), false));
+
+        if !file.has_header() {
+            println!("wal_{} has no header, relying on zeroed memory to find its end", index);
+        }
+
+        loop {
+            let position = WalPosition {
+                index: index,
+                offset: file.cursor() as u64
+            };
+
+            match file.next() {
+                Some(Ok(msg)) => {
+                    emit(options, &mut decoder, &mut summary, position, &msg);
+                    continue;
+                },
+                Some(Err(e)) => {
+                    return Err(format!("failed to read entry at {}: {}", position, e));
+                },
+                None => ()
+            }
+
+            // Check for the next segment before refreshing so that nothing committed to this one
+            // in between is missed
+            let rotated = wal::segment_path(dir.as_path(), index + 1).exists();
+            file.refresh();
+
+            if rotated && (!file.has_header() || file.cursor() >= file.committed()) {
+                break;
+            }
+
+            if !rotated {
+                sleep(poll);
+            }
+        }
+
+        index += 1;
+    }
+}
+
+fn print_journal<L>(iter: L) where L: Iterator<Item=Result<JournalEntry, String>> {
     for entry in iter {
         match entry {
-            Ok(msg) => {
-                match msg {
-                    EngineMessage::NewOrder(data) => {
-                        println!("new order {:?}", data);
-                    },
-                    EngineMessage::CancelOrder(data) => {
-                        println!("cancel order {}", data.order_id);
-                    },
-                    _ => unreachable!()
-                }
+            Ok(e) => {
+                println!("{} -> {:?}", e.input, e.output);
             },
             Err(e) => {
                 println!("failed to read entry: {}", e);
@@ -36,17 +683,37 @@ fn print_entries<L>(iter: L) where L: Iterator<Item=Result<EngineMessage, String
     }
 }
 
+// usage: walread [--journal] [--user ID] [--symbol SYM] [--order ID]
+//                [--type new|cancel|serialization|open_orders|list|suspend|resume|delist|
+//                        deposit|withdrawal|set_balance]
+//                [--from SECS] [--to SECS]
+//                [--format text|json|csv] [--summary | --follow] <path>
+// With --journal the path is read as an engine output journal instead of an input log; filters,
+// formats and summaries only apply to input logs.  --follow keeps reading new entries as a running
+// server writes them and moves on to new segments as they're created.  Segment statistics in the
+// summary cover every entry while the per-type, per-symbol and per-user counts only include
+// entries that pass the filters.
 fn main() {
-    let mut cli_args = args();
-    cli_args.next();
-    let path_str = cli_args.next().unwrap();
-    let wal_path = Path::new(path_str.as_str());
+    let options = parse_args().unwrap_or_else(|e| {
+        panic!("invalid arguments: {}", e)
+    });
+    let wal_path = options.path.as_path();
 
-    if wal_path.is_dir() {
-        let reader = wal::WalDirectoryReader::new(wal_path).unwrap();
-        print_entries(reader);
+    if options.journal {
+        if wal_path.is_dir() {
+            print_journal(wal::WalDirectoryReader::new(wal_path).unwrap());
+        } else {
+            print_journal(wal::WalFile::open(wal_path, false).unwrap());
+        }
     } else {
-        let reader = wal::WalFile::open(wal_path, false).unwrap();
-        print_entries(reader);
+        let result = if options.follow {
+            follow_entries(&options)
+        } else {
+            read_entries(&options)
+        };
+
+        if let Err(e) = result {
+            println!("failed to read log: {}", e);
+        }
     }
 }
@@ -1,6 +1,8 @@
 extern crate libcix;
+extern crate time;
 
 use libcix::book::*;
+use libcix::clock::{Clock, ReplayClock};
 use libcix::order::trade_types::*;
 
 const SYMBOL: &'static str = "GOOG";
@@ -19,10 +21,6 @@ fn entry_display(entry: Option<MdEntry>) -> (String, String) {
 }
 
 impl ExecutionHandler for ExecutionPrinter {
-    fn ack_order(&self, order_id: OrderId, status: ErrorCode) {
-        println!("ACK {}", order_id)
-    }
-
     fn handle_match(&self, execution: &Execution) {
         println!("{}", execution)
     }
@@ -59,9 +57,17 @@ impl ExecutionHandler for ExecutionPrinter {
     }
 }
 
+fn add_order(matcher: &mut BasicMatcher, book: &mut OrderBook, order: Order,
+             printer: &ExecutionPrinter) {
+    let status = matcher.add_order(book, order, printer);
+    println!("ACK {}: {:?}", order.id, status);
+}
+
 fn create_order(side: OrderSide, price: Price, quantity: Quantity,
-                order_seq: &mut u64) -> Order {
+                order_seq: &mut u64, clock: &ReplayClock) -> Order {
     let mut o = Order::default();
+    clock.advance(time::Duration::nanoseconds(1));
+    o.update = clock.now();
     o.id = OrderId::new(0, side, *order_seq).unwrap();
     o.symbol = Symbol::from_str(SYMBOL).unwrap();
     o.side = side;
@@ -76,36 +82,37 @@ fn main() {
     let mut matcher = BasicMatcher{};
     let printer = ExecutionPrinter{};
     let mut order_seq = 0u64;
+    let clock = ReplayClock::default();
 
     // Match two orders with same price against each other completely
     let mut order = create_order(OrderSide::Sell, 500f64, 1000u32,
-                                 &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+                                 &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
-    order = create_order(OrderSide::Buy, 500f64, 1000u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Buy, 500f64, 1000u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
     // Check two orders that cross in price and leave some of the old order on
     // the book
-    order = create_order(OrderSide::Buy, 500f64, 1000u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Buy, 500f64, 1000u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
-    order = create_order(OrderSide::Sell, 450f64, 100u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Sell, 450f64, 100u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
     // Cross that order and leave some of the new order on the book
-    order = create_order(OrderSide::Buy, 475f64, 1200u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Buy, 475f64, 1200u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
     // Trade with remainder of last order
-    order = create_order(OrderSide::Sell, 470f64, 100u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Sell, 470f64, 100u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
     // Add another buy order to the book
-    order = create_order(OrderSide::Buy, 472f64, 500u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Buy, 472f64, 500u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 
     // Trade through both sell orders on book
-    order = create_order(OrderSide::Sell, 470f64, 2000u32, &mut order_seq);
-    matcher.add_order(&mut book, order, &printer);
+    order = create_order(OrderSide::Sell, 470f64, 2000u32, &mut order_seq, &clock);
+    add_order(&mut matcher, &mut book, order, &printer);
 }